@@ -0,0 +1,57 @@
+//! Sizing up bencoded input from the network before it is decoded. `serde_bencode` recurses once
+//! for every level of nesting, so a datagram or message of nothing but `l`s would run it out of
+//! stack; such input is turned away here first, without recursing.
+
+/// Lists and dictionaries nested deeper than this are refused. Nothing a peer or DHT node
+/// legitimately sends comes close.
+pub(crate) const MAX_DEPTH: usize = 32;
+
+/// The length of the bencoded value at the start of `bytes`, or `None` if it is malformed, cut
+/// short, or nests lists and dictionaries deeper than [`MAX_DEPTH`].
+pub(crate) fn value_len(bytes: &[u8]) -> Option<usize> {
+    let mut depth = 0;
+    let mut at = 0;
+    loop {
+        match *bytes.get(at)? {
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return None;
+                }
+                at += 1;
+                continue;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                at += 1;
+            }
+            b'i' => at += bytes[at..].iter().position(|&b| b == b'e')? + 1,
+            b'0'..=b'9' => {
+                let colon = at + bytes[at..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&bytes[at..colon]).ok()?.parse().ok()?;
+                at = colon.checked_add(1 + len).filter(|&end| end <= bytes.len())?;
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(at);
+        }
+    }
+}
+
+#[test]
+fn test_value_len() {
+    assert_eq!(value_len(b"i42etrailing"), Some(4));
+    assert_eq!(value_len(b"4:spam"), Some(6));
+    // the `l`s and `d`s inside strings are data, not nesting
+    assert_eq!(value_len(b"d3:keyl4:lldde1:ai1ee"), Some(21));
+    assert_eq!(value_len(b"l4:spam"), None);
+    assert_eq!(value_len(b"5:spam"), None);
+    assert_eq!(value_len(b"e"), None);
+
+    let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+    assert_eq!(value_len(&nested(MAX_DEPTH)), Some(2 * MAX_DEPTH));
+    assert_eq!(value_len(&nested(MAX_DEPTH + 1)), None);
+    let hostile = [&b"d1:a"[..], &[b'l'; 65_000]].concat();
+    assert_eq!(value_len(&hostile), None);
+}
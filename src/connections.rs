@@ -0,0 +1,293 @@
+//! Keeping a download connected to enough peers: the pool of addresses that discovery turned up,
+//! limits on how many connections are open at once, and backoff for addresses that fail.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Waiting this long before retrying an address that failed once; it doubles with every failure.
+const BACKOFF: Duration = Duration::from_secs(5);
+/// Addresses that failed this many times in a row are not tried again.
+const MAX_FAILURES: u32 = 4;
+/// A peer that left in good order may have new pieces to offer after this long.
+const RECONNECT_AFTER: Duration = Duration::from_secs(2 * 60);
+
+/// How many connections a download keeps open.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// Peers to download from at once.
+    pub per_torrent: usize,
+    /// Connections that are still being set up, handshakes included.
+    pub half_open: usize,
+    /// Shared between downloads, to cap the connections of all of them together.
+    pub global: Option<GlobalLimit>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self { per_torrent: 20, half_open: 8, global: None }
+    }
+}
+
+/// A cap on connections across every download that is given a clone of it.
+#[derive(Clone)]
+pub struct GlobalLimit(Arc<Semaphore>);
+
+impl GlobalLimit {
+    pub fn new(connections: usize) -> Self {
+        Self(Arc::new(Semaphore::new(connections)))
+    }
+
+    /// A slot for one more connection, held for as long as it stays open.
+    pub(crate) fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.0.clone().try_acquire_owned().ok()
+    }
+}
+
+impl fmt::Debug for GlobalLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GlobalLimit({} free)", self.0.available_permits())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Can be tried once `retry_at` has passed.
+    Waiting,
+    Connecting,
+    Connected,
+    /// Failed too often, or turned out to be a peer we already have under another address.
+    Gone,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    state: State,
+    failures: u32,
+    retry_at: Instant,
+    // from the handshake, once it has connected
+    peer_id: Option<[u8; 20]>,
+}
+
+/// Every peer address a download has heard of, and how connecting to each of them went.
+#[derive(Debug, Default)]
+pub(crate) struct PeerPool {
+    candidates: HashMap<SocketAddrV4, Candidate>,
+    // in the order discovery found them, which puts the most promising first
+    order: Vec<SocketAddrV4>,
+    // of the peers we are connected to, so that one peer is never connected twice
+    peer_ids: HashSet<[u8; 20]>,
+    // of the peers that were banned, whatever address they come back from
+    banned_ids: HashSet<[u8; 20]>,
+}
+
+impl PeerPool {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the addresses that are new to the pool, and returns how many there were.
+    pub(crate) fn add(&mut self, addrs: impl IntoIterator<Item = SocketAddrV4>, now: Instant) -> usize {
+        let mut added = 0;
+        for addr in addrs {
+            if self.candidates.contains_key(&addr) {
+                continue;
+            }
+            self.candidates.insert(addr, Candidate { state: State::Waiting, failures: 0, retry_at: now, peer_id: None });
+            self.order.push(addr);
+            added += 1;
+        }
+        added
+    }
+
+    /// The next address to connect to, if any is due. It counts as connecting from here on.
+    pub(crate) fn next(&mut self, now: Instant) -> Option<SocketAddrV4> {
+        let candidates = &mut self.candidates;
+        let addr = *self.order.iter().find(|addr| {
+            let candidate = &candidates[addr];
+            candidate.state == State::Waiting && candidate.retry_at <= now
+        })?;
+        candidates.get_mut(&addr).expect("every address in order is a candidate").state = State::Connecting;
+        Some(addr)
+    }
+
+    /// Whether any address is left to try, now or after its backoff.
+    pub(crate) fn has_prospects(&self) -> bool {
+        self.candidates.values().any(|candidate| candidate.state == State::Waiting)
+    }
+
+    /// Records a finished handshake. Returns false if the peer turns out to be connected already,
+    /// under another address, or to have been banned, in which case the new connection should
+    /// be dropped.
+    pub(crate) fn connected(&mut self, addr: SocketAddrV4, peer_id: [u8; 20]) -> bool {
+        let candidate = self.candidates.get_mut(&addr).expect("connected to an address from the pool");
+        if self.banned_ids.contains(&peer_id) || !self.peer_ids.insert(peer_id) {
+            candidate.state = State::Gone;
+            return false;
+        }
+        candidate.peer_id = Some(peer_id);
+        candidate.state = State::Connected;
+        candidate.failures = 0;
+        true
+    }
+
+    /// Records a connection that could not be opened, or that broke down. The address is retried
+    /// after a backoff that doubles every time, until it has failed [`MAX_FAILURES`] times.
+    pub(crate) fn failed(&mut self, addr: SocketAddrV4, now: Instant) {
+        let candidate = self.candidates.get_mut(&addr).expect("failed on an address from the pool");
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            candidate.state = State::Gone;
+        } else {
+            candidate.state = State::Waiting;
+            candidate.retry_at = now + BACKOFF * 2u32.pow(candidate.failures - 1);
+        }
+    }
+
+    /// Records that a connected peer went away, `failed` or not.
+    pub(crate) fn disconnected(&mut self, addr: SocketAddrV4, peer_id: [u8; 20], failed: bool, now: Instant) {
+        self.peer_ids.remove(&peer_id);
+        if self.candidates[&addr].state == State::Gone {
+            // banned while it was connected
+        } else if failed {
+            self.failed(addr, now);
+        } else {
+            let candidate = self.candidates.get_mut(&addr).expect("disconnected from an address from the pool");
+            candidate.state = State::Waiting;
+            candidate.retry_at = now + RECONNECT_AFTER;
+        }
+    }
+
+    /// Never connects to `addr` again, nor to the peer that was last connected from it under
+    /// any other address.
+    pub(crate) fn ban(&mut self, addr: SocketAddrV4) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = State::Gone;
+            self.banned_ids.extend(candidate.peer_id);
+        }
+    }
+
+    /// Makes the peers that left in good order due again, now that there is new work they may
+    /// be able to help with.
+    pub(crate) fn recall(&mut self, now: Instant) {
+        for candidate in self.candidates.values_mut() {
+            if candidate.state == State::Waiting && candidate.failures == 0 {
+                candidate.retry_at = candidate.retry_at.min(now);
+            }
+        }
+    }
+}
+
+/// A block of a piece that failed its hash check, and who sent it.
+#[derive(Debug)]
+struct SentBlock {
+    begin: usize,
+    length: usize,
+    hash: [u8; 20],
+    from: SocketAddrV4,
+}
+
+/// Finds the peers behind corrupt pieces: the blocks of a piece that fails its hash check are
+/// remembered, and once a copy of the piece checks out, whoever sent a block that differs from
+/// it was lying.
+#[derive(Debug, Default)]
+pub(crate) struct SmartBan {
+    failed: HashMap<usize, Vec<SentBlock>>,
+}
+
+impl SmartBan {
+    /// Remembers the blocks of a `piece` that failed its hash check, each sent by the peer that
+    /// `senders` lists at its offset.
+    pub(crate) fn failed(&mut self, piece: usize, data: &[u8], senders: &BTreeMap<usize, SocketAddrV4>) {
+        let mut begins = senders.iter().peekable();
+        let blocks = self.failed.entry(piece).or_default();
+        while let Some((&begin, &from)) = begins.next() {
+            let end = begins.peek().map_or(data.len(), |(&next, _)| next);
+            let hash = Sha1::digest(&data[begin..end]).into();
+            blocks.push(SentBlock { begin, length: end - begin, hash, from });
+        }
+    }
+
+    /// Compares the blocks remembered for `piece` with its verified `data`, and returns the peers
+    /// that sent any that differ.
+    pub(crate) fn passed(&mut self, piece: usize, data: &[u8]) -> HashSet<SocketAddrV4> {
+        let Some(blocks) = self.failed.remove(&piece) else {
+            return HashSet::new();
+        };
+        blocks
+            .into_iter()
+            .filter(|block| <[u8; 20]>::from(Sha1::digest(&data[block.begin..][..block.length])) != block.hash)
+            .map(|block| block.from)
+            .collect()
+    }
+}
+
+#[test]
+fn test_peer_pool() {
+    let addr = |port| SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
+    let now = Instant::now();
+    let mut pool = PeerPool::new();
+    assert_eq!(pool.add([addr(1), addr(2), addr(1)], now), 2);
+    assert_eq!(pool.add([addr(2), addr(3)], now), 1);
+
+    // discovery order, and each address only once at a time
+    assert_eq!(pool.next(now), Some(addr(1)));
+    assert_eq!(pool.next(now), Some(addr(2)));
+    assert!(pool.connected(addr(2), [2; 20]));
+    // the same peer behind another address is turned away, and never tried again
+    assert_eq!(pool.next(now), Some(addr(3)));
+    assert!(!pool.connected(addr(3), [2; 20]));
+
+    // failures back off exponentially, and eventually for good
+    let mut at = now;
+    for failures in 1..MAX_FAILURES {
+        pool.failed(addr(1), at);
+        assert_eq!(pool.next(at), None);
+        at += BACKOFF * 2u32.pow(failures - 1);
+        assert_eq!(pool.next(at), Some(addr(1)));
+    }
+    pool.failed(addr(1), at);
+    assert!(!pool.has_prospects());
+    assert_eq!(pool.next(at + RECONNECT_AFTER * 100), None);
+
+    // a peer that left in good order is welcome back later, under its id as well
+    pool.disconnected(addr(2), [2; 20], false, now);
+    assert!(pool.has_prospects());
+    assert_eq!(pool.next(now), None);
+    assert_eq!(pool.next(now + RECONNECT_AFTER), Some(addr(2)));
+    assert!(pool.connected(addr(2), [2; 20]));
+    assert!(!pool.has_prospects());
+
+    // or straight away when there is new work for it, unless it was banned meanwhile
+    pool.disconnected(addr(2), [2; 20], false, now);
+    pool.recall(now);
+    assert_eq!(pool.next(now), Some(addr(2)));
+    assert!(pool.connected(addr(2), [2; 20]));
+    pool.ban(addr(2));
+    pool.disconnected(addr(2), [2; 20], false, now);
+    pool.recall(now);
+    assert!(!pool.has_prospects());
+    // a banned peer stays banned under a new address
+    pool.add([addr(5)], now);
+    assert_eq!(pool.next(now), Some(addr(5)));
+    assert!(!pool.connected(addr(5), [2; 20]));
+}
+
+#[test]
+fn test_smart_ban() {
+    let addr = |port| SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
+    let good = vec![1u8; 12];
+    let mut corrupt = good.clone();
+    corrupt[5] = 0;
+    let mut smart_ban = SmartBan::default();
+    smart_ban.failed(3, &corrupt, &BTreeMap::from([(0, addr(1)), (4, addr(2)), (8, addr(1))]));
+    // nothing to compare a piece with until a copy checks out
+    assert!(smart_ban.passed(2, &good).is_empty());
+    assert_eq!(smart_ban.passed(3, &good), HashSet::from([addr(2)]));
+    assert!(smart_ban.passed(3, &good).is_empty());
+}
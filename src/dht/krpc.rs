@@ -0,0 +1,278 @@
+//! KRPC, the bencoded request/response protocol that DHT nodes speak over UDP (BEP 5).
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use anyhow::Context;
+use serde_bencode::value::Value;
+
+use super::routing::NodeId;
+use crate::bencode;
+
+/// A DHT node as carried in `nodes`: its ID followed by its compact IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+pub(crate) fn compact_addr(addr: &SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0u8; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+pub(crate) fn parse_compact_addr(compact: &[u8]) -> Option<SocketAddrV4> {
+    let compact: &[u8; 6] = compact.try_into().ok()?;
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]),
+        u16::from_be_bytes([compact[4], compact[5]]),
+    ))
+}
+
+/// Encodes nodes in the 26-bytes-per-node compact format.
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut compact = Vec::with_capacity(26 * nodes.len());
+    for node in nodes {
+        compact.extend_from_slice(&node.id);
+        compact.extend_from_slice(&compact_addr(&node.addr));
+    }
+    compact
+}
+
+/// Decodes the 26-bytes-per-node compact format; a trailing partial entry is ignored.
+pub fn decode_nodes(compact: &[u8]) -> Vec<NodeInfo> {
+    compact
+        .chunks_exact(26)
+        .filter_map(|node| {
+            Some(NodeInfo {
+                id: node[..20].try_into().expect("chunk is 26 bytes"),
+                addr: parse_compact_addr(&node[20..])?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode { target: NodeId },
+    GetPeers { info_hash: [u8; 20] },
+    /// With `implied_port` set the receiver uses the UDP source port instead of `port`.
+    AnnouncePeer { info_hash: [u8; 20], port: u16, implied_port: bool, token: Vec<u8> },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// The union of the fields any response can carry; which are set depends on the query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddrV4>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response { id: NodeId, response: Response },
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+fn bytes(v: impl Into<Vec<u8>>) -> Value {
+    Value::Bytes(v.into())
+}
+
+fn get<'a>(dict: &'a HashMap<Vec<u8>, Value>, key: &str) -> Option<&'a Value> {
+    dict.get(key.as_bytes())
+}
+
+fn get_bytes<'a>(dict: &'a HashMap<Vec<u8>, Value>, key: &str) -> anyhow::Result<&'a [u8]> {
+    match get(dict, key) {
+        Some(Value::Bytes(b)) => Ok(b),
+        _ => anyhow::bail!("missing byte string {key:?}"),
+    }
+}
+
+fn get_id(dict: &HashMap<Vec<u8>, Value>, key: &str) -> anyhow::Result<[u8; 20]> {
+    get_bytes(dict, key)?
+        .try_into()
+        .with_context(|| format!("{key:?} is not 20 bytes long"))
+}
+
+fn get_dict<'a>(dict: &'a HashMap<Vec<u8>, Value>, key: &str) -> anyhow::Result<&'a HashMap<Vec<u8>, Value>> {
+    match get(dict, key) {
+        Some(Value::Dict(d)) => Ok(d),
+        _ => anyhow::bail!("missing dictionary {key:?}"),
+    }
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+        dict.insert(b"t".to_vec(), bytes(self.transaction.clone()));
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args = HashMap::new();
+                args.insert(b"id".to_vec(), bytes(*id));
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } => {
+                        args.insert(b"target".to_vec(), bytes(*target));
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.insert(b"info_hash".to_vec(), bytes(*info_hash));
+                    }
+                    Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                        args.insert(b"info_hash".to_vec(), bytes(*info_hash));
+                        args.insert(b"port".to_vec(), Value::Int(*port as i64));
+                        args.insert(b"implied_port".to_vec(), Value::Int(*implied_port as i64));
+                        args.insert(b"token".to_vec(), bytes(token.clone()));
+                    }
+                }
+                dict.insert(b"y".to_vec(), bytes("q"));
+                dict.insert(b"q".to_vec(), bytes(query.method()));
+                dict.insert(b"a".to_vec(), Value::Dict(args));
+            }
+            Body::Response { id, response } => {
+                let mut values = HashMap::new();
+                values.insert(b"id".to_vec(), bytes(*id));
+                if !response.nodes.is_empty() {
+                    values.insert(b"nodes".to_vec(), bytes(encode_nodes(&response.nodes)));
+                }
+                if !response.values.is_empty() {
+                    let peers = response.values.iter().map(|peer| bytes(compact_addr(peer))).collect();
+                    values.insert(b"values".to_vec(), Value::List(peers));
+                }
+                if let Some(token) = &response.token {
+                    values.insert(b"token".to_vec(), bytes(token.clone()));
+                }
+                dict.insert(b"y".to_vec(), bytes("r"));
+                dict.insert(b"r".to_vec(), Value::Dict(values));
+            }
+            Body::Error { code, message } => {
+                dict.insert(b"y".to_vec(), bytes("e"));
+                dict.insert(
+                    b"e".to_vec(),
+                    Value::List(vec![Value::Int(*code), bytes(message.clone())]),
+                );
+            }
+        }
+        serde_bencode::to_bytes(&Value::Dict(dict)).expect("bencode values always serialize")
+    }
+
+    pub fn decode(packet: &[u8]) -> anyhow::Result<Self> {
+        // checked before the decoder, which would recurse through whatever nesting we are sent
+        anyhow::ensure!(bencode::value_len(packet) == Some(packet.len()), "packet is not bencoded, or nested too deeply");
+        let Value::Dict(dict) = serde_bencode::from_bytes(packet).context("packet is not bencoded")? else {
+            anyhow::bail!("packet is not a dictionary");
+        };
+        let transaction = get_bytes(&dict, "t")?.to_vec();
+        let body = match get_bytes(&dict, "y")? {
+            b"q" => {
+                let args = get_dict(&dict, "a")?;
+                let id = get_id(args, "id")?;
+                let query = match get_bytes(&dict, "q")? {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode { target: get_id(args, "target")? },
+                    b"get_peers" => Query::GetPeers { info_hash: get_id(args, "info_hash")? },
+                    b"announce_peer" => {
+                        let port = match get(args, "port") {
+                            Some(Value::Int(port)) => u16::try_from(*port).context("port out of range")?,
+                            _ => 0,
+                        };
+                        let implied_port = matches!(get(args, "implied_port"), Some(Value::Int(1)));
+                        anyhow::ensure!(implied_port || port != 0, "announce_peer without a port");
+                        Query::AnnouncePeer {
+                            info_hash: get_id(args, "info_hash")?,
+                            port,
+                            implied_port,
+                            token: get_bytes(args, "token")?.to_vec(),
+                        }
+                    }
+                    method => anyhow::bail!("unknown method {:?}", String::from_utf8_lossy(method)),
+                };
+                Body::Query { id, query }
+            }
+            b"r" => {
+                let values = get_dict(&dict, "r")?;
+                let id = get_id(values, "id")?;
+                let nodes = get_bytes(values, "nodes").map(decode_nodes).unwrap_or_default();
+                let peers = match get(values, "values") {
+                    Some(Value::List(peers)) => peers
+                        .iter()
+                        .filter_map(|peer| match peer {
+                            Value::Bytes(peer) => parse_compact_addr(peer),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                let token = get_bytes(values, "token").ok().map(<[u8]>::to_vec);
+                Body::Response { id, response: Response { nodes, values: peers, token } }
+            }
+            b"e" => match get(&dict, "e") {
+                Some(Value::List(error)) => match error.as_slice() {
+                    [Value::Int(code), Value::Bytes(message), ..] => Body::Error {
+                        code: *code,
+                        message: String::from_utf8_lossy(message).into_owned(),
+                    },
+                    _ => anyhow::bail!("malformed error"),
+                },
+                _ => anyhow::bail!("missing error list"),
+            },
+            kind => anyhow::bail!("unknown message type {:?}", String::from_utf8_lossy(kind)),
+        };
+        Ok(Message { transaction, body })
+    }
+}
+
+#[test]
+fn test_krpc_roundtrip() {
+    let peer = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+    let node = NodeInfo { id: [7; 20], addr: peer };
+    let messages = [
+        Message {
+            transaction: b"aa".to_vec(),
+            body: Body::Query {
+                id: [1; 20],
+                query: Query::AnnouncePeer { info_hash: [2; 20], port: 6881, implied_port: true, token: b"tok".to_vec() },
+            },
+        },
+        Message {
+            transaction: b"bb".to_vec(),
+            body: Body::Response {
+                id: [3; 20],
+                response: Response { nodes: vec![node], values: vec![peer], token: Some(b"tok".to_vec()) },
+            },
+        },
+        Message { transaction: b"cc".to_vec(), body: Body::Error { code: ERROR_PROTOCOL, message: "bad token".into() } },
+    ];
+    for message in messages {
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    }
+    // the ping example from BEP 5
+    let ping = Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+    assert_eq!(ping.body, Body::Query { id: *b"abcdefghij0123456789", query: Query::Ping });
+    assert_eq!(ping.encode(), b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe");
+}
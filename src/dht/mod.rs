@@ -0,0 +1,591 @@
+//! A Mainline DHT node (BEP 5), for finding peers of torrents whose trackers are gone.
+
+pub mod krpc;
+pub mod routing;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::events::{Event, Events};
+use crate::ipfilter::IpFilter;
+use crate::random::{secure_source, Rng};
+use krpc::{Body, Message, NodeInfo, Query, Response};
+use routing::{distance, NodeId, RoutingTable, K};
+
+/// Well-known nodes to join the DHT through.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Queries in flight at once during a lookup.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Tokens handed out with `get_peers` stay valid for between one and two of these.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten unless they announce again within this long.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 256;
+/// How often the routing table is checked for nodes that have gone quiet.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Room for any datagram we receive. KRPC packets fit in one unfragmented UDP packet, so longer
+/// ones are not from a node we can talk to.
+const MAX_PACKET: usize = 2048;
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// The UDP address to listen on. Only IPv4 is supported.
+    pub bind: SocketAddr,
+    /// `host:port` of the nodes to join through.
+    pub bootstrap: Vec<String>,
+    /// Where to keep our node ID and routing table between runs.
+    pub state: Option<PathBuf>,
+    /// Nodes and peers in these ranges are neither contacted nor listened to.
+    pub ip_filter: Option<Arc<IpFilter>>,
+    /// Where the node reports what goes wrong without stopping it.
+    pub events: Events,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|node| node.to_string()).collect(),
+            state: None,
+            ip_filter: None,
+            events: Events::new(),
+        }
+    }
+}
+
+/// A running DHT node. It answers other nodes, and keeps its routing table fresh, in the
+/// background until it is dropped.
+pub struct Dht {
+    node: Arc<Node>,
+    receiver: JoinHandle<()>,
+    refresher: JoinHandle<()>,
+}
+
+/// Transaction -> who we asked, and who to hand their answer to.
+type Pending = HashMap<Vec<u8>, (SocketAddrV4, oneshot::Sender<Body>)>;
+
+/// Info hash -> peers announced for it, and when.
+type PeerStore = HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>;
+
+struct Node {
+    id: NodeId,
+    socket: UdpSocket,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<Pending>,
+    next_transaction: AtomicU32,
+    peers: Mutex<PeerStore>,
+    secrets: Mutex<Secrets>,
+}
+
+/// Secrets that tokens are derived from. They come from the operating system's secure generator,
+/// never from anything an observer could work out, such as our node ID.
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+    // kept open, so that rotating does not depend on a file descriptor being free
+    source: File,
+}
+
+/// What an iterative lookup found.
+struct Lookup {
+    peers: Vec<SocketAddrV4>,
+    // the closest nodes that answered, with the token they handed out (if any)
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+}
+
+impl fmt::Debug for Dht {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dht")
+            .field("id", &hex::encode(self.node.id))
+            .field("addr", &self.local_addr())
+            .finish()
+    }
+}
+
+impl Dht {
+    /// Starts a node, picking up the ID and routing table from `config.state` if it exists.
+    pub async fn bind(config: DhtConfig) -> anyhow::Result<Self> {
+        anyhow::ensure!(config.bind.is_ipv4(), "the DHT only supports IPv4");
+        let (id, known) = match &config.state {
+            Some(path) if path.exists() => load_state(&std::fs::read(path).context("read dht state")?)
+                .with_context(|| format!("parse dht state in {}", path.display()))?,
+            _ => {
+                let mut id = [0u8; 20];
+                Rng::new().fill(&mut id);
+                (id, Vec::new())
+            }
+        };
+        let mut table = RoutingTable::new(id);
+        for node in known {
+            table.insert(node);
+        }
+        let socket = UdpSocket::bind(config.bind).await.context("bind dht socket")?;
+        let mut source = secure_source().context("open the secure generator")?;
+        let mut current = [0u8; 20];
+        let mut previous = [0u8; 20];
+        source.read_exact(&mut current).context("generate token secret")?;
+        source.read_exact(&mut previous).context("generate token secret")?;
+        let node = Arc::new(Node {
+            id,
+            socket,
+            config,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU32::new(0),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(Secrets { current, previous, rotated: Instant::now(), source }),
+        });
+        let receiver = tokio::spawn(node.clone().receive());
+        let refresher = tokio::spawn(node.clone().refresh_every(REFRESH_INTERVAL));
+        Ok(Self { node, receiver, refresher })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.node.id
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        match self.node.socket.local_addr() {
+            Ok(SocketAddr::V4(addr)) => addr,
+            _ => unreachable!("bound to an IPv4 address"),
+        }
+    }
+
+    /// The number of nodes in the routing table.
+    pub fn nodes(&self) -> usize {
+        self.node.table.lock().expect("table lock is never poisoned").len()
+    }
+
+    /// Joins the DHT through the configured bootstrap nodes and the nodes remembered from the
+    /// last run, then fills the routing table with the nodes closest to us.
+    pub async fn bootstrap(&self) -> anyhow::Result<()> {
+        let mut addrs: HashSet<SocketAddrV4> = self
+            .node
+            .table
+            .lock()
+            .expect("table lock is never poisoned")
+            .nodes()
+            .map(|node| node.addr)
+            .collect();
+        for host in &self.node.config.bootstrap {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => {
+                    let message = format!("dht: failed to resolve bootstrap node {host}: {e}");
+                    self.node.config.events.send(Event::Log { info_hash: None, message });
+                }
+            }
+        }
+        addrs.remove(&self.local_addr());
+        self.add_nodes(addrs).await;
+        anyhow::ensure!(self.nodes() > 0, "no bootstrap node answered");
+        self.node.lookup(self.node.id, Query::FindNode { target: self.node.id }).await;
+        Ok(())
+    }
+
+    /// Pings the nodes at `addrs`, adding the ones that answer to the routing table.
+    pub async fn add_nodes(&self, addrs: impl IntoIterator<Item = SocketAddrV4>) {
+        self.node.add_nodes(addrs).await;
+    }
+
+    /// Pings the nodes we have not heard from in a while, so that dead ones can be replaced.
+    /// This happens on its own every few minutes.
+    pub async fn refresh(&self) {
+        self.node.refresh().await;
+    }
+
+    /// Finds peers for a torrent.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.node.lookup(info_hash, Query::GetPeers { info_hash }).await.peers
+    }
+
+    /// Tells the nodes closest to a torrent that we are a peer of it. Without a `port` the nodes
+    /// use the port our DHT traffic comes from. Returns how many nodes accepted the announcement.
+    pub async fn announce(&self, info_hash: [u8; 20], port: Option<u16>) -> usize {
+        let lookup = self.node.lookup(info_hash, Query::GetPeers { info_hash }).await;
+        let mut announces: FuturesUnordered<_> = lookup
+            .closest
+            .into_iter()
+            .filter_map(|(node, token)| Some((node, token?)))
+            .take(K)
+            .map(|(node, token)| {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port: port.unwrap_or(0),
+                    implied_port: port.is_none(),
+                    token,
+                };
+                self.node.query(node.addr, query)
+            })
+            .collect();
+        let mut accepted = 0;
+        while let Some(announced) = announces.next().await {
+            accepted += announced.is_ok() as usize;
+        }
+        accepted
+    }
+
+    /// Writes our node ID and routing table to `config.state`, if set.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.node.config.state else {
+            return Ok(());
+        };
+        let nodes: Vec<NodeInfo> = self.node.table.lock().expect("table lock is never poisoned").nodes().collect();
+        let state = Value::Dict(HashMap::from([
+            (b"id".to_vec(), Value::Bytes(self.node.id.to_vec())),
+            (b"nodes".to_vec(), Value::Bytes(krpc::encode_nodes(&nodes))),
+        ]));
+        let state = serde_bencode::to_bytes(&state).context("encode dht state")?;
+        std::fs::write(path, state).with_context(|| format!("write dht state to {}", path.display()))
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+        self.refresher.abort();
+    }
+}
+
+fn load_state(state: &[u8]) -> anyhow::Result<(NodeId, Vec<NodeInfo>)> {
+    let Value::Dict(state) = serde_bencode::from_bytes(state)? else {
+        anyhow::bail!("not a dictionary");
+    };
+    let (Some(Value::Bytes(id)), Some(Value::Bytes(nodes))) = (state.get(&b"id"[..]), state.get(&b"nodes"[..])) else {
+        anyhow::bail!("missing id or nodes");
+    };
+    let id = id.as_slice().try_into().context("node id is not 20 bytes long")?;
+    Ok((id, krpc::decode_nodes(nodes)))
+}
+
+impl Node {
+    async fn add_nodes(&self, addrs: impl IntoIterator<Item = SocketAddrV4>) {
+        let mut pings: FuturesUnordered<_> = addrs.into_iter().map(|addr| self.query(addr, Query::Ping)).collect();
+        while pings.next().await.is_some() {}
+    }
+
+    async fn refresh(&self) {
+        let questionable = self.table.lock().expect("table lock is never poisoned").questionable();
+        self.add_nodes(questionable.into_iter().map(|node| node.addr)).await;
+    }
+
+    async fn refresh_every(self: Arc<Self>, period: Duration) {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            ticks.tick().await;
+            self.refresh().await;
+        }
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut buf = [0u8; MAX_PACKET];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // e.g. ICMP port unreachable surfacing on some platforms; not fatal for a UDP node
+                Err(_) => continue,
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            if self.blocked(&from) {
+                continue;
+            }
+            let Ok(message) = Message::decode(&buf[..n]) else {
+                continue;
+            };
+            match message.body {
+                Body::Query { id, query } => {
+                    let node = NodeInfo { id, addr: from };
+                    let known = {
+                        let mut table = self.table.lock().expect("table lock is never poisoned");
+                        // a node of ours asking us something is alive
+                        table.contains(&node) && table.insert(node)
+                    };
+                    let body = self.answer(from, query);
+                    let reply = Message { transaction: message.transaction, body }.encode();
+                    let _ = self.socket.send_to(&reply, from).await;
+                    if !known {
+                        // a query's source address may be forged, so the node only gets into the
+                        // table by answering a ping of ours
+                        let this = self.clone();
+                        tokio::spawn(async move { this.add_nodes([from]).await });
+                    }
+                }
+                body => {
+                    let mut pending = self.pending.lock().expect("pending lock is never poisoned");
+                    // only the node we asked gets to answer
+                    if pending.get(&message.transaction).is_some_and(|(asked, _)| *asked == from) {
+                        let (_, answer) = pending.remove(&message.transaction).expect("just checked");
+                        let _ = answer.send(body);
+                    }
+                }
+            }
+        }
+    }
+
+    fn token(&self, secret: &[u8; 20], addr: &SocketAddrV4) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(addr.ip().octets());
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn secrets(&self) -> std::sync::MutexGuard<'_, Secrets> {
+        let mut secrets = self.secrets.lock().expect("secrets lock is never poisoned");
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION {
+            let mut current = [0u8; 20];
+            // should the generator ever fail us, the current secret just serves another round
+            if secrets.source.read_exact(&mut current).is_ok() {
+                secrets.previous = secrets.current;
+                secrets.current = current;
+            }
+            secrets.rotated = Instant::now();
+        }
+        secrets
+    }
+
+    fn answer(&self, from: SocketAddrV4, query: Query) -> Body {
+        let table = self.table.lock().expect("table lock is never poisoned");
+        let response = match query {
+            Query::Ping => Response::default(),
+            Query::FindNode { target } => Response { nodes: table.closest(&target, K), ..Default::default() },
+            Query::GetPeers { info_hash } => {
+                let token = Some(self.token(&self.secrets().current, &from));
+                let mut peers = self.peers.lock().expect("peers lock is never poisoned");
+                let values: Vec<SocketAddrV4> = match peers.get_mut(&info_hash) {
+                    Some(known) => {
+                        known.retain(|(_, announced)| announced.elapsed() < PEER_TTL);
+                        known.iter().map(|(peer, _)| *peer).collect()
+                    }
+                    None => Vec::new(),
+                };
+                let nodes = if values.is_empty() { table.closest(&info_hash, K) } else { Vec::new() };
+                Response { nodes, values, token }
+            }
+            Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                let valid = {
+                    let secrets = self.secrets();
+                    token == self.token(&secrets.current, &from) || token == self.token(&secrets.previous, &from)
+                };
+                if !valid {
+                    return Body::Error { code: krpc::ERROR_PROTOCOL, message: "bad token".to_string() };
+                }
+                let peer = SocketAddrV4::new(*from.ip(), if implied_port { from.port() } else { port });
+                let mut peers = self.peers.lock().expect("peers lock is never poisoned");
+                let known = peers.entry(info_hash).or_default();
+                known.retain(|(known, announced)| *known != peer && announced.elapsed() < PEER_TTL);
+                if known.len() < MAX_PEERS_PER_TORRENT {
+                    known.push((peer, Instant::now()));
+                }
+                Response::default()
+            }
+        };
+        Body::Response { id: self.id, response }
+    }
+
+    fn blocked(&self, addr: &SocketAddrV4) -> bool {
+        self.config.ip_filter.as_ref().is_some_and(|filter| filter.blocks(addr))
+    }
+
+    async fn query(&self, addr: SocketAddrV4, query: Query) -> anyhow::Result<(NodeId, Response)> {
+        anyhow::ensure!(!self.blocked(&addr), "{addr} is blocked");
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let (answer, answered) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("pending lock is never poisoned")
+            .insert(transaction.clone(), (addr, answer));
+        let packet = Message { transaction: transaction.clone(), body: Body::Query { id: self.id, query } }.encode();
+        let sent = self.socket.send_to(&packet, addr).await;
+        let answer = match sent {
+            Ok(_) => tokio::time::timeout(QUERY_TIMEOUT, answered).await,
+            Err(e) => {
+                self.pending.lock().expect("pending lock is never poisoned").remove(&transaction);
+                return Err(e).with_context(|| format!("send query to {addr}"));
+            }
+        };
+        self.pending.lock().expect("pending lock is never poisoned").remove(&transaction);
+        match answer {
+            Ok(Ok(Body::Response { id, response })) => {
+                self.table.lock().expect("table lock is never poisoned").insert(NodeInfo { id, addr });
+                Ok((id, response))
+            }
+            Ok(Ok(Body::Error { code, message })) => anyhow::bail!("{addr} answered with error {code}: {message}"),
+            Ok(Ok(Body::Query { .. })) => unreachable!("queries are answered, not forwarded"),
+            Ok(Err(_)) | Err(_) => {
+                self.table.lock().expect("table lock is never poisoned").failed(&addr);
+                anyhow::bail!("{addr} did not answer")
+            }
+        }
+    }
+
+    /// Walks towards `target`, asking the closest nodes we know of for even closer ones until the
+    /// closest [`K`] have all been asked.
+    async fn lookup(&self, target: NodeId, query: Query) -> Lookup {
+        let mut shortlist: BTreeMap<NodeId, NodeInfo> = self
+            .table
+            .lock()
+            .expect("table lock is never poisoned")
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered = BTreeMap::new();
+        let mut peers = Vec::new();
+        loop {
+            let batch: Vec<NodeInfo> = shortlist
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let mut queries: FuturesUnordered<_> = batch
+                .into_iter()
+                .map(|node| {
+                    queried.insert(node.addr);
+                    let query = query.clone();
+                    async move { (node, self.query(node.addr, query).await) }
+                })
+                .collect();
+            while let Some((node, result)) = queries.next().await {
+                match result {
+                    Ok((id, response)) => {
+                        for peer in response.values {
+                            if !peers.contains(&peer) && !self.blocked(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                        for found in response.nodes {
+                            if found.id != self.id && !queried.contains(&found.addr) {
+                                shortlist.entry(distance(&found.id, &target)).or_insert(found);
+                            }
+                        }
+                        // the node may not be who another node claimed it to be
+                        shortlist.remove(&distance(&node.id, &target));
+                        let node = NodeInfo { id, addr: node.addr };
+                        shortlist.insert(distance(&id, &target), node);
+                        answered.insert(distance(&id, &target), (node, response.token));
+                    }
+                    Err(_) => {
+                        shortlist.remove(&distance(&node.id, &target));
+                    }
+                }
+            }
+        }
+        Lookup { peers, closest: answered.into_values().take(K).collect() }
+    }
+}
+
+#[tokio::test]
+async fn test_local_cluster() {
+    use std::net::Ipv4Addr;
+    let config = |bootstrap: Vec<String>, state: Option<PathBuf>| DhtConfig {
+        bind: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        bootstrap,
+        state,
+        ip_filter: None,
+        events: Events::new(),
+    };
+    let seed = Dht::bind(config(Vec::new(), None)).await.unwrap();
+    let seed_addr = vec![seed.local_addr().to_string()];
+    let mut nodes = Vec::new();
+    for _ in 0..24 {
+        let node = Dht::bind(config(seed_addr.clone(), None)).await.unwrap();
+        node.bootstrap().await.unwrap();
+        nodes.push(node);
+    }
+
+    // a bootstrap node that cannot be resolved is reported, and the others still do
+    let events = Events::new();
+    let reported = events.subscribe();
+    tokio::pin!(reported);
+    let node = Dht::bind(DhtConfig { events, ..config(vec!["nowhere".to_string(), seed_addr[0].clone()], None) }).await.unwrap();
+    node.bootstrap().await.unwrap();
+    let Some(Event::Log { info_hash: None, message }) = reported.next().await else {
+        panic!("the unresolved bootstrap node was not reported");
+    };
+    assert!(message.contains("nowhere"), "{message}");
+
+    let info_hash = [0x42; 20];
+    assert!(nodes[3].announce(info_hash, Some(51413)).await > 0);
+    let peers = nodes[17].get_peers(info_hash).await;
+    assert_eq!(peers, vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 51413)]);
+
+    // a restarted node comes back with the same ID and knows where to start from
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("dht.dat");
+    let node = Dht::bind(config(Vec::new(), Some(state.clone()))).await.unwrap();
+    node.add_nodes([seed.local_addr()]).await;
+    node.bootstrap().await.unwrap();
+    node.save().unwrap();
+    let id = node.id();
+    drop(node);
+    let node = Dht::bind(config(Vec::new(), Some(state))).await.unwrap();
+    assert_eq!(node.id(), id);
+    assert!(node.nodes() > 0);
+    node.bootstrap().await.unwrap();
+    assert_eq!(node.get_peers(info_hash).await, peers);
+}
+
+#[tokio::test]
+async fn test_untrusted_packets() {
+    use std::net::Ipv4Addr;
+    let node = Dht::bind(DhtConfig { bind: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), bootstrap: Vec::new(), ..Default::default() })
+        .await
+        .unwrap();
+    let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    // nesting that would run the decoder out of stack, in a datagram too big for KRPC and in one
+    // that fits
+    let nested = [&b"d1:a"[..], &[b'l'; 65_000]].concat();
+    other.send_to(&nested, node.local_addr()).await.unwrap();
+    other.send_to(&nested[..MAX_PACKET], node.local_addr()).await.unwrap();
+
+    // the node is still there to answer, but a query alone does not get the sender into its
+    // routing table: answering the node's own ping does
+    let ping = Message { transaction: b"aa".to_vec(), body: Body::Query { id: [7; 20], query: Query::Ping } };
+    other.send_to(&ping.encode(), node.local_addr()).await.unwrap();
+    let mut buf = [0u8; MAX_PACKET];
+    let (n, _) = other.recv_from(&mut buf).await.unwrap();
+    assert!(matches!(Message::decode(&buf[..n]).unwrap().body, Body::Response { .. }));
+    let (n, _) = other.recv_from(&mut buf).await.unwrap();
+    let theirs = Message::decode(&buf[..n]).unwrap();
+    assert!(matches!(theirs.body, Body::Query { query: Query::Ping, .. }));
+    assert_eq!(node.nodes(), 0);
+    let pong = Message { transaction: theirs.transaction, body: Body::Response { id: [7; 20], response: Response::default() } };
+    other.send_to(&pong.encode(), node.local_addr()).await.unwrap();
+    while node.nodes() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
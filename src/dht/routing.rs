@@ -0,0 +1,169 @@
+//! The Kademlia routing table: known nodes, kept in buckets by how close they are to us.
+
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use super::krpc::NodeInfo;
+
+pub type NodeId = [u8; 20];
+
+/// Nodes per bucket.
+pub const K: usize = 8;
+
+/// Unanswered queries after which a node may be replaced by a newcomer.
+const MAX_FAILURES: u32 = 2;
+
+/// Nodes that have not been heard from for this long are asked whether they are still around.
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// The bucket for `id`: the number of leading bits it shares with `own`. `None` for `own` itself.
+fn bucket_index(own: &NodeId, id: &NodeId) -> Option<usize> {
+    let distance = distance(own, id);
+    let zeros = distance
+        .iter()
+        .position(|&byte| byte != 0)
+        .map(|byte_i| byte_i * 8 + distance[byte_i].leading_zeros() as usize)?;
+    Some(zeros)
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// A routing table with one bucket of up to [`K`] nodes per shared-prefix length.
+///
+/// Nodes that keep answering stay: a full bucket only takes a newcomer in place of a node that has
+/// stopped answering.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self { own, buckets: vec![Vec::new(); 160] }
+    }
+
+    pub fn own_id(&self) -> &NodeId {
+        &self.own
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records that `node` is alive. Returns whether it is in the table afterwards.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(bucket_i) = bucket_index(&self.own, &node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[bucket_i];
+        let now = Instant::now();
+        if let Some(i) = bucket.iter().position(|entry| entry.node.id == node.id) {
+            // move it to the back, where the most recently seen nodes are
+            let mut entry = bucket.remove(i);
+            entry.node.addr = node.addr;
+            entry.last_seen = now;
+            entry.failures = 0;
+            bucket.push(entry);
+            return true;
+        }
+        let entry = Entry { node, last_seen: now, failures: 0 };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        if let Some(i) = bucket.iter().position(|entry| entry.failures >= MAX_FAILURES) {
+            bucket.remove(i);
+            bucket.push(entry);
+            return true;
+        }
+        false
+    }
+
+    /// Whether `node` is in the table, at that address.
+    pub fn contains(&self, node: &NodeInfo) -> bool {
+        let Some(bucket_i) = bucket_index(&self.own, &node.id) else {
+            return false;
+        };
+        self.buckets[bucket_i].iter().any(|entry| entry.node == *node)
+    }
+
+    /// Records that the node at `addr` did not answer a query.
+    pub fn failed(&mut self, addr: &SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.addr == *addr) {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// Up to `n` known nodes, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<&Entry> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .collect();
+        nodes.sort_by_key(|entry| distance(&entry.node.id, target));
+        nodes.into_iter().take(n).map(|entry| entry.node).collect()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.buckets.iter().flatten().map(|entry| entry.node)
+    }
+
+    /// Nodes that have not been heard from in a while and should be pinged.
+    pub fn questionable(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.last_seen.elapsed() >= QUESTIONABLE_AFTER || entry.failures > 0)
+            .map(|entry| entry.node)
+            .collect()
+    }
+}
+
+#[test]
+fn test_routing_table_buckets() {
+    use std::net::Ipv4Addr;
+    let own = [0u8; 20];
+    let mut table = RoutingTable::new(own);
+    let node = |first: u8, last: u8| NodeInfo {
+        id: {
+            let mut id = [0u8; 20];
+            id[0] = first;
+            id[19] = last;
+            id
+        },
+        addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000 + u16::from(first) * 256 + u16::from(last)),
+    };
+    assert!(!table.insert(NodeInfo { id: own, addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1) }));
+    // all of these share no prefix with us, so they land in the same bucket
+    for last in 0..K as u8 {
+        assert!(table.insert(node(0x80, last)));
+    }
+    assert!(!table.insert(node(0x80, 100)));
+    // until one of the old ones stops answering
+    table.failed(&node(0x80, 3).addr);
+    table.failed(&node(0x80, 3).addr);
+    assert!(table.insert(node(0x80, 100)));
+    assert_eq!(table.len(), K);
+
+    table.insert(node(0x01, 0));
+    assert_eq!(table.closest(&node(0x01, 1).id, 1), vec![node(0x01, 0)]);
+    assert_eq!(bucket_index(&own, &node(0x01, 0).id), Some(7));
+}
@@ -1,172 +1,686 @@
 
-use std::{collections::BinaryHeap, vec};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures_util::StreamExt;
+use std::time::Instant;
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
 use sha1::{Sha1, Digest};
-use tokio::task::JoinSet;
 use anyhow::Context;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
 
-use crate::{peers::{MessageTag, Peer, Piece}, piece::{self, PieceInfo}, torrent::{FileInfo, Keys, Torrent}, tracker::TrackerResponse};
-use crate::BLOCK_MAX;
+use crate::{connections::{ConnectionLimits, GlobalLimit, PeerPool, SmartBan}, dht::Dht, events::{Event, Events, Stats}, ipfilter::IpFilter, lsd::Lsd, mse::Encryption, ratelimit::BandwidthLimits, peers::{Peer, PeerStats, PieceBlock, Transferred}, proxy::Proxy, transport::{Connector, Transport, TransportPolicy}, piece::{PickerKind, PieceInfo, Priority}, scheduler::Scheduler, selection::FileSelection, storage::{Layout, Storage}, streaming::{Playhead, Streaming}, torrent::{FileInfo, Torrent}, tracker::TrackerResponse};
 
-pub(crate) async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
-    let info_hash = t.clone().info_hash();
-    let peer_info = TrackerResponse::query_tracker_info(t, info_hash)
-        .await
-        .context("query tracker for peer info")?;
-
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5 /* user config */);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                peer_list.push(peer);
-                if peer_list.len() >= 5
-                /* TODO: user config */
-                {
-                    break;
-                }
+/// Knobs for a single download. The defaults are what [`Torrent::download_all`] uses.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// The strategy that decides which piece to start next.
+    pub picker: PickerKind,
+    /// The files to download and their priorities.
+    pub files: FileSelection,
+    /// Fetch the pieces right after this playhead first, for reading the data while it downloads.
+    pub playhead: Option<Playhead>,
+    /// Also look for peers in the DHT, and carry on if the tracker cannot be reached.
+    pub dht: Option<Arc<Dht>>,
+    /// Also announce the torrent on the local network and use the peers found there.
+    pub lsd: Option<Arc<Lsd>>,
+    /// Whether to obfuscate connections to peers.
+    pub encryption: Encryption,
+    /// Whether to reach peers over TCP, uTP or either.
+    pub transport: TransportPolicy,
+    /// Opens the connections to peers instead of the transport `transport` picks.
+    pub connector: Option<Arc<dyn Connector>>,
+    /// Send tracker requests and peer connections through this proxy. Peers are then reached
+    /// over TCP whatever `transport` says, since a proxy cannot carry uTP. The `dht` and `lsd`
+    /// still talk to the network directly, so leave them unset to keep all traffic on the proxy.
+    pub proxy: Option<Proxy>,
+    /// How many peers to keep connections open to.
+    pub limits: ConnectionLimits,
+    /// Never connect to peers in these ranges, wherever they were found.
+    pub ip_filter: Option<Arc<IpFilter>>,
+    /// How fast to download and upload.
+    pub bandwidth: BandwidthLimits,
+    /// The port we accept peers on, for trackers and the DHT to hand out; 6881 if none is set.
+    pub listen_port: Option<u16>,
+    /// Peers to try besides the ones discovery finds, such as those a magnet link names.
+    pub peers: Vec<SocketAddrV4>,
+    /// Where to report what happens while the download runs.
+    pub events: Events,
+}
+
+/// How long to listen for local peers when no other source found any.
+const LSD_WAIT: Duration = Duration::from_secs(5);
+/// How often to look for connections to open, when nothing else happens.
+const TICK: Duration = Duration::from_secs(1);
+/// How often to consider replacing the slowest peer.
+const SLOW_CHECK: Duration = Duration::from_secs(30);
+/// Discovery runs again when the pool runs dry, but no more often than this.
+const REDISCOVER: Duration = Duration::from_secs(60);
+/// Peers that had a hand in this many pieces that failed their hash check are dropped for good,
+/// once one of those pieces came from them alone.
+const MAX_STRIKES: u32 = 3;
+
+impl DownloadOptions {
+    pub fn file_priorities(&self, layout: &Layout) -> Vec<Priority> {
+        self.files.file_priorities(layout.files())
+    }
+}
+
+/// Collects the addresses of peers from every discovery source that is enabled.
+async fn find_peers(t: &Torrent, info_hash: [u8; 20], options: &DownloadOptions) -> anyhow::Result<Vec<SocketAddrV4>> {
+    let mut candidates = options.peers.clone();
+    let port = options.listen_port.unwrap_or(6881);
+    // a torrent that came from a magnet link without trackers has no announce url
+    if !t.announce.is_empty() {
+        match TrackerResponse::query_tracker_info(t, info_hash, port, options.proxy.as_ref()).await {
+            Ok(response) => {
+                options.events.send(Event::TrackerReply { info_hash, tracker: t.announce.clone(), peers: response.peers.0.len() });
+                candidates.extend(response.peers.0.into_iter().filter(|peer| !options.peers.contains(peer)));
             }
             Err(e) => {
-                eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
+                options.events.send(Event::TrackerError { info_hash, tracker: t.announce.clone(), error: format!("{e:#}") });
+                if options.dht.is_none() && options.lsd.is_none() && candidates.is_empty() {
+                    return Err(e).context("query tracker for peer info");
+                }
             }
         }
     }
-    drop(peers);
-    let mut peers = peer_list;
-
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = PieceInfo::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
+    if let Some(dht) = &options.dht {
+        if options.listen_port.is_some() {
+            dht.announce(info_hash, Some(port)).await;
+        }
+        for peer in dht.get_peers(info_hash).await {
+            if !candidates.contains(&peer) {
+                candidates.push(peer);
+            }
+        }
+    }
+    if let Some(lsd) = &options.lsd {
+        if let Err(e) = lsd.announce(info_hash).await {
+            options.events.send(Event::Log { info_hash: Some(info_hash), message: format!("{e:#}") });
+        }
+        let local = if candidates.is_empty() {
+            lsd.wait_for_peers(&info_hash, LSD_WAIT).await
         } else {
-            need_pieces.push(piece);
+            lsd.peers(&info_hash)
+        };
+        // peers on the same network are the cheapest to download from, so try them first
+        candidates.retain(|peer| !local.contains(peer));
+        candidates.splice(0..0, local);
+    }
+    if let Some(filter) = &options.ip_filter {
+        let found = candidates.len();
+        candidates.retain(|peer| !filter.blocks(peer));
+        if candidates.len() < found {
+            let message = format!("ip filter blocked {} of {found} peers", found - candidates.len());
+            options.events.send(Event::Log { info_hash: Some(info_hash), message });
+        }
+    }
+    anyhow::ensure!(!candidates.is_empty(), "found no peers for the torrent");
+    Ok(candidates)
+}
+
+/// Opens the connections to peers that `options` asks for.
+pub(crate) async fn connector(options: &DownloadOptions) -> anyhow::Result<Arc<dyn Connector>> {
+    Ok(match (&options.connector, &options.proxy) {
+        (Some(connector), _) => connector.clone(),
+        (None, Some(proxy)) => Arc::new(proxy.clone()),
+        (None, None) => Arc::new(Transport::new(options.transport, options.events.clone()).await?),
+    })
+}
+
+/// Downloads the pieces of `t` that the selected files need into `storage`.
+pub(crate) async fn all(t: &Torrent, options: &DownloadOptions, storage: &dyn Storage) -> anyhow::Result<()> {
+    run(t, t.clone().info_hash(), options, storage, &HashSet::new(), None).await
+}
+
+/// Downloads the pieces of `t` that the selected files need and that are not in `have` already
+/// into `storage`, also taking on the peers that connected to us and come in through `inbound`.
+///
+/// Peers come and go while it runs: connections are opened from the pool of discovered addresses
+/// up to the [`ConnectionLimits`], peers that fail are replaced, the slowest one is swapped out
+/// now and then for a fresh address, and discovery runs again when the pool runs dry. A piece that
+/// fails its hash check is fetched again, and the peers that sent it get a strike; once a copy
+/// checks out, the peers whose blocks differ from it are banned. When no peer is left for the
+/// missing pieces the download waits for discovery to find new ones.
+pub(crate) async fn run(
+    t: &Torrent,
+    info_hash: [u8; 20],
+    options: &DownloadOptions,
+    storage: &dyn Storage,
+    have: &HashSet<usize>,
+    mut inbound: Option<&mut tokio::sync::mpsc::Receiver<Peer>>,
+) -> anyhow::Result<()> {
+    let npieces = t.info.pieces.0.len();
+    let limits = &options.limits;
+    anyhow::ensure!(limits.per_torrent > 0, "cannot download without connecting to any peers");
+    let mut pool = PeerPool::new();
+    pool.add(find_peers(t, info_hash, options).await?, Instant::now());
+    let mut discovered_at = Instant::now();
+    let connector = connector(options).await?;
+    let connector = &*connector;
+
+    let pieces: Vec<PieceInfo> = (0..npieces)
+        .map(|piece_i| PieceInfo::new(piece_i, t))
+        .collect();
+    let hashes: HashMap<usize, ([u8; 20], usize)> = pieces
+        .iter()
+        .map(|piece| (piece.index(), (piece.hash(), piece.length())))
+        .collect();
+    let layout = Layout::new(t);
+    let file_priorities = options.file_priorities(&layout);
+    let priorities = layout.piece_priorities(&file_priorities);
+    let wanted: Vec<usize> = (0..pieces.len())
+        .filter(|&piece_i| priorities[piece_i] != Priority::Skip)
+        .collect();
+    let need: Vec<usize> = wanted.iter().copied().filter(|piece_i| !have.contains(piece_i)).collect();
+    let events = &options.events;
+    let log = |message: String| events.send(Event::Log { info_hash: Some(info_hash), message });
+    let mut stats = Stats {
+        pieces: wanted.len() - need.len(),
+        wanted_pieces: wanted.len(),
+        verified: wanted.iter().filter(|piece_i| have.contains(piece_i)).map(|piece_i| hashes[piece_i].1).sum(),
+        wanted: wanted.iter().map(|piece_i| hashes[piece_i].1).sum(),
+        ..Default::default()
+    };
+    let transferred = Arc::new(Transferred::default());
+    let mut reported_at = Instant::now();
+    // the pieces each wanted file still misses, to tell when it is complete
+    let mut file_missing: HashMap<usize, usize> = HashMap::new();
+    for &piece_i in &need {
+        for file in layout.piece_files(piece_i).filter(|&file| file_priorities[file] != Priority::Skip) {
+            *file_missing.entry(file).or_default() += 1;
         }
     }
+    let mut picker = options.picker.build((!options.files.is_everything()).then_some(priorities));
+    if let Some(playhead) = &options.playhead {
+        picker = Box::new(Streaming::new(playhead.clone(), picker));
+    }
+    let mut missing: HashSet<usize> = need.iter().copied().collect();
+    let scheduler = Scheduler::new(pieces, need, picker, 2 * limits.per_torrent.max(1));
+    let scheduler = &scheduler;
+    let (finish, mut done) = tokio::sync::mpsc::channel(limits.per_torrent.max(1));
+
+    let mut connecting = FuturesUnordered::new();
+    let mut participants = FuturesUnordered::new();
+    let mut live: HashMap<SocketAddrV4, Participant> = HashMap::new();
+    let mut discovery = None;
+    let mut tick = tokio::time::interval(TICK);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut slow_check = tokio::time::interval_at(tokio::time::Instant::now() + SLOW_CHECK, SLOW_CHECK);
 
-    // TODO
-    //assert!(no_peers.is_empty());
-
-    let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        // the + (BLOCK_MAX - 1) rounds up
-        let piece_size = piece.length();
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers: Vec<_> = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
-            .collect();
-
-        let (submit, tasks) = kanal::bounded_async(nblocks);
-        for block in 0..nblocks {
-            submit
-                .send(block)
-                .await
-                .expect("bound holds all these items");
+    // pieces that have some, but not all, of their blocks
+    let mut in_flight: HashMap<usize, InFlight> = HashMap::new();
+    // how many pieces each peer helped corrupt, for when we cannot tell which one did it
+    let mut strikes: HashMap<SocketAddrV4, u32> = HashMap::new();
+    let mut smart_ban = SmartBan::default();
+    // whether we said that no peer is left for the missing pieces
+    let mut parked = false;
+    while !missing.is_empty() {
+        while live.len() + connecting.len() < limits.per_torrent && connecting.len() < limits.half_open {
+            let permit = match &limits.global {
+                Some(global) => match global.try_acquire() {
+                    Some(permit) => Some(permit),
+                    None => break,
+                },
+                None => None,
+            };
+            let Some(peer_addr) = pool.next(Instant::now()) else {
+                break;
+            };
+            connecting.push(async move {
+                let peer = Peer::new(peer_addr, info_hash, npieces, connector, options.encryption, options.dht.clone()).await;
+                (peer_addr, peer, permit)
+            });
         }
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
-        let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                nblocks,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
+        if discovery.is_none() && !pool.has_prospects() && live.len() < limits.per_torrent {
+            if discovered_at.elapsed() >= REDISCOVER {
+                discovery = Some(Box::pin(find_peers(t, info_hash, options)));
+            } else if live.is_empty() && connecting.is_empty() && !parked {
+                // the missing pieces wait in the scheduler until discovery turns up someone new
+                let piece_i = missing.iter().min().expect("some pieces are missing");
+                log(format!("no peers left to get piece {piece_i}, waiting for new ones"));
+                parked = true;
+            }
         }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
-
-        let mut all_blocks = vec![0u8; piece_size];
-        let mut bytes_received = 0;
-        loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
-                    match joined {
-                        None => {
-                            // there are no peers!
-                            // this must mean we are about to get None from done.recv(),
-                            // so we'll handle it there
+
+        tokio::select! {
+            Some((peer_addr, peer, permit)) = connecting.next() => match peer {
+                Ok(mut peer) if pool.connected(peer_addr, peer.peer_id()) => {
+                    peer.throttle(options.bandwidth.throttle());
+                    peer.count_into(transferred.clone());
+                    events.send(Event::PeerConnected { info_hash, peer: peer_addr });
+                    parked = false;
+                    let retire = CancellationToken::new();
+                    live.insert(peer_addr, Participant { retire: retire.clone(), recent: 0, total: 0, stats: peer.shared_stats() });
+                    participants.push(participate(peer, scheduler, finish.clone(), retire, permit));
+                }
+                Ok(_) => {
+                    log(format!("peer {peer_addr} is already connected under another address"));
+                }
+                Err(e) => {
+                    log(format!("failed to connect to peer {peer_addr}: {e:#}"));
+                    pool.failed(peer_addr, Instant::now());
+                }
+            },
+            Some(mut peer) = async { inbound.as_mut().expect("only polled with inbound peers").recv().await }, if inbound.is_some() => {
+                let peer_addr = peer.peer_addr();
+                let permit = limits.global.as_ref().map(GlobalLimit::try_acquire);
+                if live.len() >= limits.per_torrent || matches!(permit, Some(None)) {
+                    log(format!("turning away peer {peer_addr}: too many connections"));
+                    continue;
+                }
+                pool.add([peer_addr], Instant::now());
+                if !pool.connected(peer_addr, peer.peer_id()) {
+                    log(format!("turning away peer {peer_addr}: already connected, or banned"));
+                    continue;
+                }
+                peer.throttle(options.bandwidth.throttle());
+                peer.count_into(transferred.clone());
+                events.send(Event::PeerConnected { info_hash, peer: peer_addr });
+                parked = false;
+                let retire = CancellationToken::new();
+                live.insert(peer_addr, Participant { retire: retire.clone(), recent: 0, total: 0, stats: peer.shared_stats() });
+                participants.push(participate(peer, scheduler, finish.clone(), retire, permit.flatten()));
+            }
+            Some((peer, result)) = participants.next() => {
+                let peer_addr = peer.peer_addr();
+                let participant = live.remove(&peer_addr).expect("every participant is live");
+                // a peer that ends in good order has nothing left that we need, or was retired;
+                // one that fails has already handed its outstanding block back to the scheduler.
+                // Either way, one that never delivered anything is not worth many more tries.
+                let failed = result.is_err() || participant.total == 0;
+                pool.disconnected(peer_addr, peer.peer_id(), failed, Instant::now());
+                events.send(Event::PeerDisconnected { info_hash, peer: peer_addr, error: result.err().map(|e| format!("{e:#}")) });
+            }
+            found = async { discovery.as_mut().expect("only polled while discovering").await }, if discovery.is_some() => {
+                discovery = None;
+                discovered_at = Instant::now();
+                match found {
+                    Ok(found) => {
+                        if pool.add(found, Instant::now()) > 0 {
+                            parked = false;
                         }
-                        Some(Ok(_)) => {
-                            // the peer gave up because it timed out
-                            // nothing to do, except maybe de-prioritize this peer for later
-                            // TODO
+                    }
+                    Err(e) => log(format!("failed to find more peers: {e:#}")),
+                }
+            }
+            Some((peer_addr, piece)) = done.recv() => {
+                if let Some(participant) = live.get_mut(&peer_addr) {
+                    participant.recent += piece.block.len() as u64;
+                    participant.total += piece.block.len() as u64;
+                }
+                // only the first copy of every block makes it here
+                let piece_i = piece.index as usize;
+                let (hash, piece_size) = hashes[&piece_i];
+                let in_flight_piece = in_flight
+                    .entry(piece_i)
+                    .or_insert_with(|| InFlight { data: vec![0u8; piece_size], received: 0, from: BTreeMap::new() });
+                let begin = piece.begin as usize;
+                in_flight_piece.data[begin..begin + piece.block.len()].copy_from_slice(&piece.block);
+                in_flight_piece.received += piece.block.len();
+                in_flight_piece.from.insert(begin, peer_addr);
+                if in_flight_piece.received < piece_size {
+                    continue;
+                }
+
+                let InFlight { data: all_blocks, from, .. } = in_flight.remove(&piece_i).expect("just looked it up");
+                let mut hasher = Sha1::new();
+                hasher.update(&all_blocks);
+                let piece_hash: [u8; 20] = hasher.finalize().into();
+                if piece_hash != hash {
+                    events.send(Event::PieceFailed { info_hash, piece: piece_i });
+                    smart_ban.failed(piece_i, &all_blocks, &from);
+                    // when several peers sent the piece there is no telling which one spoiled it,
+                    // and an honest peer would go down with the one that did; smart ban sorts
+                    // those out once a copy checks out
+                    let senders: HashSet<SocketAddrV4> = from.into_values().collect();
+                    let alone = senders.len() == 1;
+                    for peer_addr in senders {
+                        let strikes = strikes.entry(peer_addr).or_default();
+                        *strikes += 1;
+                        if alone && *strikes >= MAX_STRIKES {
+                            events.send(Event::PeerBanned { info_hash, peer: peer_addr });
+                            pool.ban(peer_addr);
+                            if let Some(participant) = live.get(&peer_addr) {
+                                participant.retire.cancel();
+                            }
                         }
-                        Some(Err(_)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            // TODO
+                    }
+                    scheduler.retry(piece_i);
+                    // peers that left because they had nothing more for us may have this piece
+                    pool.recall(Instant::now());
+                    continue;
+                }
+
+                for peer_addr in smart_ban.passed(piece_i, &all_blocks) {
+                    events.send(Event::PeerBanned { info_hash, peer: peer_addr });
+                    pool.ban(peer_addr);
+                    if let Some(participant) = live.get(&peer_addr) {
+                        participant.retire.cancel();
+                    }
+                }
+
+                storage
+                    .write(piece_i * t.info.plength, &all_blocks)
+                    .with_context(|| format!("write piece {piece_i}"))?;
+                missing.remove(&piece_i);
+                stats.pieces += 1;
+                stats.verified += piece_size;
+                events.send(Event::PieceVerified { info_hash, piece: piece_i });
+                for file in layout.piece_files(piece_i) {
+                    if let Some(missing) = file_missing.get_mut(&file) {
+                        *missing -= 1;
+                        if *missing == 0 {
+                            events.send(Event::FileCompleted { info_hash, file });
                         }
                     }
                 }
-                piece = done.recv() => {
-                    if let Some(piece) = piece {
-                        // keep track of the bytes in message
-                        let piece = crate::peers::Piece::ref_from_bytes(&piece.payload[..])
-                            .expect("always get all Piece response fields from peer");
-                        bytes_received += piece.block().len();
-                        all_blocks[piece.begin() as usize..].copy_from_slice(piece.block());
-                    } else {
-                        // have received every piece (or no peers left)
-                        // this must mean that all participations have either exited or are waiting
-                        // for more work -- in either case, it is okay to drop all the participant
-                        // futures.
-                        break;
+            }
+            _ = slow_check.tick() => {
+                // make room for a fresh address by letting go of the peer that delivered least,
+                // if it is well behind the others
+                if live.len() >= limits.per_torrent && pool.has_prospects() {
+                    let recent: u64 = live.values().map(|participant| participant.recent).sum();
+                    let slowest = live.values().min_by_key(|participant| participant.recent);
+                    if let Some(slowest) = slowest {
+                        if slowest.recent * 2 * (live.len() as u64) < recent {
+                            slowest.retire.cancel();
+                        }
                     }
                 }
+                for participant in live.values_mut() {
+                    participant.recent = 0;
+                }
+            }
+            _ = tick.tick() => {
+                // addresses whose backoff ran out are picked up at the top of the loop
+                stats.transferred(&transferred, live.len(), reported_at.elapsed());
+                reported_at = Instant::now();
+                events.send(Event::Stats { info_hash, stats, peers: peer_stats(&live) });
             }
         }
-        drop(participants);
+    }
+    stats.transferred(&transferred, live.len(), reported_at.elapsed());
+    events.send(Event::Stats { info_hash, stats, peers: peer_stats(&live) });
+    Ok(())
+}
 
-        if bytes_received == piece_size {
-            // great, we got all the bytes
-        } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the pieces we _didn't_ get from them.
-            // probably also stick this back onto the pieces_heap.
-            anyhow::bail!("no peers left to get piece {}", piece.index());
+fn peer_stats(live: &HashMap<SocketAddrV4, Participant>) -> Vec<(SocketAddrV4, PeerStats)> {
+    live.iter()
+        .map(|(&peer_addr, participant)| (peer_addr, *participant.stats.lock().expect("peer stats lock is never poisoned")))
+        .collect()
+}
+
+/// A piece whose blocks are arriving.
+struct InFlight {
+    data: Vec<u8>,
+    // bytes of `data` filled in so far
+    received: usize,
+    // the peer that sent each block, by offset
+    from: BTreeMap<usize, SocketAddrV4>,
+}
+
+/// A peer that is taking part in the download.
+struct Participant {
+    // stops the peer once it has no request outstanding
+    retire: CancellationToken,
+    // bytes delivered since the last look for slow peers
+    recent: u64,
+    // and since it connected
+    total: u64,
+    stats: Arc<std::sync::Mutex<PeerStats>>,
+}
+
+/// Runs one peer until it is done, then hands it back for the books.
+async fn participate(
+    mut peer: Peer,
+    scheduler: &Scheduler,
+    finish: tokio::sync::mpsc::Sender<(SocketAddrV4, PieceBlock)>,
+    retire: CancellationToken,
+    // the peer's share of the global connection limit, given back when it is done
+    _permit: Option<OwnedSemaphorePermit>,
+) -> (Peer, anyhow::Result<()>) {
+    let result = peer.participate(scheduler, finish, &retire).await;
+    (peer, result)
+}
+
+/// How a peer of [`ScriptedPeers`] behaves.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Script {
+    pub(crate) peer_id: [u8; 20],
+    // refuse the connection
+    pub(crate) refuses: bool,
+    // hang up on the request after this many blocks
+    pub(crate) blocks: Option<usize>,
+    // serve garbage instead of the data
+    pub(crate) corrupt: bool,
+}
+
+/// Peers for tests, each behind a duplex pipe: seeders that serve every piece of `data` as
+/// their [`Script`] says, or addresses that refuse connections.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct ScriptedPeers {
+    pub(crate) data: Arc<Vec<u8>>,
+    pub(crate) plength: usize,
+    // unlisted addresses refuse too
+    pub(crate) seeders: HashMap<SocketAddrV4, Script>,
+    // the info dictionary, for seeders to hand out to those who ask for it
+    pub(crate) metadata: Option<Arc<Vec<u8>>>,
+    pub(crate) served: Arc<std::sync::Mutex<HashMap<SocketAddrV4, usize>>>,
+    pub(crate) attempts: std::sync::Mutex<HashMap<SocketAddrV4, usize>>,
+}
+
+/// A single-file torrent of `npieces` pieces of `plength` bytes, whose tracker hands out the
+/// addresses of `peers` in order, and the [`ScriptedPeers`] that play them.
+#[cfg(test)]
+pub(crate) async fn scripted_torrent(
+    npieces: usize,
+    plength: usize,
+    peers: impl IntoIterator<Item = (SocketAddrV4, Script)>,
+) -> (Torrent, ScriptedPeers) {
+    use crate::{hash::Hashes, torrent::{Info, Keys}};
+    let data: Vec<u8> = (0..npieces * plength).map(|i| (i % 251) as u8).collect();
+    let hashes = data.chunks(plength).map(|piece| Sha1::digest(piece).into()).collect();
+    let peers: Vec<(SocketAddrV4, Script)> = peers.into_iter().collect();
+    let tracker = crate::tracker::udp_tracker_stand_in(peers.iter().map(|&(addr, _)| addr).collect()).await;
+    let t = Torrent {
+        announce: format!("udp://{tracker}/announce"),
+        info: Info { name: "test".to_string(), plength, pieces: Hashes(hashes), keys: Keys::SingleFile { length: data.len() } },
+        nodes: Vec::new(),
+    };
+    let peers = ScriptedPeers { data: Arc::new(data), plength, seeders: peers.into_iter().collect(), ..Default::default() };
+    (t, peers)
+}
+
+#[cfg(test)]
+impl Connector for ScriptedPeers {
+    fn connect(&self, addr: SocketAddrV4) -> futures_util::future::BoxFuture<'_, anyhow::Result<Box<dyn crate::transport::PeerStream>>> {
+        Box::pin(async move {
+            *self.attempts.lock().unwrap().entry(addr).or_default() += 1;
+            let Some(&script) = self.seeders.get(&addr).filter(|script| !script.refuses) else {
+                anyhow::bail!("connection to {addr} refused");
+            };
+            let (ours, theirs) = tokio::io::duplex(1 << 16);
+            let (data, plength, served, metadata) = (self.data.clone(), self.plength, self.served.clone(), self.metadata.clone());
+            tokio::spawn(async move {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                use crate::peers::Handshake;
+                let mut conn = theirs;
+                let mut handshake = [0u8; Handshake::LEN];
+                conn.read_exact(&mut handshake).await?;
+                let handshake = Handshake::decode(&handshake)?;
+                if let Some(metadata) = metadata.filter(|_| handshake.reserved[5] & Handshake::EXTENSION != 0) {
+                    return crate::metadata::serve(conn, handshake, &metadata).await;
+                }
+                conn.write_all(&Handshake::new(&handshake.info_hash, &script.peer_id).encode()).await?;
+                seed(conn, addr, script, data, plength, served).await
+            });
+            Ok(Box::new(ours) as Box<dyn crate::transport::PeerStream>)
+        })
+    }
+}
+
+/// Plays the seeder `addr` of [`ScriptedPeers`] over `conn`, once the handshakes are done, with
+/// `data` cut into pieces of `plength` bytes.
+#[cfg(test)]
+pub(crate) async fn seed(
+    conn: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    addr: SocketAddrV4,
+    Script { blocks, corrupt, .. }: Script,
+    data: Arc<Vec<u8>>,
+    plength: usize,
+    served: Arc<std::sync::Mutex<HashMap<SocketAddrV4, usize>>>,
+) -> anyhow::Result<()> {
+    use futures_util::SinkExt;
+    use crate::peers::{Bitfield, Message, MessageFramer};
+    let npieces = data.len().div_ceil(plength);
+    let mut conn = tokio_util::codec::Framed::new(conn, MessageFramer::for_pieces(npieces));
+    conn.send(Message::Bitfield(Bitfield::full(npieces))).await?;
+    while let Some(msg) = conn.next().await {
+        match msg? {
+            Message::Interested => conn.send(Message::Unchoke).await?,
+            Message::Request(_) if blocks.is_some_and(|blocks| served.lock().unwrap().get(&addr) == Some(&blocks)) => {
+                // hang up on the request
+                break;
+            }
+            Message::Request(request) => {
+                let begin = request.index as usize * plength + request.begin as usize;
+                let mut block = data[begin..begin + request.length as usize].to_vec();
+                if corrupt {
+                    block.iter_mut().for_each(|byte| *byte ^= 0xff);
+                }
+                let block = bytes::Bytes::from(block);
+                conn.send(Message::Piece(PieceBlock { index: request.index, begin: request.begin, block })).await?;
+                *served.lock().unwrap().entry(addr).or_default() += 1;
+            }
+            _ => {}
         }
+    }
+    Ok(())
+}
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]");
-        assert_eq!(hash, piece.hash());
-
-        all_pieces[piece.index() * t.info.plength..].copy_from_slice(&all_blocks);
-    }
-
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &t.info.keys {
-            Keys::SingleFile { length } => vec![FileInfo {
-                length: *length,
-                path: vec![t.info.name.clone()],
-            }],
-            Keys::MultiFile { files } => files.clone(),
-        },
-    })
+#[tokio::test]
+async fn test_replaces_failed_and_duplicate_peers() {
+    use crate::{storage::MemoryStorage, BLOCK_MAX};
+    use std::net::Ipv4Addr;
+    let npieces = 6;
+    let addr = |port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port);
+    // one that refuses, one that hangs up after a block, and the same seeder under two addresses
+    let (refuses, flaky, seeder, twin) = (addr(1), addr(2), addr(3), addr(4));
+    let (t, peers) = scripted_torrent(npieces, BLOCK_MAX, [
+        (refuses, Script { refuses: true, ..Default::default() }),
+        (flaky, Script { peer_id: [2; 20], blocks: Some(1), ..Default::default() }),
+        (seeder, Script { peer_id: [3; 20], ..Default::default() }),
+        (twin, Script { peer_id: [3; 20], ..Default::default() }),
+    ])
+    .await;
+    let (data, peers) = (peers.data.clone(), Arc::new(peers));
+    let options = DownloadOptions { connector: Some(peers.clone()), ..Default::default() };
+    let events = options.events.subscribe();
+    tokio::pin!(events);
+    let storage = MemoryStorage::new(data.len());
+    all(&t, &options, &storage).await.unwrap();
+    assert_eq!(storage.into_bytes(), *data);
+
+    // what happened, as told to whoever listens
+    let info_hash = t.clone().info_hash();
+    let mut reported = Vec::new();
+    while let Some(Some(event)) = futures_util::FutureExt::now_or_never(events.next()) {
+        reported.push(event);
+    }
+    assert_eq!(reported[0], Event::TrackerReply { info_hash, tracker: t.announce.clone(), peers: 4 });
+    assert!(reported.contains(&Event::PeerConnected { info_hash, peer: flaky }));
+    assert!(reported.iter().any(|event| matches!(event, Event::PeerDisconnected { peer, error: Some(_), .. } if *peer == flaky)));
+    let verified: HashSet<usize> = reported.iter().filter_map(|event| match event { Event::PieceVerified { piece, .. } => Some(*piece), _ => None }).collect();
+    assert_eq!(verified, (0..npieces).collect());
+    assert!(reported.contains(&Event::FileCompleted { info_hash, file: 0 }));
+    let Some(Event::Stats { stats, .. }) = reported.last() else {
+        panic!("the last word is the stats");
+    };
+    assert_eq!((stats.pieces, stats.wanted_pieces, stats.verified, stats.wanted), (npieces, npieces, data.len(), data.len()));
+    assert!(stats.downloaded >= data.len() as u64);
+
+    let served = peers.served.lock().unwrap();
+    assert_eq!(served.get(&flaky), Some(&1));
+    // whichever address of the seeder got through first, the other was turned away; with
+    // requests pipelined, end-game may well have it duplicate the block the flaky peer sent
+    let from_seeder = served.get(&seeder).unwrap_or(&0) + served.get(&twin).unwrap_or(&0);
+    assert!((npieces - 1..=npieces).contains(&from_seeder), "{served:?}");
+    assert!(served.get(&seeder).is_none() || served.get(&twin).is_none());
+    // every address was tried, the broken ones only once in the time it took
+    let attempts = peers.attempts.lock().unwrap();
+    assert_eq!(attempts.get(&refuses), Some(&1));
+    assert_eq!(attempts.get(&flaky), Some(&1));
 }
+
+#[tokio::test]
+async fn test_refetches_corrupt_pieces() {
+    use crate::{storage::MemoryStorage, BLOCK_MAX};
+    use std::net::Ipv4Addr;
+    // pieces of two blocks each
+    let npieces = 8;
+    let liar = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1);
+    let seeder = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1);
+    let (t, peers) = scripted_torrent(npieces, 2 * BLOCK_MAX, [
+        (liar, Script { peer_id: [1; 20], corrupt: true, ..Default::default() }),
+        (seeder, Script { peer_id: [2; 20], ..Default::default() }),
+    ])
+    .await;
+    let (data, peers) = (peers.data.clone(), Arc::new(peers));
+    let options = DownloadOptions { connector: Some(peers.clone()), ..Default::default() };
+    let storage = MemoryStorage::new(data.len());
+    all(&t, &options, &storage).await.unwrap();
+    // every piece the liar sent was thrown away and fetched again, from the seeder
+    assert_eq!(storage.into_bytes(), *data);
+    assert!(peers.served.lock().unwrap()[&seeder] >= 2 * npieces);
+}
+
+#[tokio::test]
+async fn test_bandwidth_limit_changes_while_running() {
+    use crate::{ratelimit::Bandwidth, storage::MemoryStorage, BLOCK_MAX};
+    use std::net::Ipv4Addr;
+    let npieces = 6;
+    let seeder = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1);
+    let (t, peers) = scripted_torrent(npieces, BLOCK_MAX, [(seeder, Script { peer_id: [1; 20], ..Default::default() })]).await;
+    let (data, peers) = (peers.data.clone(), Arc::new(peers));
+    // a block's worth a second would take six seconds
+    let global = Bandwidth::new(Some(BLOCK_MAX as u64), None);
+    let mut options = DownloadOptions { connector: Some(peers.clone()), ..Default::default() };
+    options.bandwidth.global = Some(global.clone());
+    let lift = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        global.download.set_rate(None);
+    });
+    let events = options.events.subscribe();
+    tokio::pin!(events);
+    let storage = MemoryStorage::new(data.len());
+    let start = std::time::Instant::now();
+    all(&t, &options, &storage).await.unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3), "{elapsed:?}");
+    assert_eq!(storage.into_bytes(), *data);
+    lift.await.unwrap();
+
+    // halfway through, the stats showed the seeder and what came from it
+    let mut seen = Vec::new();
+    while let Some(Some(event)) = futures_util::FutureExt::now_or_never(events.next()) {
+        if let Event::Stats { peers, .. } = event {
+            seen.extend(peers);
+        }
+    }
+    assert!(seen.iter().any(|(peer, stats)| *peer == seeder && stats.downloaded > 0 && stats.last_sent >= stats.connected_at), "{seen:?}");
+}
+
 pub struct Downloaded { 
-    bytes : Vec<u8>,
-    files : Vec<FileInfo>
+    pub(crate) bytes : Vec<u8>,
+    pub(crate) files : Vec<FileInfo>
 }
 
 impl<'a> IntoIterator for &'a Downloaded {
@@ -199,6 +713,7 @@ impl<'a> Iterator for DownloadIter<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let file = self.file_iter.next()?;
         let bytes = &self.downloaded.bytes[self.offset..][..file.length];
+        self.offset += file.length;
         Some(DownloadedFile { file , bytes})
     }
 }
@@ -214,6 +729,6 @@ impl<'a> DownloadedFile<'a> {
     }
 
     pub fn bytes(&self ) -> &'a [u8] { 
-        &self.bytes
+        self.bytes
     }
 }
\ No newline at end of file
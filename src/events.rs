@@ -0,0 +1,161 @@
+//! What downloads report as they go: peers coming and going, pieces checked, tracker replies,
+//! state changes and periodic statistics, as typed events that any number of subscribers read
+//! as a stream.
+
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures_util::Stream;
+use tokio::sync::broadcast;
+
+use crate::peers::{PeerStats, Transferred};
+use crate::session::TorrentState;
+
+/// Events a subscriber may fall behind by before it starts missing them.
+const CAPACITY: usize = 1024;
+
+/// Something that happened to a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    PeerConnected { info_hash: [u8; 20], peer: SocketAddrV4 },
+    /// The peer went away, in good order unless there is an `error`.
+    PeerDisconnected { info_hash: [u8; 20], peer: SocketAddrV4, error: Option<String> },
+    /// The peer is never to be connected to again, for sending corrupt data.
+    PeerBanned { info_hash: [u8; 20], peer: SocketAddrV4 },
+    /// A piece passed its hash check and was stored.
+    PieceVerified { info_hash: [u8; 20], piece: usize },
+    /// A piece failed its hash check, and will be fetched again.
+    PieceFailed { info_hash: [u8; 20], piece: usize },
+    TrackerReply { info_hash: [u8; 20], tracker: String, peers: usize },
+    TrackerError { info_hash: [u8; 20], tracker: String, error: String },
+    /// A torrent of a session moved on to `state`.
+    StateChanged { info_hash: [u8; 20], state: TorrentState },
+    /// Every piece of the file, by its index in the torrent, is stored.
+    FileCompleted { info_hash: [u8; 20], file: usize },
+    /// Sent every second while a torrent downloads, and once more when it is done, with the
+    /// traffic of every peer connected at the moment.
+    Stats { info_hash: [u8; 20], stats: Stats, peers: Vec<(SocketAddrV4, PeerStats)> },
+    /// Anything else worth telling someone who watches closely, about a torrent or, without an
+    /// `info_hash`, about what the torrents share: the DHT node, local discovery, uTP, the port
+    /// peers connect to.
+    Log { info_hash: Option<[u8; 20]>, message: String },
+    /// The subscriber fell behind, and missed this many events.
+    Lagged(u64),
+}
+
+/// How far a download is, and how fast it goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Pieces stored and verified, of the `wanted` ones.
+    pub pieces: usize,
+    pub wanted_pieces: usize,
+    /// Bytes of those pieces.
+    pub verified: usize,
+    pub wanted: usize,
+    /// Bytes received and sent since the download started, protocol overhead included.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes per second over the last second.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// Peers connected at the moment.
+    pub peers: usize,
+}
+
+impl Stats {
+    /// Catches up with the bytes `transferred` over `elapsed`, and with the `peers` connected.
+    pub(crate) fn transferred(&mut self, transferred: &Transferred, peers: usize, elapsed: Duration) {
+        let downloaded = transferred.downloaded.load(Ordering::Relaxed);
+        let uploaded = transferred.uploaded.load(Ordering::Relaxed);
+        let rate = |bytes: u64| (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        self.download_rate = rate(downloaded - self.downloaded);
+        self.upload_rate = rate(uploaded - self.uploaded);
+        (self.downloaded, self.uploaded, self.peers) = (downloaded, uploaded, peers);
+    }
+}
+
+/// Where downloads send their events. Clones share the subscribers, so one kept aside hears
+/// everything the downloads it was handed to report.
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Events {
+    pub fn new() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+
+    /// The events sent from now on. A subscriber that falls more than a thousand events behind
+    /// gets an [`Event::Lagged`] in place of the ones it missed.
+    pub fn subscribe(&self) -> impl Stream<Item = Event> + Send + 'static {
+        futures_util::stream::unfold(self.0.subscribe(), |mut receiver| async move {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => Event::Lagged(missed),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            Some((event, receiver))
+        })
+    }
+
+    pub(crate) fn send(&self, event: Event) {
+        // nobody listening is fine
+        let _ = self.0.send(event);
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Events({} subscribers)", self.0.receiver_count())
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::PeerConnected { peer, .. } => write!(f, "peer {peer} connected"),
+            Event::PeerDisconnected { peer, error: None, .. } => write!(f, "peer {peer} disconnected"),
+            Event::PeerDisconnected { peer, error: Some(e), .. } => write!(f, "peer {peer} disconnected: {e}"),
+            Event::PeerBanned { peer, .. } => write!(f, "peer {peer} sent corrupt data, banned it"),
+            Event::PieceVerified { piece, .. } => write!(f, "piece {piece} verified"),
+            Event::PieceFailed { piece, .. } => write!(f, "piece {piece} failed its hash check, fetching it again"),
+            Event::TrackerReply { tracker, peers, .. } => write!(f, "tracker {tracker} sent {peers} peers"),
+            Event::TrackerError { tracker, error, .. } => write!(f, "tracker {tracker} failed: {error}"),
+            Event::StateChanged { state, .. } => write!(f, "{state}"),
+            Event::FileCompleted { file, .. } => write!(f, "file {file} complete"),
+            Event::Stats { stats, .. } => write!(
+                f,
+                "{}/{} pieces, {} B/s down, {} B/s up, {} peers",
+                stats.pieces, stats.wanted_pieces, stats.download_rate, stats.upload_rate, stats.peers
+            ),
+            Event::Log { message, .. } => write!(f, "{message}"),
+            Event::Lagged(missed) => write!(f, "missed {missed} events"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_events() {
+    use futures_util::StreamExt;
+    let events = Events::new();
+    // sent before anyone listens: gone
+    events.send(Event::Lagged(0));
+    let stream = events.subscribe();
+    tokio::pin!(stream);
+    events.send(Event::PieceVerified { info_hash: [1; 20], piece: 3 });
+    assert_eq!(stream.next().await, Some(Event::PieceVerified { info_hash: [1; 20], piece: 3 }));
+    for piece in 0..CAPACITY + 5 {
+        events.send(Event::PieceVerified { info_hash: [1; 20], piece });
+    }
+    assert_eq!(stream.next().await, Some(Event::Lagged(5)));
+    assert_eq!(stream.next().await, Some(Event::PieceVerified { info_hash: [1; 20], piece: 5 }));
+    drop(events);
+    assert_eq!(stream.count().await, CAPACITY - 1);
+}
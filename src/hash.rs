@@ -25,7 +25,7 @@ use serde::de::{Visitor, Deserialize, Deserializer};
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
             where
                 E: de::Error, {
-            if v.len() % 20 != 0 { 
+            if !v.len().is_multiple_of(20) { 
                 return Err(E::custom(format!("length is {}", v.len())));
             }
             let slices  = v.chunks_exact(20).map(|slice_20| slice_20.try_into().expect("")).collect();
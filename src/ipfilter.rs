@@ -0,0 +1,206 @@
+//! Blocklists of IPv4 ranges that no connection is made to or accepted from, loaded from eMule
+//! DAT (`ipfilter.dat`), PeerGuardian P2P or plain CIDR lists.
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use anyhow::Context;
+
+/// eMule DAT entries with an access level above this are allowed rather than blocked.
+const DAT_ALLOW_LEVEL: u32 = 127;
+
+/// A set of blocked addresses, kept as sorted, disjoint ranges so that a lookup is a binary
+/// search however long the list is.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    // inclusive, sorted by start, neither overlapping nor adjacent
+    ranges: Vec<(u32, u32)>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a blocklist from a file, in any of the formats [`IpFilter::parse`] understands.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let list = std::fs::read(path).with_context(|| format!("read ip filter {}", path.display()))?;
+        Self::parse(&String::from_utf8_lossy(&list)).with_context(|| format!("parse ip filter {}", path.display()))
+    }
+
+    /// Parses a blocklist, one entry per line, in any mix of
+    ///
+    /// - eMule DAT: `1.2.3.0 - 1.2.3.255 , 000 , description`, where entries with an access
+    ///   level above 127 are allowed, not blocked;
+    /// - PeerGuardian P2P: `description:1.2.3.0-1.2.3.255`;
+    /// - CIDR: `1.2.3.0/24`, or a single address.
+    ///
+    /// Blank lines and lines starting with `#` or `//` are skipped.
+    pub fn parse(list: &str) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for (n, line) in list.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let range = parse_line(line).with_context(|| format!("line {}: {line:?}", n + 1))?;
+            ranges.extend(range);
+        }
+        let mut filter = Self { ranges };
+        filter.normalize();
+        Ok(filter)
+    }
+
+    /// Blocks every address from `first` to `last`, both included.
+    pub fn block(&mut self, first: Ipv4Addr, last: Ipv4Addr) {
+        self.ranges.push((u32::from(first).min(u32::from(last)), u32::from(first).max(u32::from(last))));
+        self.normalize();
+    }
+
+    /// Lifts the block on every address from `first` to `last`, both included.
+    pub fn allow(&mut self, first: Ipv4Addr, last: Ipv4Addr) {
+        let (first, last) = (u32::from(first).min(u32::from(last)), u32::from(first).max(u32::from(last)));
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+        for &(start, end) in &self.ranges {
+            if end < first || start > last {
+                ranges.push((start, end));
+                continue;
+            }
+            if start < first {
+                ranges.push((start, first - 1));
+            }
+            if end > last {
+                ranges.push((last + 1, end));
+            }
+        }
+        self.ranges = ranges;
+    }
+
+    /// Adds everything `other` blocks.
+    pub fn extend(&mut self, other: &IpFilter) {
+        self.ranges.extend_from_slice(&other.ranges);
+        self.normalize();
+    }
+
+    pub fn is_blocked(&self, ip: Ipv4Addr) -> bool {
+        let ip = u32::from(ip);
+        let after = self.ranges.partition_point(|&(start, _)| start <= ip);
+        after > 0 && self.ranges[after - 1].1 >= ip
+    }
+
+    /// Whether connections to or from `peer` are forbidden.
+    pub fn blocks(&self, peer: &SocketAddrV4) -> bool {
+        self.is_blocked(*peer.ip())
+    }
+
+    /// The blocked ranges, in order.
+    pub fn ranges(&self) -> impl Iterator<Item = RangeInclusive<Ipv4Addr>> + '_ {
+        self.ranges.iter().map(|&(start, end)| Ipv4Addr::from(start)..=Ipv4Addr::from(end))
+    }
+
+    /// The number of disjoint ranges blocked.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    fn normalize(&mut self) {
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= last_end.saturating_add(1) => *last_end = (*last_end).max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+impl fmt::Debug for IpFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IpFilter({} ranges)", self.ranges.len())
+    }
+}
+
+/// The range a blocklist line blocks, or `None` for an eMule entry that allows its range.
+fn parse_line(line: &str) -> anyhow::Result<Option<(u32, u32)>> {
+    // P2P descriptions may hold anything, commas and colons included, but never end in a range
+    if let Some(range) = line.rsplit_once(':').and_then(|(_, range)| parse_range(range).ok()) {
+        return Ok(Some(range));
+    }
+    if let Some((range, rest)) = line.split_once(',') {
+        let level = rest.split(',').next().unwrap_or_default().trim();
+        let level: u32 = level.parse().with_context(|| format!("access level {level:?}"))?;
+        let range = parse_range(range)?;
+        return Ok((level <= DAT_ALLOW_LEVEL).then_some(range));
+    }
+    if let Some((ip, bits)) = line.split_once('/') {
+        let ip = parse_ip(ip)?;
+        let bits: u32 = bits.trim().parse().with_context(|| format!("prefix length {bits:?}"))?;
+        anyhow::ensure!(bits <= 32, "prefix length {bits} is longer than an address");
+        let host = u32::MAX.checked_shr(bits).unwrap_or(0);
+        return Ok(Some((ip & !host, ip | host)));
+    }
+    parse_range(line).map(Some)
+}
+
+/// `first - last`, or a single address.
+fn parse_range(range: &str) -> anyhow::Result<(u32, u32)> {
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let (first, last) = (parse_ip(first)?, parse_ip(last)?);
+    anyhow::ensure!(first <= last, "range ends before it starts");
+    Ok((first, last))
+}
+
+/// Parses an address the way blocklists write them, which may zero-pad every octet.
+fn parse_ip(ip: &str) -> anyhow::Result<u32> {
+    let ip = ip.trim();
+    let octets: Vec<u8> = ip
+        .split('.')
+        .map(|octet| octet.parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("address {ip:?}"))?;
+    let octets: [u8; 4] = octets.try_into().map_err(|_| anyhow::anyhow!("address {ip:?} does not have four parts"))?;
+    Ok(u32::from_be_bytes(octets))
+}
+
+#[test]
+fn test_ip_filter_formats() {
+    let list = "\
+# eMule
+001.002.003.000 - 001.002.003.255 , 000 , Some ISP
+010.000.000.000 - 010.255.255.255 , 200 , allowed
+// PeerGuardian
+Bad, people: ads:5.6.7.8-5.6.7.20
+192.168.0.0/16
+9.9.9.9
+";
+    let mut filter = IpFilter::parse(list).unwrap();
+    let ip = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+    assert_eq!(filter.len(), 4);
+    assert!(filter.is_blocked(ip("1.2.3.4")));
+    assert!(!filter.is_blocked(ip("1.2.4.0")));
+    assert!(!filter.is_blocked(ip("10.1.1.1")));
+    assert!(filter.is_blocked(ip("5.6.7.8")) && filter.is_blocked(ip("5.6.7.20")));
+    assert!(!filter.is_blocked(ip("5.6.7.21")));
+    assert!(filter.is_blocked(ip("192.168.200.1")));
+    assert!(filter.is_blocked(ip("9.9.9.9")) && !filter.is_blocked(ip("9.9.9.10")));
+
+    // overlapping and adjacent ranges merge, and holes can be punched back in
+    filter.block(ip("1.2.4.0"), ip("1.2.4.255"));
+    filter.block(ip("1.2.3.128"), ip("1.2.4.10"));
+    assert_eq!(filter.ranges().next(), Some(ip("1.2.3.0")..=ip("1.2.4.255")));
+    filter.allow(ip("1.2.3.255"), ip("1.2.4.0"));
+    assert!(!filter.is_blocked(ip("1.2.4.0")) && filter.is_blocked(ip("1.2.4.1")));
+    assert_eq!(filter.len(), 5);
+
+    assert!(IpFilter::parse("1.2.3/24").is_err());
+    assert!(IpFilter::parse("1.2.3.4 - 1.2.3.0").is_err());
+}
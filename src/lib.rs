@@ -4,6 +4,26 @@ pub mod tracker;
 pub mod peers;
 pub mod download;
 pub mod piece;
+pub mod scheduler;
+pub mod random;
+pub mod selection;
+pub mod storage;
+pub mod streaming;
+pub mod bencode;
+pub mod dht;
+pub mod lsd;
+pub mod mse;
+pub mod utp;
+pub mod transport;
+pub mod proxy;
+pub mod connections;
+pub mod ipfilter;
+pub mod ratelimit;
+pub mod magnet;
+pub mod metadata;
+pub mod session;
+pub mod events;
+pub mod progress;
 
 
 pub const BLOCK_MAX: usize = 1 << 14;
@@ -0,0 +1,293 @@
+//! Local Service Discovery (BEP 14): finding peers on the same network through multicast
+//! `BT-SEARCH` announcements, without a tracker.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::events::{Event, Events};
+use crate::random::Rng;
+
+/// The multicast group and port that BEP 14 assigns to IPv4.
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+/// Torrents we take part in are announced again this often.
+const REANNOUNCE: Duration = Duration::from_secs(5 * 60);
+/// Announcing the same torrent more often than this only floods the network.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// Peers that have not announced again within this long are forgotten.
+const PEER_TTL: Duration = Duration::from_secs(2 * REANNOUNCE.as_secs());
+
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    /// Where to listen for announcements: normally the port of [`LSD_GROUP`] on every interface.
+    pub bind: SocketAddrV4,
+    /// The group to join and announce to.
+    pub group: SocketAddrV4,
+    /// The TCP port we accept peers on, put into our announcements.
+    pub port: u16,
+    /// Where announcements that fail to go out are reported.
+    pub events: Events,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self { bind: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LSD_GROUP.port()), group: LSD_GROUP, port: 6881, events: Events::new() }
+    }
+}
+
+/// A `BT-SEARCH` announcement: the sender has these torrents and accepts peers on `port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn encode(&self, group: &SocketAddrV4) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {group}\r\nPort: {}\r\n", self.port);
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn decode(packet: &[u8]) -> anyhow::Result<Self> {
+        let packet = std::str::from_utf8(packet).context("announcement is not text")?;
+        let mut lines = packet.split("\r\n");
+        anyhow::ensure!(lines.next() == Some("BT-SEARCH * HTTP/1.1"), "not a BT-SEARCH announcement");
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                anyhow::bail!("malformed header {line:?}");
+            };
+            let value = value.trim();
+            // header names are case-insensitive, and clients disagree on how to spell them
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().with_context(|| format!("bad port {value:?}"))?),
+                "infohash" => {
+                    let mut info_hash = [0u8; 20];
+                    hex::decode_to_slice(value, &mut info_hash).with_context(|| format!("bad info hash {value:?}"))?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        let port = port.context("announcement without a port")?;
+        anyhow::ensure!(port != 0, "announcement for port 0");
+        anyhow::ensure!(!info_hashes.is_empty(), "announcement without an info hash");
+        Ok(Announce { port, info_hashes, cookie })
+    }
+}
+
+/// Announces our torrents to the local network and collects the peers other clients announce.
+/// Listens in the background until it is dropped.
+pub struct Lsd {
+    inner: Arc<Inner>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+/// Info hash -> peers that announced it, and when.
+type Found = HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>;
+
+struct Inner {
+    socket: UdpSocket,
+    config: LsdConfig,
+    // tells our own announcements apart from everyone else's, since multicast loops back
+    cookie: String,
+    // info hash -> when we last announced it
+    announced: Mutex<HashMap<[u8; 20], Option<Instant>>>,
+    found: Mutex<Found>,
+    notify: Notify,
+}
+
+impl fmt::Debug for Lsd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsd")
+            .field("addr", &self.local_addr())
+            .field("group", &self.inner.config.group)
+            .finish()
+    }
+}
+
+impl Lsd {
+    pub async fn bind(config: LsdConfig) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(config.bind).await.context("bind lsd socket")?;
+        socket
+            .join_multicast_v4(*config.group.ip(), Ipv4Addr::UNSPECIFIED)
+            .with_context(|| format!("join multicast group {}", config.group.ip()))?;
+        // other clients on this host should hear us too
+        socket.set_multicast_loop_v4(true).context("enable multicast loopback")?;
+        let mut cookie = [0u8; 8];
+        Rng::new().fill(&mut cookie);
+        let inner = Arc::new(Inner {
+            socket,
+            config,
+            cookie: hex::encode(cookie),
+            announced: Mutex::new(HashMap::new()),
+            found: Mutex::new(HashMap::new()),
+            notify: Notify::new(),
+        });
+        let tasks = [tokio::spawn(inner.clone().receive()), tokio::spawn(inner.clone().reannounce())];
+        Ok(Self { inner, tasks })
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        match self.inner.socket.local_addr() {
+            Ok(SocketAddr::V4(addr)) => addr,
+            _ => unreachable!("bound to an IPv4 address"),
+        }
+    }
+
+    /// Starts announcing `info_hash` on the local network, and keeps doing so every few minutes.
+    pub async fn announce(&self, info_hash: [u8; 20]) -> anyhow::Result<()> {
+        {
+            let mut announced = self.inner.announced.lock().expect("announced lock is never poisoned");
+            let last = announced.entry(info_hash).or_insert(None);
+            if last.is_some_and(|last| last.elapsed() < MIN_INTERVAL) {
+                return Ok(());
+            }
+            *last = Some(Instant::now());
+        }
+        self.inner.send(vec![info_hash]).await
+    }
+
+    /// The peers that announced `info_hash` lately.
+    pub fn peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        let mut found = self.inner.found.lock().expect("found lock is never poisoned");
+        let Some(peers) = found.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|(_, seen)| seen.elapsed() < PEER_TTL);
+        peers.iter().map(|(peer, _)| *peer).collect()
+    }
+
+    /// Waits up to `timeout` for the first peer of `info_hash` to turn up, then returns every
+    /// peer known for it.
+    pub async fn wait_for_peers(&self, info_hash: &[u8; 20], timeout: Duration) -> Vec<SocketAddrV4> {
+        let wait = async {
+            loop {
+                let notified = self.inner.notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                let peers = self.peers(info_hash);
+                if !peers.is_empty() {
+                    return peers;
+                }
+                notified.await;
+            }
+        };
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(peers) => peers,
+            Err(_) => self.peers(info_hash),
+        }
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Inner {
+    async fn send(&self, info_hashes: Vec<[u8; 20]>) -> anyhow::Result<()> {
+        let announce = Announce { port: self.config.port, info_hashes, cookie: Some(self.cookie.clone()) };
+        self.socket
+            .send_to(&announce.encode(&self.config.group), self.config.group)
+            .await
+            .context("send lsd announcement")?;
+        Ok(())
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut buf = vec![0u8; 1500];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            let Ok(announce) = Announce::decode(&buf[..n]) else {
+                continue;
+            };
+            if announce.cookie.as_ref() == Some(&self.cookie) {
+                continue;
+            }
+            let peer = SocketAddrV4::new(*from.ip(), announce.port);
+            let now = Instant::now();
+            let mut found = self.found.lock().expect("found lock is never poisoned");
+            for info_hash in announce.info_hashes {
+                let peers = found.entry(info_hash).or_default();
+                match peers.iter_mut().find(|(known, _)| *known == peer) {
+                    Some((_, seen)) => *seen = now,
+                    None => peers.push((peer, now)),
+                }
+            }
+            drop(found);
+            self.notify.notify_waiters();
+        }
+    }
+
+    async fn reannounce(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REANNOUNCE);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let info_hashes: Vec<[u8; 20]> = {
+                let mut announced = self.announced.lock().expect("announced lock is never poisoned");
+                announced.values_mut().for_each(|last| *last = Some(Instant::now()));
+                announced.keys().copied().collect()
+            };
+            // a datagram is comfortably big enough for a few dozen info hashes at a time
+            for info_hashes in info_hashes.chunks(20) {
+                if let Err(e) = self.send(info_hashes.to_vec()).await {
+                    self.config.events.send(Event::Log { info_hash: None, message: format!("lsd: {e:#}") });
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_lsd_announcements() {
+    let info_hash = [0xab; 20];
+    let announce = Announce { port: 51413, info_hashes: vec![info_hash, [1; 20]], cookie: Some("c00k1e".into()) };
+    assert_eq!(Announce::decode(&announce.encode(&LSD_GROUP)).unwrap(), announce);
+    let other_client = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+        Infohash: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n\r\n";
+    assert_eq!(Announce::decode(other_client).unwrap().info_hashes, vec![info_hash]);
+    assert!(Announce::decode(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+
+    let lsd = Lsd::bind(LsdConfig { bind: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), ..Default::default() })
+        .await
+        .unwrap();
+    let addr = lsd.local_addr();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    // our own announcements are not peers
+    let own = Announce { port: 6881, info_hashes: vec![info_hash], cookie: Some(lsd.inner.cookie.clone()) };
+    sender.send_to(&own.encode(&LSD_GROUP), addr).await.unwrap();
+    sender.send_to(other_client, addr).await.unwrap();
+    let peers = lsd.wait_for_peers(&info_hash, Duration::from_secs(5)).await;
+    assert_eq!(peers, vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881)]);
+    assert!(lsd.peers(&[1; 20]).is_empty());
+}
@@ -0,0 +1,122 @@
+//! Magnet links (BEP 9): a torrent named by its info hash alone, with the metainfo to be fetched
+//! from peers.
+
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::str::FromStr;
+
+use anyhow::Context;
+use reqwest::Url;
+
+/// What a magnet link tells us about a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// `dn`: a name to show until the metainfo arrives.
+    pub name: Option<String>,
+    /// `tr`: trackers to find peers through.
+    pub trackers: Vec<String>,
+    /// `x.pe`: peers to ask for the metainfo straight away.
+    pub peers: Vec<SocketAddrV4>,
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(s).context("parse magnet link")?;
+        anyhow::ensure!(url.scheme() == "magnet", "not a magnet link: {s}");
+        let mut info_hash = None;
+        let mut magnet = Magnet { info_hash: [0; 20], name: None, trackers: Vec::new(), peers: Vec::new() };
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    // other kinds of exact topic (`urn:btmh:` for v2 torrents) are not for us
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "x.pe" => match value.parse() {
+                    Ok(peer) => magnet.peers.push(peer),
+                    // hostnames and IPv6 peers are of no use to us
+                    Err(_) => continue,
+                },
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.context("magnet link without a btih info hash")?;
+        Ok(magnet)
+    }
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut url = Url::parse("magnet:").expect("a valid url");
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("xt", &format!("urn:btih:{}", hex::encode(self.info_hash)));
+            if let Some(name) = &self.name {
+                query.append_pair("dn", name);
+            }
+            for tracker in &self.trackers {
+                query.append_pair("tr", tracker);
+            }
+            for peer in &self.peers {
+                query.append_pair("x.pe", &peer.to_string());
+            }
+        }
+        // the colons of `urn:btih:` are customarily left as they are
+        write!(f, "{}", url.as_str().replace("%3A", ":"))
+    }
+}
+
+/// An info hash as magnet links write it: 40 hex digits, or 32 base32 characters.
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("info hash is not hex")?,
+        32 => base32(hash).context("info hash is not base32")?,
+        n => anyhow::bail!("info hash of {n} characters"),
+    };
+    Ok(bytes.try_into().expect("both encodings hold 20 bytes"))
+}
+
+/// Decodes unpadded RFC 4648 base32, in either case.
+fn base32(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+#[test]
+fn test_magnet_link() {
+    let magnet: Magnet = "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.txt&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce&x.pe=10.0.0.1:6881"
+        .parse()
+        .unwrap();
+    assert_eq!(hex::encode(magnet.info_hash), "d69f91e6b2ae4c542468d1073a71d4ea13879a7f");
+    assert_eq!(magnet.name.as_deref(), Some("sample.txt"));
+    assert_eq!(magnet.trackers, vec!["http://bittorrent-test-tracker.codecrafters.io/announce"]);
+    assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+    assert_eq!(magnet.to_string().parse::<Magnet>().unwrap(), magnet);
+
+    // the same hash in base32
+    let base32: Magnet = "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7".parse().unwrap();
+    assert_eq!(base32.info_hash, magnet.info_hash);
+    assert!("magnet:?dn=nothing".parse::<Magnet>().is_err());
+    assert!("http://example.com/?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7".parse::<Magnet>().is_err());
+}
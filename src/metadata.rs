@@ -0,0 +1,241 @@
+//! Fetching the info dictionary of a torrent from its peers (BEP 9), which is all a magnet link
+//! needs to become a torrent. It travels over the extension protocol (BEP 10).
+
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{stream, SinkExt, StreamExt};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use tokio_util::codec::Framed;
+
+use crate::bencode;
+use crate::events::{Event, Events};
+use crate::peers::{Handshake, Message, MessageFramer};
+use crate::torrent::Info;
+use crate::transport::{Connector, PeerStream};
+
+/// The metadata travels in pieces of this size, the last one shorter.
+pub(crate) const METADATA_PIECE: usize = 1 << 14;
+/// The id we ask peers to send `ut_metadata` messages to us under.
+const UT_METADATA: u8 = 1;
+/// Frames we take while fetching the metadata: a metadata piece, with room for the message ids
+/// and the dictionary in front of it.
+const MAX_FRAME: usize = METADATA_PIECE + 1024;
+/// Metadata bigger than this is more likely an attack than a torrent.
+const MAX_METADATA: usize = 16 << 20;
+/// How long a single peer gets to hand over the metadata.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Peers asked at the same time.
+const PARALLEL: usize = 5;
+
+/// Asks `peers`, a few at a time, for the info dictionary of `info_hash`, and returns the first
+/// copy that hashes to it: decoded, and as the bytes it came as. The peers that fail to are
+/// reported to `events`.
+pub(crate) async fn fetch(info_hash: [u8; 20], peers: &[SocketAddrV4], connector: &dyn Connector, events: &Events) -> anyhow::Result<(Info, Vec<u8>)> {
+    let mut attempts = stream::iter(peers.iter().copied())
+        .map(|peer| async move {
+            let fetched = tokio::time::timeout(PEER_TIMEOUT, async {
+                let conn = connector.connect(peer).await?;
+                from_peer(conn, info_hash).await
+            })
+            .await
+            .context("peer took too long")
+            .and_then(|fetched| fetched);
+            (peer, fetched)
+        })
+        .buffer_unordered(PARALLEL);
+    while let Some((peer, fetched)) = attempts.next().await {
+        match fetched {
+            Ok(fetched) => return Ok(fetched),
+            Err(e) => events.send(Event::Log { info_hash: Some(info_hash), message: format!("failed to get metadata from peer {peer}: {e:#}") }),
+        }
+    }
+    anyhow::bail!("none of {} peers handed over the metadata", peers.len())
+}
+
+/// Gets the info dictionary of `info_hash` from the peer at the other end of `conn`.
+pub(crate) async fn from_peer(mut conn: Box<dyn PeerStream>, info_hash: [u8; 20]) -> anyhow::Result<(Info, Vec<u8>)> {
+    let mut handshake = Handshake::new(&info_hash, b"00112233445566778899");
+    handshake.reserved[5] |= Handshake::EXTENSION;
+    let theirs = handshake.exchange(&mut conn).await?;
+    anyhow::ensure!(theirs.info_hash == info_hash, "peer is on another torrent");
+    anyhow::ensure!(theirs.reserved[5] & Handshake::EXTENSION != 0, "peer does not speak the extension protocol");
+
+    let mut conn = Framed::new(conn, MessageFramer::with_max_frame(MAX_FRAME));
+    let ours = dict([("m", dict([("ut_metadata", Value::Int(UT_METADATA as i64))]))]);
+    conn.send(Message::Extended(0, encode(&ours))).await.context("send extension handshake")?;
+
+    let mut metadata = Vec::new();
+    let mut received = Vec::new();
+    loop {
+        let msg = conn
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer message was invalid")?;
+        let Message::Extended(id, payload) = msg else {
+            // bitfields and the like; we are not here for pieces
+            continue;
+        };
+        let (message, data) = split_dict(&payload).context("extension message is not a dictionary")?;
+        if id == 0 {
+            let their_id = message
+                .get(&b"m"[..])
+                .and_then(|m| int(as_dict(m)?, "ut_metadata"))
+                .filter(|&id| (1..=255).contains(&id))
+                .context("peer does not offer the metadata")?;
+            let size = int(&message, "metadata_size").context("peer did not say how big the metadata is")?;
+            let size = usize::try_from(size).ok().filter(|&size| size > 0 && size <= MAX_METADATA);
+            let size = size.context("peer claims metadata of an unlikely size")?;
+            metadata = vec![0; size];
+            received = vec![false; size.div_ceil(METADATA_PIECE)];
+            for piece in 0..received.len() {
+                let request = dict([("msg_type", Value::Int(0)), ("piece", Value::Int(piece as i64))]);
+                conn.send(Message::Extended(their_id as u8, encode(&request))).await.context("request metadata piece")?;
+            }
+            continue;
+        }
+        if id != UT_METADATA {
+            continue;
+        }
+        anyhow::ensure!(!metadata.is_empty(), "peer sent metadata before its extension handshake");
+        let piece = int(&message, "piece").and_then(|piece| usize::try_from(piece).ok()).filter(|&piece| piece < received.len());
+        let piece = piece.context("metadata message for no piece of ours")?;
+        match int(&message, "msg_type") {
+            Some(1) => {
+                let begin = piece * METADATA_PIECE;
+                let length = (metadata.len() - begin).min(METADATA_PIECE);
+                anyhow::ensure!(data.len() == length, "peer sent {} bytes of metadata piece {piece}", data.len());
+                metadata[begin..begin + length].copy_from_slice(data);
+                received[piece] = true;
+                if received.iter().all(|&received| received) {
+                    break;
+                }
+            }
+            Some(2) => anyhow::bail!("peer turned down our request for metadata piece {piece}"),
+            _ => {}
+        }
+    }
+    let hash: [u8; 20] = Sha1::digest(&metadata).into();
+    anyhow::ensure!(hash == info_hash, "peer sent metadata that does not match the info hash");
+    let info = serde_bencode::from_bytes(&metadata).context("decode metadata")?;
+    Ok((info, metadata))
+}
+
+type Dict = HashMap<Vec<u8>, Value>;
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Dict(entries.into_iter().map(|(key, value)| (key.as_bytes().to_vec(), value)).collect())
+}
+
+fn as_dict(value: &Value) -> Option<&Dict> {
+    match value {
+        Value::Dict(dict) => Some(dict),
+        _ => None,
+    }
+}
+
+fn int(dict: &Dict, key: &str) -> Option<i64> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Int(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+fn encode(value: &Value) -> Bytes {
+    Bytes::from(serde_bencode::to_bytes(value).expect("values always encode"))
+}
+
+/// Splits a payload into the bencoded dictionary it starts with and the raw data after it, which
+/// is how metadata pieces travel.
+fn split_dict(payload: &[u8]) -> Option<(Dict, &[u8])> {
+    let end = bencode::value_len(payload)?;
+    let Ok(Value::Dict(dict)) = serde_bencode::from_bytes(&payload[..end]) else {
+        return None;
+    };
+    Some((dict, &payload[end..]))
+}
+
+/// Plays a peer that has the metadata `info` and hands it out over `conn`, once the other side's
+/// handshake, `theirs`, has been read.
+#[cfg(test)]
+pub(crate) async fn serve(mut conn: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin, theirs: Handshake, info: &[u8]) -> anyhow::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let mut handshake = Handshake::new(&theirs.info_hash, &[9; 20]);
+    handshake.reserved[5] |= Handshake::EXTENSION;
+    conn.write_all(&handshake.encode()).await?;
+    let mut conn = Framed::new(conn, MessageFramer::with_max_frame(MAX_FRAME));
+    // ours is 3, to make sure the other side uses the id we give it
+    let handshake = dict([("m", dict([("ut_metadata", Value::Int(3))])), ("metadata_size", Value::Int(info.len() as i64))]);
+    conn.send(Message::Extended(0, encode(&handshake))).await?;
+    let mut their_id = None;
+    while let Some(msg) = conn.next().await {
+        let Message::Extended(id, payload) = msg? else {
+            continue;
+        };
+        let (message, _) = split_dict(&payload).context("not a dictionary")?;
+        match id {
+            0 => their_id = message.get(&b"m"[..]).and_then(|m| int(as_dict(m)?, "ut_metadata")),
+            3 => {
+                let piece = int(&message, "piece").context("no piece")? as usize;
+                let data = &info[piece * METADATA_PIECE..][..(info.len() - piece * METADATA_PIECE).min(METADATA_PIECE)];
+                let header = dict([("msg_type", Value::Int(1)), ("piece", Value::Int(piece as i64)), ("total_size", Value::Int(info.len() as i64))]);
+                let mut payload = encode(&header).to_vec();
+                payload.extend_from_slice(data);
+                conn.send(Message::Extended(their_id.context("no handshake")? as u8, Bytes::from(payload))).await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_metadata() {
+    use crate::{hash::Hashes, torrent::Keys};
+    // big enough to come in three pieces
+    let info = Info { name: "big".to_string(), plength: 1 << 14, pieces: Hashes(vec![[7; 20]; 2000]), keys: Keys::SingleFile { length: 2000 << 14 } };
+    let raw = serde_bencode::to_bytes(&info).unwrap();
+    assert!(raw.len() > 2 * METADATA_PIECE);
+    let info_hash: [u8; 20] = Sha1::digest(&raw).into();
+
+    async fn serve_after_handshake(mut conn: tokio::io::DuplexStream, info: Vec<u8>) -> anyhow::Result<()> {
+        use tokio::io::AsyncReadExt;
+        let mut theirs = [0u8; Handshake::LEN];
+        conn.read_exact(&mut theirs).await?;
+        serve(conn, Handshake::decode(&theirs)?, &info).await
+    }
+    let (ours, theirs) = tokio::io::duplex(1 << 16);
+    tokio::spawn(serve_after_handshake(theirs, raw.clone()));
+    let (fetched, fetched_raw) = from_peer(Box::new(ours), info_hash).await.unwrap();
+    assert_eq!(fetched_raw, raw);
+    assert_eq!(fetched.name, "big");
+    assert_eq!(fetched.pieces.0.len(), 2000);
+
+    // a peer with metadata for another torrent is found out
+    let (ours, theirs) = tokio::io::duplex(1 << 16);
+    let mut other = raw.clone();
+    other[10] ^= 1;
+    tokio::spawn(serve_after_handshake(theirs, other));
+    assert!(from_peer(Box::new(ours), info_hash).await.is_err());
+
+
+    // as is one whose message nests deeper than the decoder should be made to recurse
+    let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut handshake = [0u8; Handshake::LEN];
+        theirs.read_exact(&mut handshake).await?;
+        let mut handshake = Handshake::new(&Handshake::decode(&handshake)?.info_hash, &[9; 20]);
+        handshake.reserved[5] |= Handshake::EXTENSION;
+        theirs.write_all(&handshake.encode()).await?;
+        let mut conn = Framed::new(theirs, MessageFramer::default());
+        conn.send(Message::Extended(0, Bytes::from(vec![b'l'; METADATA_PIECE]))).await?;
+        conn.next().await.transpose()
+    });
+    assert!(from_peer(Box::new(ours), info_hash).await.is_err());
+}
@@ -0,0 +1,566 @@
+//! Message Stream Encryption (MSE/PE): the obfuscated handshake most clients speak, a
+//! Diffie-Hellman exchange followed by RC4 keyed from the shared secret and the info hash.
+//!
+//! This hides BitTorrent traffic from shapers that look for the plaintext handshake; it is not
+//! meant to, and does not, protect against anyone who knows the info hash.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::random::secure_fill;
+
+/// Whether to obfuscate peer connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encryption {
+    /// Plaintext only, as before MSE existed.
+    #[default]
+    Disabled,
+    /// Try the encrypted handshake first, and fall back to plaintext for peers that do not speak it.
+    Prefer,
+    /// Only talk to peers over RC4.
+    Require,
+}
+
+impl FromStr for Encryption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" | "off" => Ok(Encryption::Disabled),
+            "prefer" | "enabled" => Ok(Encryption::Prefer),
+            "require" | "forced" => Ok(Encryption::Require),
+            _ => anyhow::bail!("unknown encryption policy {s:?} (expected disabled, prefer or require)"),
+        }
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encryption::Disabled => "disabled",
+            Encryption::Prefer => "prefer",
+            Encryption::Require => "require",
+        })
+    }
+}
+
+/// How long the encrypted handshake may take before we give up on it.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const VC: [u8; 8] = [0; 8];
+const MAX_PAD: usize = 512;
+/// The length of a public key on the wire.
+const KEY_LEN: usize = 96;
+
+const LIMBS: usize = 12;
+
+/// Fixed-size unsigned integers modulo the MSE prime, just enough for the key exchange.
+mod dh {
+    use super::LIMBS;
+
+    pub(super) type Num = [u64; LIMBS];
+
+    /// The 768-bit safe prime the key exchange works in, with generator 2.
+    pub(super) const P_BYTES: [u8; 96] = *b"\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xC9\x0F\xDA\xA2\x21\x68\xC2\x34\xC4\xC6\x62\x8B\x80\xDC\x1C\xD1\x29\x02\x4E\x08\x8A\x67\xCC\x74\x02\x0B\xBE\xA6\x3B\x13\x9B\x22\x51\x4A\x08\x79\x8E\x34\x04\xDD\xEF\x95\x19\xB3\xCD\x3A\x43\x1B\x30\x2B\x0A\x6D\xF2\x5F\x14\x37\x4F\xE1\x35\x6D\x6D\x51\xC2\x45\xE4\x85\xB5\x76\x62\x5E\x7E\xC6\xF4\x4C\x42\xE9\xA6\x3A\x36\x21\x00\x00\x00\x00\x00\x09\x05\x63";
+
+    pub(super) fn from_bytes(bytes: &[u8; 96]) -> Num {
+        std::array::from_fn(|i| {
+            let end = 96 - 8 * i;
+            u64::from_be_bytes(bytes[end - 8..end].try_into().expect("8 bytes"))
+        })
+    }
+
+    pub(super) fn to_bytes(n: &Num) -> [u8; 96] {
+        let mut bytes = [0u8; 96];
+        for (i, limb) in n.iter().enumerate() {
+            let end = 96 - 8 * i;
+            bytes[end - 8..end].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn geq(a: &Num, b: &Num) -> bool {
+        for i in (0..LIMBS).rev() {
+            if a[i] != b[i] {
+                return a[i] > b[i];
+            }
+        }
+        true
+    }
+
+    fn sub_assign(a: &mut Num, b: &Num) {
+        let mut borrow = false;
+        for i in 0..LIMBS {
+            let (d, b1) = a[i].overflowing_sub(b[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            a[i] = d;
+            borrow = b1 || b2;
+        }
+    }
+
+    /// `a * 2 mod m`, for `a < m`.
+    fn double(a: &mut Num, m: &Num) {
+        let carry = a[LIMBS - 1] >> 63;
+        for i in (1..LIMBS).rev() {
+            a[i] = a[i] << 1 | a[i - 1] >> 63;
+        }
+        a[0] <<= 1;
+        if carry != 0 || geq(a, m) {
+            sub_assign(a, m);
+        }
+    }
+
+    /// Montgomery multiplication: `a * b / 2^(64 * LIMBS) mod m`.
+    fn mont_mul(a: &Num, b: &Num, m: &Num, m_inv: u64) -> Num {
+        let mut t = [0u64; LIMBS + 2];
+        for &b_i in b {
+            let mut carry = 0u128;
+            for j in 0..LIMBS {
+                let s = t[j] as u128 + a[j] as u128 * b_i as u128 + carry;
+                t[j] = s as u64;
+                carry = s >> 64;
+            }
+            let s = t[LIMBS] as u128 + carry;
+            t[LIMBS] = s as u64;
+            t[LIMBS + 1] = (s >> 64) as u64;
+
+            let q = t[0].wrapping_mul(m_inv);
+            let mut carry = (t[0] as u128 + q as u128 * m[0] as u128) >> 64;
+            for j in 1..LIMBS {
+                let s = t[j] as u128 + q as u128 * m[j] as u128 + carry;
+                t[j - 1] = s as u64;
+                carry = s >> 64;
+            }
+            let s = t[LIMBS] as u128 + carry;
+            t[LIMBS - 1] = s as u64;
+            t[LIMBS] = t[LIMBS + 1] + (s >> 64) as u64;
+            t[LIMBS + 1] = 0;
+        }
+        let mut result: Num = t[..LIMBS].try_into().expect("LIMBS limbs");
+        if t[LIMBS] != 0 || geq(&result, m) {
+            sub_assign(&mut result, m);
+        }
+        result
+    }
+
+    /// `base ^ exp mod m`, with `exp` big-endian. `m` must be odd and have its top bit set.
+    pub(super) fn pow(base: &Num, exp: &[u8], m: &Num) -> Num {
+        // -m^-1 mod 2^64, by Newton's iteration
+        let mut inv = 1u64;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(m[0].wrapping_mul(inv)));
+        }
+        let m_inv = inv.wrapping_neg();
+        // R mod m and R^2 mod m, for R = 2^(64 * LIMBS)
+        let mut one = [0u64; LIMBS];
+        one[0] = 1;
+        let mut r = one;
+        for _ in 0..64 * LIMBS {
+            double(&mut r, m);
+        }
+        let mut r2 = r;
+        for _ in 0..64 * LIMBS {
+            double(&mut r2, m);
+        }
+        let mut base = *base;
+        if geq(&base, m) {
+            // m has its top bit set, so one subtraction is enough
+            sub_assign(&mut base, m);
+        }
+        let base = mont_mul(&base, &r2, m, m_inv);
+        let mut result = r;
+        for byte in exp {
+            for bit in (0..8).rev() {
+                result = mont_mul(&result, &result, m, m_inv);
+                if byte >> bit & 1 == 1 {
+                    result = mont_mul(&result, &base, m, m_inv);
+                }
+            }
+        }
+        mont_mul(&result, &one, m, m_inv)
+    }
+}
+
+/// A key pair for one handshake.
+struct KeyPair {
+    private: [u8; 20],
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    /// A fresh key pair. The private key comes from the operating system's secure generator, and
+    /// from nothing that is ever sent over the wire.
+    fn generate() -> io::Result<Self> {
+        // 160 bits of private key are what the spec asks for
+        let mut private = [0u8; 20];
+        secure_fill(&mut private)?;
+        Ok(Self::from_private(private))
+    }
+
+    fn from_private(private: [u8; 20]) -> Self {
+        let mut generator = [0u64; LIMBS];
+        generator[0] = 2;
+        let public = dh::to_bytes(&dh::pow(&generator, &private, &prime()));
+        Self { private, public }
+    }
+
+    fn shared_secret(&self, their_public: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        dh::to_bytes(&dh::pow(&dh::from_bytes(their_public), &self.private, &prime()))
+    }
+}
+
+fn prime() -> dh::Num {
+    dh::from_bytes(&dh::P_BYTES)
+}
+
+/// The RC4 stream cipher.
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// A cipher keyed with `SHA1(label, secret, skey)`, with the first 1 KiB of keystream thrown
+    /// away as the spec requires.
+    fn for_handshake(label: &[u8], secret: &[u8], skey: &[u8; 20]) -> Self {
+        let mut rc4 = Rc4::new(&hash(&[label, secret, skey]));
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    fn new(key: &[u8]) -> Self {
+        let mut s: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+async fn write_public_key<S: AsyncWrite + Unpin>(stream: &mut S, keys: &KeyPair) -> io::Result<()> {
+    let mut pad_len = [0u8; 2];
+    secure_fill(&mut pad_len)?;
+    let mut pad = vec![0u8; u16::from_le_bytes(pad_len) as usize % (MAX_PAD + 1)];
+    secure_fill(&mut pad)?;
+    stream.write_all(&[&keys.public[..], &pad].concat()).await
+}
+
+/// Reads until the stream has produced `marker`, which must turn up within `MAX_PAD` bytes
+/// (the padding the other side sent before it).
+async fn sync_to<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> anyhow::Result<()> {
+    let mut window = Vec::with_capacity(MAX_PAD + marker.len());
+    while window.len() < MAX_PAD + marker.len() {
+        window.push(stream.read_u8().await.context("read handshake padding")?);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("peer did not synchronize within {MAX_PAD} bytes of padding")
+}
+
+async fn read_decrypted<S: AsyncRead + Unpin>(stream: &mut S, rc4: &mut Rc4, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    rc4.apply(&mut buf);
+    Ok(buf)
+}
+
+/// Runs the encrypted handshake as the side that opened the connection, for the torrent
+/// `info_hash`. `policy` decides whether plaintext may be chosen for the rest of the stream.
+pub async fn initiate<S>(mut stream: S, info_hash: &[u8; 20], policy: Encryption) -> anyhow::Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    anyhow::ensure!(policy != Encryption::Disabled, "encryption is disabled");
+    let keys = KeyPair::generate().context("generate key pair")?;
+    write_public_key(&mut stream, &keys).await.context("send public key")?;
+    let mut their_public = [0u8; KEY_LEN];
+    stream.read_exact(&mut their_public).await.context("read public key")?;
+    let secret = keys.shared_secret(&their_public);
+
+    let mut encrypt = Rc4::for_handshake(b"keyA", &secret, info_hash);
+    let mut decrypt = Rc4::for_handshake(b"keyB", &secret, info_hash);
+    let provide = match policy {
+        Encryption::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+    let mut offer = Vec::with_capacity(8 + 4 + 2 + 2);
+    offer.extend_from_slice(&VC);
+    offer.extend_from_slice(&provide.to_be_bytes());
+    // no padding, and no initial payload: the BitTorrent handshake follows on its own
+    offer.extend_from_slice(&0u16.to_be_bytes());
+    offer.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut offer);
+    let mut message = Vec::with_capacity(40 + offer.len());
+    message.extend_from_slice(&hash(&[b"req1", &secret]));
+    message.extend_from_slice(&xor(&hash(&[b"req2", info_hash]), &hash(&[b"req3", &secret])));
+    message.extend_from_slice(&offer);
+    stream.write_all(&message).await.context("send crypto offer")?;
+
+    // the answer starts with an encrypted VC somewhere after their padding
+    let mut vc = VC;
+    decrypt.clone().apply(&mut vc);
+    sync_to(&mut stream, &vc).await?;
+    decrypt.apply(&mut [0u8; 8]);
+    let select = read_decrypted(&mut stream, &mut decrypt, 4).await.context("read crypto select")?;
+    let select = u32::from_be_bytes(select.try_into().expect("read 4 bytes"));
+    anyhow::ensure!(
+        select.count_ones() == 1 && select & provide != 0,
+        "peer selected crypto {select:#x}, we offered {provide:#x}"
+    );
+    let pad_len = read_decrypted(&mut stream, &mut decrypt, 2).await.context("read padding length")?;
+    let pad_len = u16::from_be_bytes([pad_len[0], pad_len[1]]) as usize;
+    anyhow::ensure!(pad_len <= MAX_PAD, "padding of {pad_len} bytes is too long");
+    read_decrypted(&mut stream, &mut decrypt, pad_len).await.context("read padding")?;
+
+    let ciphers = (select == CRYPTO_RC4).then_some((encrypt, decrypt));
+    Ok(MseStream { inner: stream, ciphers, prefix: Vec::new(), pending: Vec::new() })
+}
+
+/// Handles a connection someone else opened: either a plaintext BitTorrent handshake or the
+/// encrypted one, for any torrent in `info_hashes`, as far as `policy` allows. The BitTorrent
+/// handshake itself is left to be read from the returned stream.
+pub async fn accept<S>(mut stream: S, info_hashes: &[[u8; 20]], policy: Encryption) -> anyhow::Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut first = [0u8; 20];
+    stream.read_exact(&mut first).await.context("read first bytes")?;
+    if first[0] == 19 && &first[1..] == b"BitTorrent protocol" {
+        anyhow::ensure!(policy != Encryption::Require, "peer sent a plaintext handshake, but encryption is required");
+        return Ok(MseStream { inner: stream, ciphers: None, prefix: first.to_vec(), pending: Vec::new() });
+    }
+    anyhow::ensure!(policy != Encryption::Disabled, "peer sent an encrypted handshake, but encryption is disabled");
+
+    let mut their_public = [0u8; KEY_LEN];
+    their_public[..20].copy_from_slice(&first);
+    stream.read_exact(&mut their_public[20..]).await.context("read public key")?;
+    let keys = KeyPair::generate().context("generate key pair")?;
+    write_public_key(&mut stream, &keys).await.context("send public key")?;
+    let secret = keys.shared_secret(&their_public);
+
+    sync_to(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut skey_hash = [0u8; 20];
+    stream.read_exact(&mut skey_hash).await.context("read torrent hash")?;
+    let skey_hash = xor(&skey_hash, &hash(&[b"req3", &secret]));
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", &info_hash[..]]) == skey_hash)
+        .context("peer asked for a torrent we do not have")?;
+
+    let mut decrypt = Rc4::for_handshake(b"keyA", &secret, info_hash);
+    let mut encrypt = Rc4::for_handshake(b"keyB", &secret, info_hash);
+    let offer = read_decrypted(&mut stream, &mut decrypt, 8 + 4 + 2).await.context("read crypto offer")?;
+    anyhow::ensure!(offer[..8] == VC, "peer sent a bad verification constant");
+    let provide = u32::from_be_bytes(offer[8..12].try_into().expect("read 4 bytes"));
+    let pad_len = u16::from_be_bytes([offer[12], offer[13]]) as usize;
+    anyhow::ensure!(pad_len <= MAX_PAD, "padding of {pad_len} bytes is too long");
+    read_decrypted(&mut stream, &mut decrypt, pad_len).await.context("read padding")?;
+    let ia_len = read_decrypted(&mut stream, &mut decrypt, 2).await.context("read initial payload length")?;
+    let ia_len = u16::from_be_bytes([ia_len[0], ia_len[1]]) as usize;
+    // whatever the peer sent along with the handshake is the start of the plaintext stream
+    let initial = read_decrypted(&mut stream, &mut decrypt, ia_len).await.context("read initial payload")?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy == Encryption::Prefer {
+        CRYPTO_PLAINTEXT
+    } else {
+        anyhow::bail!("peer offered crypto {provide:#x}, none of which we accept");
+    };
+    let mut answer = Vec::with_capacity(8 + 4 + 2);
+    answer.extend_from_slice(&VC);
+    answer.extend_from_slice(&select.to_be_bytes());
+    answer.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await.context("send crypto select")?;
+
+    let ciphers = (select == CRYPTO_RC4).then_some((encrypt, decrypt));
+    Ok(MseStream { inner: stream, ciphers, prefix: initial, pending: Vec::new() })
+}
+
+/// A connection after the MSE handshake: RC4 in both directions, or plaintext if that is what
+/// the two sides settled on.
+pub struct MseStream<S> {
+    inner: S,
+    // (encrypt, decrypt)
+    ciphers: Option<(Rc4, Rc4)>,
+    // plaintext that was read during the handshake and is yet to be handed out
+    prefix: Vec<u8>,
+    // encrypted bytes accepted from the caller but not yet written to `inner`
+    pending: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    /// A connection that never went through the MSE handshake.
+    pub fn plaintext(inner: S) -> Self {
+        Self { inner, ciphers: None, prefix: Vec::new(), pending: Vec::new() }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_pending(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let n = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..n]);
+            this.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((_, decrypt)) = &mut this.ciphers {
+            decrypt.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // the keystream has moved on once data is encrypted, so it has to be kept until written
+        ready!(this.poll_pending(cx))?;
+        let (encrypt, _) = this.ciphers.as_mut().expect("checked above");
+        let mut data = buf.to_vec();
+        encrypt.apply(&mut data);
+        this.pending = data;
+        // get a head start; whatever is left goes out on the next write or flush
+        let _ = this.poll_pending(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn test_rc4_and_key_exchange() {
+    // the "Key" / "Plaintext" vector from the original RC4 posting
+    let mut data = *b"Plaintext";
+    Rc4::new(b"Key").apply(&mut data);
+    assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+
+    let mut small = [0u64; LIMBS];
+    small[0] = 3;
+    assert_eq!(dh::pow(&small, &[5], &prime())[0], 243);
+    let (a, b) = (KeyPair::from_private([7; 20]), KeyPair::generate().unwrap());
+    assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+    assert_ne!(a.public, b.public);
+}
+
+#[tokio::test]
+async fn test_mse_handshake() {
+    let info_hash = [9u8; 20];
+    for (ours, theirs, encrypted) in [
+        (Encryption::Prefer, Encryption::Prefer, true),
+        (Encryption::Require, Encryption::Prefer, true),
+        (Encryption::Prefer, Encryption::Require, true),
+    ] {
+        let (a, b) = tokio::io::duplex(4096);
+        let accepting = tokio::spawn(async move {
+            let mut b = accept(b, &[[1; 20], info_hash], theirs).await.unwrap();
+            let mut hello = [0u8; 5];
+            b.read_exact(&mut hello).await.unwrap();
+            b.write_all(b"world").await.unwrap();
+            b.flush().await.unwrap();
+            hello
+        });
+        let mut a = initiate(a, &info_hash, ours).await.unwrap();
+        assert_eq!(a.is_encrypted(), encrypted);
+        a.write_all(b"hello").await.unwrap();
+        a.flush().await.unwrap();
+        let mut world = [0u8; 5];
+        a.read_exact(&mut world).await.unwrap();
+        assert_eq!(&world, b"world");
+        assert_eq!(&accepting.await.unwrap(), b"hello");
+    }
+
+    // a plaintext handshake gets through unless encryption is required
+    let plain = [&[19u8][..], b"BitTorrent protocol", &[0; 48]].concat();
+    let (mut a, b) = tokio::io::duplex(128);
+    a.write_all(&plain).await.unwrap();
+    let mut b = accept(b, &[info_hash], Encryption::Prefer).await.unwrap();
+    assert!(!b.is_encrypted());
+    let mut handshake = vec![0u8; plain.len()];
+    b.read_exact(&mut handshake).await.unwrap();
+    assert_eq!(handshake, plain);
+    let (mut a, b) = tokio::io::duplex(128);
+    a.write_all(&plain).await.unwrap();
+    assert!(accept(b, &[info_hash], Encryption::Require).await.is_err());
+
+    // and the encrypted one is only accepted for torrents we know
+    let (a, b) = tokio::io::duplex(4096);
+    let accepting = tokio::spawn(async move { accept(b, &[[1; 20]], Encryption::Prefer).await.is_err() });
+    assert!(initiate(a, &info_hash, Encryption::Prefer).await.is_err());
+    assert!(accepting.await.unwrap());
+}
@@ -1,30 +1,192 @@
-use reqwest::blocking::get;
 use serde::de::{Visitor, Deserialize, Deserializer};
 use serde::{de, Serialize};
-use core::panic;
 use std::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder, Framed};
 use anyhow::{Context};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures_util::{stream::StreamExt, sink::SinkExt};
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::time::{Instant, Sleep};
+use tokio_util::sync::CancellationToken;
+use crate::dht::Dht;
+use crate::mse::{self, Encryption};
+use crate::ratelimit::Throttle;
+use crate::transport::{Connector, PeerStream};
+use crate::scheduler::{Block, Scheduler};
+use crate::storage::Storage;
 use crate::BLOCK_MAX;
 
+/// We send a keep-alive when we have sent nothing else for this long.
+const KEEP_ALIVE: Duration = Duration::from_secs(2 * 60);
+/// A peer that sends nothing at all for this long, not even a keep-alive, is gone.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+/// Block requests kept outstanding with each peer, so that it never sits idle waiting for our
+/// next request while the last block is still on its way.
+const PIPELINE: usize = 8;
+
 pub(crate) struct Peer { 
     peer_addr : SocketAddrV4,
-    stream : Framed<TcpStream, MessageFramer>,
+    // the id the peer gave in its handshake
+    peer_id : [u8; 20],
+    stream : Framed<Metered<Box<dyn PeerStream>>, MessageFramer>,
     bitfield : Bitfield, 
-    choked: bool
+    choked: bool,
+    // both sides support the fast extension (BEP 6)
+    fast: bool,
+    // pieces the peer lets us request even while it chokes us
+    allowed_fast: HashSet<usize>,
+    // the DHT node to tell about the peer's DHT port, if it sends one
+    dht: Option<Arc<Dht>>,
+    keep_alive: Duration,
+    idle_timeout: Duration,
+}
+
+/// What we know about the traffic on a peer connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    pub connected_at: Instant,
+    /// Bytes received, protocol overhead included.
+    pub downloaded: u64,
+    /// Bytes sent, protocol overhead included.
+    pub uploaded: u64,
+    /// When anything last arrived: a message, a keep-alive or just part of a piece.
+    pub last_received: Instant,
+    pub last_sent: Instant,
+}
+
+/// Bytes moved over all the connections that add to it, protocol overhead included.
+#[derive(Debug, Default)]
+pub(crate) struct Transferred {
+    pub(crate) downloaded: AtomicU64,
+    pub(crate) uploaded: AtomicU64,
+}
+
+/// Keeps the [`PeerStats`] of the stream it wraps where the download can look at them, and adds
+/// them up in a shared [`Transferred`].
+/// It also holds reads and writes back while the bandwidth limits of its [`Throttle`] are used up.
+pub(crate) struct Metered<S> {
+    inner: S,
+    stats: Arc<Mutex<PeerStats>>,
+    totals: Option<Arc<Transferred>>,
+    throttle: Throttle,
+    // timers for waiting out the throttle, kept between polls
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Metered<S> {
+    fn new(inner: S) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            stats: Arc::new(Mutex::new(PeerStats { connected_at: now, downloaded: 0, uploaded: 0, last_received: now, last_sent: now })),
+            totals: None,
+            throttle: Throttle::default(),
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+/// Waits until `ready_at` says there is nothing left to wait for, with the timer kept in `wait`.
+fn poll_throttle(wait: &mut Option<Pin<Box<Sleep>>>, cx: &mut TaskContext<'_>, ready_at: impl Fn() -> Option<Instant>) -> Poll<()> {
+    while let Some(at) = ready_at() {
+        let sleep = wait.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(at)));
+        sleep.as_mut().reset(at);
+        if sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+    }
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if poll_throttle(&mut this.read_wait, cx, || this.throttle.read_at()).is_pending() {
+            return Poll::Pending;
+        }
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            this.throttle.received(n);
+            let mut stats = this.stats.lock().expect("peer stats lock is never poisoned");
+            stats.downloaded += n as u64;
+            stats.last_received = Instant::now();
+            if let Some(totals) = &this.totals {
+                totals.downloaded.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        }
+        result
+    }
 }
 
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if poll_throttle(&mut this.write_wait, cx, || this.throttle.write_at()).is_pending() {
+            return Poll::Pending;
+        }
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                this.throttle.sent(n);
+                let mut stats = this.stats.lock().expect("peer stats lock is never poisoned");
+                stats.uploaded += n as u64;
+                stats.last_sent = Instant::now();
+                if let Some(totals) = &this.totals {
+                    totals.uploaded.fetch_add(n as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
 
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Bitfield { 
     payload : Vec<u8>
 }
 
 impl Bitfield {
+    pub(crate) fn new(payload: Vec<u8>) -> Self {
+        Self { payload }
+    }
+
+    pub(crate) fn full(npieces: usize) -> Self {
+        let mut bitfield = Self::new(vec![0; npieces.div_ceil(u8::BITS as usize)]);
+        for piece_i in 0..npieces {
+            bitfield.set_piece(piece_i);
+        }
+        bitfield
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.payload.iter().all(|&byte| byte == 0)
+    }
+
     pub(crate) fn has_piece(&self, piece_i: usize) -> bool { 
         let byte_i = piece_i / u8::BITS as usize;
         let bit_i = (piece_i % u8::BITS as usize) as u32;
@@ -34,6 +196,15 @@ impl Bitfield {
         (byte & 1u8.rotate_right(bit_i + 1)) != 0
     }
 
+    pub(crate) fn set_piece(&mut self, piece_i: usize) {
+        let byte_i = piece_i / u8::BITS as usize;
+        let bit_i = (piece_i % u8::BITS as usize) as u32;
+        if self.payload.len() <= byte_i {
+            self.payload.resize(byte_i + 1, 0);
+        }
+        self.payload[byte_i] |= 1u8.rotate_right(bit_i + 1);
+    }
+
     pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ { 
         self.payload.iter().enumerate().flat_map(|(byte_i, byte)| { 
             (0..u8::BITS).filter_map(move |bit_i| { 
@@ -54,159 +225,485 @@ fn test_bitfield_pieces() {
 }
 
 impl Peer { 
-    pub async fn new(peer_addr : SocketAddrV4, info_hash : [u8; 20]) -> anyhow::Result<Self> { 
-        let mut peer_conn = tokio::net::TcpStream::connect(peer_addr).await.context("connect to peer")?;
-        let mut handshake = PeerHandShake::new(&info_hash, b"00112233445566778899");
-        {
-            let  handshake_bytes = handshake.as_bytes_mut();
-            peer_conn.write_all( handshake_bytes).await.context("write to conn")?;
-            peer_conn.read_exact( handshake_bytes).await.context("read from other side of handshake")?;
+    pub async fn new(peer_addr : SocketAddrV4, info_hash : [u8; 20], npieces: usize, connector: &dyn Connector, encryption: Encryption, dht: Option<Arc<Dht>>) -> anyhow::Result<Self> { 
+        let peer_conn = Self::connect(peer_addr, &info_hash, connector, encryption).await?;
+        Self::handshake(peer_addr, peer_conn, info_hash, npieces, dht).await
+    }
+
+    /// Runs the BitTorrent handshake over an already open `peer_conn`, up to the peer's bitfield.
+    pub async fn handshake(peer_addr : SocketAddrV4, peer_conn: Box<dyn PeerStream>, info_hash : [u8; 20], npieces: usize, dht: Option<Arc<Dht>>) -> anyhow::Result<Self> {
+        let mut peer_conn = Metered::new(peer_conn);
+        let theirs = Self::our_handshake(&info_hash, &dht).exchange(&mut peer_conn).await?;
+        anyhow::ensure!(theirs.info_hash == info_hash, "peer {peer_addr} is on another torrent");
+        Self::establish(peer_addr, peer_conn, theirs, npieces, dht).await
+    }
+
+    /// Takes over a connection the peer opened, once `theirs`, its handshake, has been read: we
+    /// answer with ours and carry on as [`Peer::handshake`] does.
+    pub async fn accept(peer_addr : SocketAddrV4, peer_conn: Box<dyn PeerStream>, theirs: Handshake, npieces: usize, dht: Option<Arc<Dht>>) -> anyhow::Result<Self> {
+        let mut peer_conn = Metered::new(peer_conn);
+        peer_conn
+            .write_all(&Self::our_handshake(&theirs.info_hash, &dht).encode())
+            .await
+            .context("write handshake")?;
+        Self::establish(peer_addr, peer_conn, theirs, npieces, dht).await
+    }
+
+    fn our_handshake(info_hash: &[u8; 20], dht: &Option<Arc<Dht>>) -> Handshake {
+        let mut handshake = Handshake::new(info_hash, b"00112233445566778899");
+        handshake.reserved[7] |= Handshake::FAST;
+        if dht.is_some() {
+            handshake.reserved[7] |= Handshake::DHT;
         }
+        handshake
+    }
+
+    /// Everything after the handshakes: the peer's bitfield, and our DHT port.
+    async fn establish(peer_addr : SocketAddrV4, peer_conn: Metered<Box<dyn PeerStream>>, theirs: Handshake, npieces: usize, dht: Option<Arc<Dht>>) -> anyhow::Result<Self> {
+        let fast = theirs.reserved[7] & Handshake::FAST != 0;
         
-        let mut peer_conn = tokio_util::codec::Framed::new(peer_conn, MessageFramer);
-        let bitfield: Message   = peer_conn.next().await.context("first message tag should be a bitfield tag")??;
-        Ok(Peer { peer_addr : peer_addr, stream : peer_conn, bitfield: Bitfield {payload: Vec::new() }, choked: true })
+        let mut peer_conn = tokio_util::codec::Framed::new(peer_conn, MessageFramer::for_pieces(npieces));
+        let first: Message   = peer_conn.next().await.context("first message tag should be a bitfield tag")??;
+        let bitfield = match first {
+            Message::Bitfield(bitfield) => bitfield,
+            Message::HaveAll if fast => Bitfield::full(npieces),
+            Message::HaveNone if fast => Bitfield::new(Vec::new()),
+            first => anyhow::bail!("first message from {peer_addr} was {first}, not a bitfield"),
+        };
+        Self::send_port(&mut peer_conn, &theirs, &dht).await?;
+        Ok(Peer { peer_addr, peer_id: theirs.peer_id, stream : peer_conn, bitfield, choked: true, fast, allowed_fast: HashSet::new(), dht, keep_alive: KEEP_ALIVE, idle_timeout: IDLE_TIMEOUT })
+    }
+
+    /// Takes over a connection the peer opened to a torrent we seed, as [`Peer::accept`] does,
+    /// except that we tell the peer the pieces we `have` rather than wait to hear of its own.
+    pub async fn accept_to_seed(peer_addr : SocketAddrV4, peer_conn: Box<dyn PeerStream>, theirs: Handshake, have: &Bitfield, npieces: usize, dht: Option<Arc<Dht>>) -> anyhow::Result<Self> {
+        let mut peer_conn = Metered::new(peer_conn);
+        peer_conn
+            .write_all(&Self::our_handshake(&theirs.info_hash, &dht).encode())
+            .await
+            .context("write handshake")?;
+        let fast = theirs.reserved[7] & Handshake::FAST != 0;
+
+        let mut peer_conn = tokio_util::codec::Framed::new(peer_conn, MessageFramer::for_pieces(npieces));
+        let ours = if fast && (0..npieces).all(|piece_i| have.has_piece(piece_i)) { Message::HaveAll } else { Message::Bitfield(have.clone()) };
+        peer_conn.send(ours).await.context("send bitfield")?;
+        Self::send_port(&mut peer_conn, &theirs, &dht).await?;
+        Ok(Peer { peer_addr, peer_id: theirs.peer_id, stream : peer_conn, bitfield: Bitfield::new(Vec::new()), choked: true, fast, allowed_fast: HashSet::new(), dht, keep_alive: KEEP_ALIVE, idle_timeout: IDLE_TIMEOUT })
     }
 
-    pub(crate) fn has_piece(&self, piece_i : usize) -> bool  { 
-        self.bitfield.has_piece(piece_i)
+    /// Tells a peer whose handshake, `theirs`, says it runs a DHT node where ours listens.
+    async fn send_port(peer_conn: &mut Framed<Metered<Box<dyn PeerStream>>, MessageFramer>, theirs: &Handshake, dht: &Option<Arc<Dht>>) -> anyhow::Result<()> {
+        if let Some(dht) = dht {
+            if theirs.reserved[7] & Handshake::DHT != 0 {
+                peer_conn
+                    .send(Message::Port(dht.local_addr().port()))
+                    .await
+                    .context("send dht port")?;
+            }
+        }
+        Ok(())
     }
 
+    /// Opens the connection through `connector`, obfuscated if `encryption` asks for it.
+    async fn connect(peer_addr: SocketAddrV4, info_hash: &[u8; 20], connector: &dyn Connector, encryption: Encryption) -> anyhow::Result<Box<dyn PeerStream>> {
+        let conn = connector.connect(peer_addr).await?;
+        if encryption == Encryption::Disabled {
+            return Ok(conn);
+        }
+        let encrypted = tokio::time::timeout(mse::HANDSHAKE_TIMEOUT, mse::initiate(conn, info_hash, encryption))
+            .await
+            .context("encrypted handshake timed out")
+            .and_then(|handshake| handshake.context("encrypted handshake"));
+        match encrypted {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(e) if encryption == Encryption::Require => Err(e),
+            Err(_) => {
+                // the peer only speaks plaintext, and has most likely hung up on us by now
+                connector.connect(peer_addr).await.context("reconnect to peer")
+            }
+        }
+    }
+
+    pub(crate) fn peer_addr(&self) -> SocketAddrV4 {
+        self.peer_addr
+    }
+
+    pub(crate) fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// Holds the connection to the bandwidth limits of `throttle` from here on.
+    pub(crate) fn throttle(&mut self, throttle: Throttle) {
+        self.stream.get_mut().throttle = throttle;
+    }
+
+    /// Adds the traffic of the connection to `totals` from here on.
+    pub(crate) fn count_into(&mut self, totals: Arc<Transferred>) {
+        self.stream.get_mut().totals = Some(totals);
+    }
+
+    pub(crate) fn stats(&self) -> PeerStats {
+        *self.stream.get_ref().stats.lock().expect("peer stats lock is never poisoned")
+    }
+
+    /// The stats of the connection as they change, for whoever outlives our hold on the peer.
+    pub(crate) fn shared_stats(&self) -> Arc<Mutex<PeerStats>> {
+        self.stream.get_ref().stats.clone()
+    }
+
+    /// When the connection next needs tending to. Whether the peer has gone quiet only counts
+    /// with `check_idle`, when we are reading from it.
+    fn tend_at(&self, check_idle: bool) -> Instant {
+        let stats = self.stats();
+        let keep_alive = stats.last_sent + self.keep_alive;
+        if check_idle {
+            keep_alive.min(stats.last_received + self.idle_timeout)
+        } else {
+            keep_alive
+        }
+    }
+
+    /// Sends a keep-alive if we have been quiet for a while, and gives up on a peer that has.
+    async fn tend(&mut self, check_idle: bool) -> anyhow::Result<()> {
+        let stats = self.stats();
+        if check_idle && stats.last_received.elapsed() >= self.idle_timeout {
+            anyhow::bail!("peer went idle: nothing received for {:?}", stats.last_received.elapsed());
+        }
+        if stats.last_sent.elapsed() >= self.keep_alive {
+            self.stream.send(KeepAlive).await.context("send keep-alive")?;
+        }
+        Ok(())
+    }
+
+    /// Hands the DHT node the peer says it runs to our own DHT node, which pings it and keeps it
+    /// if it answers.
+    fn port(&self, port: u16) {
+        let Some(dht) = &self.dht else {
+            return;
+        };
+        let node = SocketAddrV4::new(*self.peer_addr.ip(), port);
+        let dht = dht.clone();
+        tokio::spawn(async move { dht.add_nodes([node]).await });
+    }
+
+    fn have(&mut self, have: Have, scheduler: &Scheduler) {
+        let piece_i = have.index as usize;
+        if !self.bitfield.has_piece(piece_i) {
+            self.bitfield.set_piece(piece_i);
+            scheduler.have(piece_i);
+        }
+    }
+
+    /// The pieces we may request while choked: the allowed-fast ones the peer actually has.
+    fn allowed_fast(&self) -> Bitfield {
+        let mut allowed = Bitfield::new(Vec::new());
+        for &piece_i in self.allowed_fast.iter().filter(|&&piece_i| self.bitfield.has_piece(piece_i)) {
+            allowed.set_piece(piece_i);
+        }
+        allowed
+    }
+
+    /// Handles the messages that mean the same whatever we are waiting for.
+    async fn on_message(&mut self, msg: Message, scheduler: &Scheduler) -> anyhow::Result<()> {
+        if msg.tag().is_some_and(|tag| tag.is_fast()) {
+            anyhow::ensure!(self.fast, "peer sent {msg} without negotiating the fast extension");
+        }
+        match msg {
+            Message::Have(have) => {
+                self.have(have, scheduler);
+            }
+            Message::Port(port) => {
+                self.port(port);
+            }
+            Message::AllowedFast(piece_i) => {
+                self.allowed_fast.insert(piece_i as usize);
+            }
+            Message::SuggestPiece(_) => {
+                // only a hint; the scheduler knows better which pieces we are short of
+            }
+            Message::Request(request) if self.fast => {
+                // not allowing requests for now, but a fast peer deserves to hear so
+                self.stream
+                    .send(Message::RejectRequest(request))
+                    .await
+                    .context("reject request")?;
+            }
+            Message::Interested
+            | Message::NotInterested
+            | Message::Request(_)
+            | Message::Cancel(_) => {
+                // not allowing requests for now
+            }
+            Message::Piece(_) | Message::RejectRequest(_) => {
+                // piece that we no longer need/are responsible for
+            }
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                anyhow::bail!("peer sent {msg} after handshake has been completed");
+            }
+            Message::Extended(..) | Message::Unknown(..) => {
+                // an extension we do not speak, and did not advertise
+            }
+            Message::Choke | Message::Unchoke => {
+                unreachable!("choking is handled by the caller")
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches blocks from the peer until the scheduler has none left that it has, handing each
+    /// completed one to `finish` along with the peer's address. Up to [`PIPELINE`] requests are
+    /// kept outstanding at a time. Cancelling `retire` makes the peer stop, cleanly, as soon as
+    /// it has no request outstanding.
     pub(crate) async fn participate(
         &mut self,
-        piece_i: usize,
-        piece_size: usize,
-        nblocks: usize,
-        submit: kanal::AsyncSender<usize>,
-        tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<Message>,
+        scheduler: &Scheduler,
+        finish: tokio::sync::mpsc::Sender<(SocketAddrV4, PieceBlock)>,
+        retire: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mut requested = HashSet::new();
+        scheduler.add_peer(&self.bitfield);
+        let result = self
+            .participate_inner(scheduler, finish, retire, &mut requested)
+            .await
+            .with_context(|| format!("participate with peer {}", self.peer_addr));
+        scheduler.remove_peer(&self.bitfield);
+        for block in requested {
+            // someone else will have to fetch the blocks we were waiting on
+            scheduler.requeue(block);
+        }
+        result
+    }
+
+    async fn participate_inner(
+        &mut self,
+        scheduler: &Scheduler,
+        finish: tokio::sync::mpsc::Sender<(SocketAddrV4, PieceBlock)>,
+        retire: &CancellationToken,
+        requested: &mut HashSet<Block>,
     ) -> anyhow::Result<()> {
+        let mut cancelled = scheduler.subscribe();
         self.stream
-            .send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
+            .send(Message::Interested)
             .await
             .context("send interested message")?;
 
-        'task: loop {
-            while self.choked {
-                let unchoke = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends an unchoke")
-                    .context("peer message was invalid")?;
-                match unchoke.tag {
-                    MessageTag::Unchoke => {
-                        self.choked = false;
-                        assert!(unchoke.payload.is_empty());
+        // while choked we can still fetch allowed-fast pieces, if the peer gave us any
+        let mut allowed = self.allowed_fast();
+        let mut allowed_useful = !allowed.is_empty();
+        loop {
+            if retire.is_cancelled() && requested.is_empty() {
+                break;
+            }
+            let top_up = requested.len() < PIPELINE && !retire.is_cancelled() && (!self.choked || allowed_useful);
+            let wanted = if self.choked { &allowed } else { &self.bitfield };
+            // an unchoked peer we have nothing outstanding with may well go quiet
+            let tend_at = self.tend_at(self.choked || !requested.is_empty());
+            let msg;
+            tokio::select! {
+                next = self.stream.next() => {
+                    msg = next
+                        .context("peer closed the connection")?
+                        .context("peer message was invalid")?;
+                }
+                next = scheduler.next(wanted, requested), if top_up => {
+                    let Some(block) = next else {
+                        if self.choked {
+                            // nothing we need among them; wait for the unchoke
+                            allowed_useful = false;
+                            continue;
+                        }
                         break;
+                    };
+                    requested.insert(block);
+                    self.stream
+                        .send(Message::Request(block.request()))
+                        .await
+                        .with_context(|| format!("send request for block {block:?}"))?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(tend_at) => {
+                    self.tend(self.choked || !requested.is_empty()).await?;
+                    continue;
+                }
+                Ok(arrived) = cancelled.recv() => {
+                    if requested.remove(&arrived) {
+                        // another peer beat this one to the block during end-game
+                        self.stream
+                            .send(Message::Cancel(arrived.request()))
+                            .await
+                            .with_context(|| format!("send cancel for block {arrived:?}"))?;
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
-                    }
-                    MessageTag::Piece => {
-                        // piece that we no longer need/are responsible for
-                    }
-                    MessageTag::Choke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
-                    }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
-                    }
+                    continue;
+                }
+                _ = retire.cancelled(), if requested.is_empty() => {
+                    break;
                 }
             }
-            let Ok(block) = tasks.recv().await else {
-                break;
-            };
-
-            let block_size = if block == nblocks - 1 {
-                let md = piece_size % BLOCK_MAX;
-                if md == 0 {
-                    BLOCK_MAX
-                } else {
-                    md
+
+            match msg {
+                Message::Choke if self.choked => {
+                    anyhow::bail!("peer sent choke while choked");
                 }
-            } else {
-                BLOCK_MAX
-            };
-
-            let mut request = Request::new(
-                piece_i as u32,
-                (block * BLOCK_MAX) as u32,
-                block_size as u32,
-            );
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.stream
-                .send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
-
-            // TODO: timeout and return block to submit if timed out
-            let mut msg;
-            loop {
-                msg = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
-
-                match msg.tag {
-                    MessageTag::Choke => {
-                        assert!(msg.payload.is_empty());
-                        self.choked = true;
-                        submit.send(block).await.expect("we still have a receiver");
-                        continue 'task;
-                    }
-                    MessageTag::Piece => {
-                        let piece = Piece::ref_from_bytes(&msg.payload[..])
-                            .expect("always get all Piece response fields from peer");
-
-                        if piece.index() as usize != piece_i
-                            || piece.begin() as usize != block * BLOCK_MAX
-                        {
-                            // piece that we no longer need/are responsible for
-                        } else {
-                            assert_eq!(piece.block().len(), block_size);
-                            break;
+                Message::Choke => {
+                    self.choked = true;
+                    if !self.fast {
+                        // without the fast extension a choke drops all our requests; with it,
+                        // the peer either still serves them or tells us it won't
+                        for block in requested.drain() {
+                            scheduler.requeue(block);
                         }
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
+                }
+                Message::Unchoke if self.choked => {
+                    self.choked = false;
+                }
+                Message::Unchoke => {
+                    anyhow::bail!("peer sent unchoke while unchoked");
+                }
+                Message::RejectRequest(rejected) if requested.iter().any(|block| block.request() == rejected) => {
+                    anyhow::ensure!(self.fast, "peer sent {msg} without negotiating the fast extension");
+                    let block = *requested.iter().find(|block| block.request() == rejected).expect("just found");
+                    requested.remove(&block);
+                    scheduler.requeue(block);
+                    if !self.choked {
+                        // a peer that turns down requests while unchoking us has nothing more
+                        // for us
+                        return Ok(());
                     }
-                    MessageTag::Unchoke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
+                }
+                Message::Piece(piece) => {
+                    let block = requested
+                        .iter()
+                        .find(|block| block.piece == piece.index as usize && block.begin() == piece.begin as usize)
+                        .copied();
+                    let Some(block) = block else {
+                        // piece that we no longer need/are responsible for
+                        continue;
+                    };
+                    anyhow::ensure!(
+                        piece.block.len() == block.length,
+                        "peer sent {} bytes for block {block:?}",
+                        piece.block.len()
+                    );
+                    self.stream.get_ref().throttle.received_payload(piece.block.len());
+                    requested.remove(&block);
+                    if scheduler.complete(block) {
+                        finish.send((self.peer_addr, piece)).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
                     }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
+                }
+                msg => {
+                    let tag = msg.tag();
+                    self.on_message(msg, scheduler).await?;
+                    if tag == Some(MessageTag::AllowedFast) || tag == Some(MessageTag::Have) {
+                        allowed = self.allowed_fast();
+                        allowed_useful = !allowed.is_empty();
                     }
                 }
             }
-
-            finish.send(msg).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
         }
 
         Ok(())
     }
+
+    /// Serves the blocks the peer asks for out of `storage`, which holds the pieces we `have` of
+    /// a torrent of `length` bytes in pieces of `plength`. The peer is unchoked as soon as it is
+    /// interested, and served until it hangs up, goes quiet or has every piece itself, or until
+    /// `stop` is cancelled.
+    pub(crate) async fn upload(
+        &mut self,
+        have: &Bitfield,
+        storage: &dyn Storage,
+        plength: usize,
+        length: usize,
+        stop: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let npieces = length.div_ceil(plength);
+        let mut unchoked = false;
+        loop {
+            if (0..npieces).all(|piece_i| self.bitfield.has_piece(piece_i)) {
+                // a seed has nothing to ask of us
+                return Ok(());
+            }
+            let tend_at = self.tend_at(true);
+            let msg;
+            tokio::select! {
+                next = self.stream.next() => {
+                    let Some(next) = next else {
+                        return Ok(());
+                    };
+                    msg = next.context("peer message was invalid")?;
+                }
+                _ = tokio::time::sleep_until(tend_at) => {
+                    self.tend(true).await?;
+                    continue;
+                }
+                _ = stop.cancelled() => {
+                    return Ok(());
+                }
+            }
+
+            if msg.tag().is_some_and(|tag| tag.is_fast()) {
+                anyhow::ensure!(self.fast, "peer sent {msg} without negotiating the fast extension");
+            }
+            match msg {
+                Message::Bitfield(bitfield) => {
+                    self.bitfield = bitfield;
+                }
+                Message::HaveAll => {
+                    self.bitfield = Bitfield::full(npieces);
+                }
+                Message::HaveNone => {
+                    self.bitfield = Bitfield::new(Vec::new());
+                }
+                Message::Have(have) => {
+                    anyhow::ensure!((have.index as usize) < npieces, "peer has piece {} of {npieces}", have.index);
+                    self.bitfield.set_piece(have.index as usize);
+                }
+                Message::Interested if !unchoked => {
+                    self.stream.send(Message::Unchoke).await.context("send unchoke")?;
+                    unchoked = true;
+                }
+                Message::Request(request) => {
+                    let piece_i = request.index as usize;
+                    let (begin, block_len) = (request.begin as usize, request.length as usize);
+                    let piece_len = plength.min(length.saturating_sub(piece_i * plength));
+                    let servable = unchoked
+                        && piece_i < npieces
+                        && have.has_piece(piece_i)
+                        && (1..=BLOCK_MAX).contains(&block_len)
+                        && begin + block_len <= piece_len;
+                    if !servable {
+                        if self.fast {
+                            self.stream
+                                .send(Message::RejectRequest(request))
+                                .await
+                                .context("reject request")?;
+                        }
+                        continue;
+                    }
+                    let mut block = vec![0u8; block_len];
+                    storage
+                        .read(piece_i * plength + begin, &mut block)
+                        .with_context(|| format!("read block {request:?} to upload"))?;
+                    self.stream.get_ref().throttle.sent_payload(block_len);
+                    self.stream
+                        .send(Message::Piece(PieceBlock { index: request.index, begin: request.begin, block: Bytes::from(block) }))
+                        .await
+                        .with_context(|| format!("send block {request:?}"))?;
+                }
+                Message::Port(port) => {
+                    self.port(port);
+                }
+                Message::Cancel(_) => {
+                    // blocks are sent as soon as they are asked for, so there is nothing queued
+                    // to take back
+                }
+                _ => {
+                    // what the peer has to say about its own download is no concern of ours
+                }
+            }
+        }
+    }
 }
 
 
@@ -250,7 +747,7 @@ impl<'de> Visitor<'de> for PeersVisitor {
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error, {
-        if v.len() % 6 != 0 { 
+        if !v.len().is_multiple_of(6) { 
             return Err(E::custom(format!("length is {}", v.len())));
         }
         let slices  = v.chunks_exact(6).map(|slice_6| {
@@ -272,87 +769,148 @@ impl<'de> Deserialize<'de> for Peers {
     }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct PeerHandShake { 
-    pub length: u8, 
-    pub bittorrent: [u8; 19],
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn u32_at(payload: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(payload[i..i + 4].try_into().expect("4 bytes"))
+}
+
+/// The handshake that opens every connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
     pub reserved: [u8; 8],
-    pub info_hash: [u8; 20], 
-    pub peer_id : [u8; 20]
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
 }
 
-impl PeerHandShake {
+impl Handshake {
+    pub const LEN: usize = 1 + 19 + 8 + 20 + 20;
+    const PROTOCOL: &'static [u8; 19] = b"BitTorrent protocol";
+    /// Bit in the last reserved byte that says the client runs a DHT node.
+    pub const DHT: u8 = 0x01;
+    /// Bit in the last reserved byte that says the client speaks the fast extension (BEP 6).
+    pub const FAST: u8 = 0x04;
+    /// Bit in the sixth reserved byte that says the client speaks the extension protocol (BEP 10).
+    pub const EXTENSION: u8 = 0x10;
+
     pub fn new(info_hash : &[u8; 20], peer_id: &[u8; 20]) -> Self { 
-        Self { length : 19, bittorrent : *b"BitTorrent protocol", reserved : [0; 8], info_hash : *info_hash, peer_id : *peer_id}
+        Self { reserved : [0; 8], info_hash : *info_hash, peer_id : *peer_id }
     }
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] { 
-        let h_bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        let h_bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *h_bytes};
-        h_bytes
-        
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[0] = Self::PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(Self::PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        if bytes.len() != Self::LEN {
+            return Err(invalid(format!("handshake of {} bytes", bytes.len())));
+        }
+        if bytes[0] as usize != Self::PROTOCOL.len() || &bytes[1..20] != Self::PROTOCOL {
+            return Err(invalid("peer does not speak the BitTorrent protocol".to_string()));
+        }
+        Ok(Self {
+            reserved: bytes[20..28].try_into().expect("8 bytes"),
+            info_hash: bytes[28..48].try_into().expect("20 bytes"),
+            peer_id: bytes[48..68].try_into().expect("20 bytes"),
+        })
+    }
+
+    /// Sends our handshake over `stream` and reads the other side's.
+    pub async fn exchange<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(&self, stream: &mut S) -> anyhow::Result<Self> {
+        stream.write_all(&self.encode()).await.context("write to conn")?;
+        let mut theirs = [0u8; Self::LEN];
+        stream.read_exact(&mut theirs).await.context("read from other side of handshake")?;
+        Ok(Self::decode(&theirs)?)
     }
 }
-#[repr(C)]
+
+/// Asks for `length` bytes at offset `begin` of piece `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Request { 
-    index : [u8; 4],
-    begin : [u8; 4], 
-    length : [u8; 4]
+    pub index : u32,
+    pub begin : u32, 
+    pub length : u32
 }
 
+/// Takes back a [`Request`] by repeating it.
+pub type Cancel = Request;
 
 impl Request { 
+    const LEN: usize = 12;
+
     pub fn new(index: u32, begin : u32, length : u32) -> Self { 
-        Self { index : index.to_be_bytes(), begin : begin.to_be_bytes(), length: length.to_be_bytes()}
+        Self { index, begin, length }
     }
-    pub fn index(&self) -> u32 { 
-        u32::from_be_bytes(self.index)
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..4].copy_from_slice(&self.index.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.begin.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.length.to_be_bytes());
+        bytes
     }
-    pub fn begin(&self) -> u32 { 
-        u32::from_be_bytes(self.begin)
+
+    pub fn decode(payload: &[u8]) -> std::io::Result<Self> {
+        if payload.len() != Self::LEN {
+            return Err(invalid(format!("request of {} bytes", payload.len())));
+        }
+        Ok(Self { index: u32_at(payload, 0), begin: u32_at(payload, 4), length: u32_at(payload, 8) })
     }
-    pub fn length(&self) -> u32 { 
-        u32::from_be_bytes(self.length)
+}
+
+/// Says the sender now has piece `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Have {
+    pub index: u32,
+}
+
+impl Have {
+    pub fn encode(&self) -> [u8; 4] {
+        self.index.to_be_bytes()
     }
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] { 
-        let p_bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        let p_bytes : &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *p_bytes};
-        p_bytes
+
+    pub fn decode(payload: &[u8]) -> std::io::Result<Self> {
+        let index: [u8; 4] = payload.try_into().map_err(|_| invalid(format!("piece index of {} bytes", payload.len())))?;
+        Ok(Self { index: u32::from_be_bytes(index) })
     }
 }
-#[repr(C)]
-pub struct Piece<T: ?Sized = [u8]> { 
-    index : [u8; 4],
-    begin : [u8; 4], 
-    block : T
+
+/// A block of data: the answer to a [`Request`]. The block shares its memory with the buffer the
+/// frame was read into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceBlock { 
+    pub index : u32,
+    pub begin : u32, 
+    pub block : Bytes
 }
 
+impl PieceBlock {
+    const LEAD: usize = 8;
 
-impl Piece { 
-    
-    pub fn index(&self) -> u32 { 
-        u32::from_be_bytes(self.index)
+    pub fn encode_to(&self, dst: &mut BytesMut) {
+        dst.extend_from_slice(&self.index.to_be_bytes());
+        dst.extend_from_slice(&self.begin.to_be_bytes());
+        dst.extend_from_slice(&self.block);
     }
-    pub fn begin(&self) -> u32 { 
-        u32::from_be_bytes(self.begin)
-    }
-    pub fn block(&self) -> &[u8] { 
-        &self.block
-    }
-    
-    const PIECE_LEAD : usize = std::mem::size_of::<Piece<()>>();
-    pub fn ref_from_bytes(data : &[u8]) -> Option<&Self> {
-        if data.len() < Self::PIECE_LEAD {
-            return None;
+
+    pub fn decode(payload: Bytes) -> std::io::Result<Self> {
+        if payload.len() < Self::LEAD {
+            return Err(invalid(format!("piece message of {} bytes", payload.len())));
         }
-        let n = data.len();
-        let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const Piece;
-        Some(unsafe { &*piece})
+        Ok(Self { index: u32_at(&payload, 0), begin: u32_at(&payload, 4), block: payload.slice(Self::LEAD..) })
     }
 }
 
 #[repr(u8)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
  pub enum MessageTag { 
     Choke = 0, 
     Unchoke = 1, 
@@ -362,19 +920,227 @@ impl Piece {
     Bitfield = 5, 
     Request = 6, 
     Piece = 7, 
-    Cancel = 8
+    Cancel = 8,
+    Port = 9,
+    // the fast extension (BEP 6)
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    // the extension protocol (BEP 10)
+    Extended = 20
+}
+
+impl MessageTag {
+    /// Whether the message is only allowed once both sides negotiated the fast extension.
+    pub fn is_fast(&self) -> bool {
+        matches!(
+            self,
+            MessageTag::SuggestPiece
+                | MessageTag::HaveAll
+                | MessageTag::HaveNone
+                | MessageTag::RejectRequest
+                | MessageTag::AllowedFast
+        )
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Some(match id {
+            0 => MessageTag::Choke,
+            1 => MessageTag::Unchoke,
+            2 => MessageTag::Interested,
+            3 => MessageTag::NotInterested,
+            4 => MessageTag::Have,
+            5 => MessageTag::Bitfield,
+            6 => MessageTag::Request,
+            7 => MessageTag::Piece,
+            8 => MessageTag::Cancel,
+            9 => MessageTag::Port,
+            13 => MessageTag::SuggestPiece,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
+            _ => return None,
+        })
+    }
+}
+
+/// A peer wire message with its payload decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message { 
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(Have),
+    Bitfield(Bitfield),
+    Request(Request),
+    Piece(PieceBlock),
+    Cancel(Cancel),
+    /// The port the sender's DHT node listens on.
+    Port(u16),
+    /// A piece index the sender thinks we would do well to request.
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    /// The sender will not serve this request.
+    RejectRequest(Request),
+    /// A piece index we may request even while choked.
+    AllowedFast(u32),
+    /// A message of an extension (BEP 10): the extension's id, as the receiver numbered it, and
+    /// its payload. Id 0 is the extension handshake.
+    Extended(u8, Bytes),
+    /// A message with an id we do not know, say from an extension we do not speak.
+    Unknown(u8, Bytes),
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tag() {
+            Some(tag) => write!(f, "{tag:?}"),
+            None => write!(f, "unknown message {}", self.id()),
+        }
+    }
+}
+
+impl Message {
+    /// The kind of message, or `None` for an [`Unknown`](Message::Unknown) one.
+    pub fn tag(&self) -> Option<MessageTag> {
+        Some(match self {
+            Message::Choke => MessageTag::Choke,
+            Message::Unchoke => MessageTag::Unchoke,
+            Message::Interested => MessageTag::Interested,
+            Message::NotInterested => MessageTag::NotInterested,
+            Message::Have(_) => MessageTag::Have,
+            Message::Bitfield(_) => MessageTag::Bitfield,
+            Message::Request(_) => MessageTag::Request,
+            Message::Piece(_) => MessageTag::Piece,
+            Message::Cancel(_) => MessageTag::Cancel,
+            Message::Port(_) => MessageTag::Port,
+            Message::SuggestPiece(_) => MessageTag::SuggestPiece,
+            Message::HaveAll => MessageTag::HaveAll,
+            Message::HaveNone => MessageTag::HaveNone,
+            Message::RejectRequest(_) => MessageTag::RejectRequest,
+            Message::AllowedFast(_) => MessageTag::AllowedFast,
+            Message::Extended(..) => MessageTag::Extended,
+            Message::Unknown(..) => return None,
+        })
+    }
+
+    /// The message id on the wire.
+    pub fn id(&self) -> u8 {
+        match self {
+            Message::Unknown(id, _) => *id,
+            known => known.tag().expect("only unknown messages lack a tag") as u8,
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => 0,
+            Message::Have(_) | Message::SuggestPiece(_) | Message::AllowedFast(_) => 4,
+            Message::Bitfield(bitfield) => bitfield.as_bytes().len(),
+            Message::Request(_) | Message::Cancel(_) | Message::RejectRequest(_) => Request::LEN,
+            Message::Piece(piece) => PieceBlock::LEAD + piece.block.len(),
+            Message::Port(_) => 2,
+            Message::Extended(_, payload) => 1 + payload.len(),
+            Message::Unknown(_, payload) => payload.len(),
+        }
+    }
+
+    fn encode_payload(&self, dst: &mut BytesMut) {
+        match self {
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => {}
+            Message::Have(have) => dst.extend_from_slice(&have.encode()),
+            Message::SuggestPiece(index) | Message::AllowedFast(index) => dst.extend_from_slice(&index.to_be_bytes()),
+            Message::Bitfield(bitfield) => dst.extend_from_slice(bitfield.as_bytes()),
+            Message::Request(request) | Message::Cancel(request) | Message::RejectRequest(request) => {
+                dst.extend_from_slice(&request.encode())
+            }
+            Message::Piece(piece) => piece.encode_to(dst),
+            Message::Port(port) => dst.extend_from_slice(&port.to_be_bytes()),
+            Message::Extended(id, payload) => {
+                dst.put_u8(*id);
+                dst.extend_from_slice(payload);
+            }
+            Message::Unknown(_, payload) => dst.extend_from_slice(payload),
+        }
+    }
+
+    fn decode(tag: MessageTag, payload: Bytes) -> std::io::Result<Self> {
+        let empty = |message: Message| {
+            if payload.is_empty() {
+                Ok(message)
+            } else {
+                Err(invalid(format!("{tag:?} message with a {} byte payload", payload.len())))
+            }
+        };
+        match tag {
+            MessageTag::Choke => empty(Message::Choke),
+            MessageTag::Unchoke => empty(Message::Unchoke),
+            MessageTag::Interested => empty(Message::Interested),
+            MessageTag::NotInterested => empty(Message::NotInterested),
+            MessageTag::HaveAll => empty(Message::HaveAll),
+            MessageTag::HaveNone => empty(Message::HaveNone),
+            MessageTag::Have => Ok(Message::Have(Have::decode(&payload)?)),
+            MessageTag::SuggestPiece => Ok(Message::SuggestPiece(Have::decode(&payload)?.index)),
+            MessageTag::AllowedFast => Ok(Message::AllowedFast(Have::decode(&payload)?.index)),
+            MessageTag::Bitfield => Ok(Message::Bitfield(Bitfield::new(payload.to_vec()))),
+            MessageTag::Request => Ok(Message::Request(Request::decode(&payload)?)),
+            MessageTag::Cancel => Ok(Message::Cancel(Request::decode(&payload)?)),
+            MessageTag::RejectRequest => Ok(Message::RejectRequest(Request::decode(&payload)?)),
+            MessageTag::Piece => Ok(Message::Piece(PieceBlock::decode(payload)?)),
+            MessageTag::Port => {
+                let port: [u8; 2] = payload[..].try_into().map_err(|_| invalid(format!("port of {} bytes", payload.len())))?;
+                Ok(Message::Port(u16::from_be_bytes(port)))
+            }
+            MessageTag::Extended => match payload.first() {
+                Some(&id) => Ok(Message::Extended(id, payload.slice(1..))),
+                None => Err(invalid("extended message without an extension id".to_string())),
+            },
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct Message { 
-    pub tag : MessageTag,
-    pub payload : Vec<u8>
+
+/// Encodes and decodes length-prefixed peer wire messages, turning away frames longer than
+/// `max_frame` so that a peer cannot make us buffer without bound.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageFramer {
+    max_frame: usize,
 }
 
+impl MessageFramer {
+    /// A framer for a torrent of `npieces` pieces: frames can hold its whole bitfield or a block of
+    /// up to [`BLOCK_MAX`], whichever is larger.
+    pub fn for_pieces(npieces: usize) -> Self {
+        Self { max_frame: 1 + (PieceBlock::LEAD + BLOCK_MAX).max(npieces.div_ceil(u8::BITS as usize)) }
+    }
 
-const MAX : usize = 1 << 16;
+    /// A framer that turns away frames longer than `max_frame`.
+    pub fn with_max_frame(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
+}
 
-pub struct MessageFramer;
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self { max_frame: 1 << 16 }
+    }
+}
 
 impl Decoder for MessageFramer {
     type Item = Message;
@@ -401,7 +1167,7 @@ impl Decoder for MessageFramer {
         }
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
-        if length > MAX {
+        if length > self.max_frame {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", length),
@@ -417,32 +1183,28 @@ impl Decoder for MessageFramer {
             // frame.
             return Ok(None);
         }
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = match src[4] {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            tag => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unknown message type {}.", tag),
-                ))
-            }
-        };
-        let data = if src.len() > 5 {
-            src[5..4 + length].to_vec()
-        } else {
-            Vec::new()
-        };
-        src.advance(4 + length);
-        Ok(Some(Message { tag, payload: data }))
+        // Split the frame off src instead of copying it out: the payload
+        // keeps pointing into the same memory.
+        let frame = src.split_to(4 + length).freeze();
+        let payload = frame.slice(5..);
+        match MessageTag::from_id(frame[4]) {
+            Some(tag) => Message::decode(tag, payload).map(Some),
+            // most likely an extension we do not speak; up to the caller what to do with it
+            None => Ok(Some(Message::Unknown(frame[4], payload))),
+        }
+    }
+}
+
+/// The empty frame that tells the peer we are still here.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive;
+
+impl Encoder<KeepAlive> for MessageFramer {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, _: KeepAlive, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&0u32.to_be_bytes());
+        Ok(())
     }
 }
 
@@ -450,26 +1212,223 @@ impl Encoder<Message> for MessageFramer {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let payload_len = item.payload_len();
         // Don't send a string if it is longer than the other end will
         // accept.
-        if item.payload.len() + 1 > MAX { // 1 extra for message_id which a single extra byte
+        if payload_len + 1 > self.max_frame { // 1 extra for message_id which a single extra byte
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len() + 1)
+                format!("Frame of length {} is too large.", payload_len + 1)
             ));
         }
 
         // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
+        let len_slice = u32::to_be_bytes(payload_len as u32 + 1);
 
         // Reserve space in the buffer.
-        dst.reserve(4 + 1 + item.payload.len());
+        dst.reserve(4 + 1 + payload_len);
 
-        // Write the length and string to the buffer.
+        // Write the length and payload to the buffer.
         dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(&item.payload);
+        dst.put_u8(item.id());
+        item.encode_payload(dst);
         Ok(())
     }
 }
+
+#[test]
+fn test_wire_messages() {
+    let handshake = Handshake { reserved: [0, 0, 0, 0, 0, 0x10, 0, Handshake::FAST], info_hash: [1; 20], peer_id: [2; 20] };
+    assert_eq!(Handshake::decode(&handshake.encode()).unwrap(), handshake);
+    let mut not_bittorrent = handshake.encode();
+    not_bittorrent[1] = b'b';
+    assert!(Handshake::decode(&not_bittorrent).is_err());
+    assert!(Handshake::decode(&[19]).is_err());
+
+    let messages = [
+        Message::Unchoke,
+        Message::Have(Have { index: 0x01020304 }),
+        Message::Bitfield(Bitfield::new(vec![0b1010_0000])),
+        Message::Request(Request::new(1, 0x4000, 0x4000)),
+        Message::Piece(PieceBlock { index: 1, begin: 0x4000, block: Bytes::from_static(&[7; 5]) }),
+        Message::Cancel(Request::new(1, 0x4000, 0x4000)),
+        Message::Port(6881),
+        Message::RejectRequest(Request::new(2, 0, 10)),
+        Message::AllowedFast(9),
+    ];
+    let mut buf = BytesMut::new();
+    for message in messages.iter().cloned() {
+        MessageFramer::default().encode(message, &mut buf).unwrap();
+    }
+    // the request sits on the wire big-endian, whatever the host
+    assert_eq!(&buf[5 + 9 + 6..][..17], &[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0]);
+    for message in messages {
+        assert_eq!(MessageFramer::default().decode(&mut buf).unwrap(), Some(message));
+    }
+    assert!(buf.is_empty());
+
+    // a piece's block is a view of the read buffer, not a copy of it
+    MessageFramer::default().encode(Message::Piece(PieceBlock { index: 0, begin: 0, block: Bytes::from(vec![1; 100]) }), &mut buf).unwrap();
+    let frame_start = buf.as_ptr();
+    let Some(Message::Piece(piece)) = MessageFramer::default().decode(&mut buf).unwrap() else { panic!("expected a piece") };
+    assert_eq!(piece.block.as_ptr(), frame_start.wrapping_add(4 + 1 + 8));
+
+    // payloads that do not fit their message are errors, not panics or out-of-bounds reads
+    for frame in [&[0, 0, 0, 3, 7, 0, 0][..], &[0, 0, 0, 4, 6, 0, 0, 0], &[0, 0, 0, 2, 1, 0], &[0, 0, 0, 2, 4, 0]] {
+        assert!(MessageFramer::default().decode(&mut BytesMut::from(frame)).is_err());
+    }
+
+    // messages we do not know about come through as they are
+    let mut unknown = BytesMut::from(&[0, 0, 0, 3, 42, 0, 0xab][..]);
+    let unknown = MessageFramer::default().decode(&mut unknown).unwrap().unwrap();
+    assert_eq!(unknown, Message::Unknown(42, Bytes::from_static(&[0, 0xab])));
+    assert_eq!(unknown.to_string(), "unknown message 42");
+    // and extension messages with the extension's id split off
+    let mut extended = BytesMut::from(&[0, 0, 0, 3, 20, 3, 0xab][..]);
+    let extended = MessageFramer::default().decode(&mut extended).unwrap().unwrap();
+    assert_eq!(extended, Message::Extended(3, Bytes::from_static(&[0xab])));
+    MessageFramer::default().encode(extended, &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0, 0, 0, 3, 20, 3, 0xab]);
+    buf.clear();
+    assert!(MessageFramer::default().decode(&mut BytesMut::from(&[0, 0, 0, 1, 20][..])).is_err());
+
+    // the frame limit grows with the bitfield of a torrent with many pieces
+    let bitfield = Message::Bitfield(Bitfield::full(1 << 20));
+    MessageFramer::for_pieces(1 << 20).encode(bitfield.clone(), &mut buf).unwrap();
+    assert!(MessageFramer::for_pieces(100).decode(&mut buf.clone()).is_err());
+    assert_eq!(MessageFramer::for_pieces(1 << 20).decode(&mut buf).unwrap(), Some(bitfield));
+    assert_eq!(MessageFramer::for_pieces(1).max_frame, 1 + 8 + BLOCK_MAX);
+}
+
+#[tokio::test]
+async fn test_fast_extension_while_choked() {
+    use crate::{piece::PickerKind, scheduler::test_pieces};
+    let (ours, mut conn) = tokio::io::duplex(1 << 16);
+    let seeder = tokio::spawn(async move {
+        let mut handshake = [0u8; Handshake::LEN];
+        conn.read_exact(&mut handshake).await.unwrap();
+        let handshake = Handshake::decode(&handshake).unwrap();
+        assert_ne!(handshake.reserved[7] & Handshake::FAST, 0);
+        conn.write_all(&handshake.encode()).await.unwrap();
+        let mut conn = Framed::new(conn, MessageFramer::default());
+        conn.send(Message::HaveAll).await.unwrap();
+        conn.send(Message::AllowedFast(1)).await.unwrap();
+        conn.send(Message::Request(Request::new(0, 0, 1))).await.unwrap();
+        let mut rejected = false;
+        while let Some(Ok(msg)) = conn.next().await {
+            match msg {
+                Message::RejectRequest(_) => rejected = true,
+                // piece 1 is allowed fast, so it is served even though we never unchoked
+                Message::Request(request) if request.index == 1 => {
+                    let piece = PieceBlock { index: request.index, begin: request.begin, block: Bytes::from(vec![0; BLOCK_MAX]) };
+                    conn.send(Message::Piece(piece)).await.unwrap();
+                    conn.send(Message::Unchoke).await.unwrap();
+                }
+                Message::Request(request) => {
+                    conn.send(Message::RejectRequest(request)).await.unwrap();
+                }
+                _ => {}
+            }
+        }
+        rejected
+    });
+
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881);
+    let mut peer = Peer::handshake(addr, Box::new(ours), [0; 20], 2, None).await.unwrap();
+    let scheduler = Scheduler::new(test_pieces(2, BLOCK_MAX), 0..2, PickerKind::Sequential.build(None), 2);
+    let (finish, mut done) = tokio::sync::mpsc::channel(2);
+    peer.participate(&scheduler, finish, &CancellationToken::new()).await.unwrap();
+    drop(peer);
+    let (from, piece) = done.recv().await.unwrap();
+    assert_eq!((from, piece.index), (addr, 1));
+    assert!(done.recv().await.is_none());
+    // the rejected block went back to the scheduler
+    let block = scheduler.next(&Bitfield::full(2), &HashSet::new()).await.unwrap();
+    assert_eq!(block.piece, 0);
+    // and our peer turned down the seeder's request instead of ignoring it
+    assert!(seeder.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_keep_alive_and_idle_peer() {
+    use crate::{piece::PickerKind, scheduler::test_pieces};
+    let (ours, mut conn) = tokio::io::duplex(1 << 16);
+    let seeder = tokio::spawn(async move {
+        let mut handshake = [0u8; Handshake::LEN];
+        conn.read_exact(&mut handshake).await.unwrap();
+        conn.write_all(&handshake).await.unwrap();
+        let mut conn = Framed::new(conn, MessageFramer::default());
+        conn.send(Message::Bitfield(Bitfield::full(1))).await.unwrap();
+        // then never unchoke, nor say anything else
+        let interested = conn.next().await.unwrap().unwrap();
+        assert_eq!(interested, Message::Interested);
+        let mut keep_alive = [0u8; 4];
+        conn.get_mut().read_exact(&mut keep_alive).await.unwrap();
+        let sent_at = Instant::now();
+        // hold on to the connection, keep-alives and all, until the peer gives up on us
+        while conn.get_mut().read_u8().await.is_ok() {}
+        sent_at
+    });
+
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881);
+    let start = Instant::now();
+    let mut peer = Peer::handshake(addr, Box::new(ours), [0; 20], 1, None).await.unwrap();
+    peer.keep_alive = Duration::from_millis(200);
+    peer.idle_timeout = Duration::from_millis(500);
+    let scheduler = Scheduler::new(test_pieces(1, BLOCK_MAX), 0..1, PickerKind::Sequential.build(None), 1);
+    let (finish, _done) = tokio::sync::mpsc::channel(1);
+    let e = peer.participate(&scheduler, finish, &CancellationToken::new()).await.unwrap_err();
+    assert!(format!("{e:?}").contains("idle"), "{e:?}");
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    let stats = peer.stats();
+    // the handshake, interested and then nothing but keep-alives
+    let keep_alives = stats.uploaded - (Handshake::LEN + 5) as u64;
+    assert!(keep_alives >= 4 && keep_alives.is_multiple_of(4), "{stats:?}");
+    assert_eq!(stats.downloaded, (Handshake::LEN + 4 + 1 + 1) as u64);
+    drop(peer);
+    assert!(seeder.await.unwrap() - start >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn test_pipelines_requests() {
+    use crate::{piece::PickerKind, scheduler::test_pieces};
+    let npieces = 4;
+    let (ours, mut conn) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move {
+        let mut handshake = [0u8; Handshake::LEN];
+        conn.read_exact(&mut handshake).await.unwrap();
+        conn.write_all(&handshake).await.unwrap();
+        let mut conn = Framed::new(conn, MessageFramer::default());
+        conn.send(Message::Bitfield(Bitfield::full(npieces))).await.unwrap();
+        conn.send(Message::Unchoke).await.unwrap();
+        // answer nothing until every block has been asked for
+        let mut requests = Vec::new();
+        while requests.len() < npieces {
+            if let Message::Request(request) = conn.next().await.unwrap().unwrap() {
+                requests.push(request);
+            }
+        }
+        for request in requests {
+            let piece = PieceBlock { index: request.index, begin: request.begin, block: Bytes::from(vec![0; BLOCK_MAX]) };
+            conn.send(Message::Piece(piece)).await.unwrap();
+        }
+        while conn.next().await.is_some() {}
+    });
+
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881);
+    let mut peer = Peer::handshake(addr, Box::new(ours), [0; 20], npieces, None).await.unwrap();
+    let scheduler = Scheduler::new(test_pieces(npieces, BLOCK_MAX), 0..npieces, PickerKind::Sequential.build(None), npieces);
+    let (finish, mut done) = tokio::sync::mpsc::channel(npieces);
+    tokio::time::timeout(Duration::from_secs(5), peer.participate(&scheduler, finish, &CancellationToken::new()))
+        .await
+        .expect("requests were pipelined")
+        .unwrap();
+    drop(peer);
+    let mut pieces = Vec::new();
+    while let Some((_, piece)) = done.recv().await {
+        pieces.push(piece.index);
+    }
+    pieces.sort();
+    assert_eq!(pieces, [0, 1, 2, 3]);
+}
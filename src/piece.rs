@@ -1,36 +1,17 @@
-use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
 
-use crate::{peers::Peer, torrent::Torrent, tracker::TrackerResponse};
+use crate::{peers::Bitfield, random::Rng, torrent::Torrent};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PieceInfo  {
-    peers : HashSet<usize>,
     piece_i : usize,
     length : usize,
-    hash : [u8; 20], 
-    seed : u64
+    hash : [u8; 20],
 }
 
-impl Ord for PieceInfo {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.peers.len()
-            .cmp(&other.peers.len())
-            .then(self.seed.cmp(&other.seed))
-            .then(self.hash.cmp(&other.hash))
-            .then(self.length.cmp(&other.length))
-            .then(self.peers.iter().cmp(other.peers.iter()))
-            .then(self.piece_i.cmp(&other.piece_i))
-    }
-}
-
-impl PartialOrd for PieceInfo {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PieceInfo { 
-    pub(crate) fn new(piece_i : usize, t : &Torrent, peers : &[Peer]) -> Self {
+impl PieceInfo {
+    pub(crate) fn new(piece_i : usize, t : &Torrent) -> Self {
         let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
             let md = t.length() % t.info.plength;
             if md == 0 {
@@ -41,29 +22,263 @@ impl PieceInfo {
         } else {
             t.info.plength
         };
-        let hash = t.clone().info_hash();
-        let peers = peers.iter().enumerate().filter_map(|(peer_i, peer) | { 
-            peer.has_piece(piece_i).then_some(peer_i)
-        }).collect();
         Self {
-            peers : peers,
-            piece_i: piece_i,
+            piece_i,
             length: piece_size,
-            hash : hash,
-            seed: 5 as u64
-        }       
+            hash : t.info.pieces.0[piece_i],
+        }
     }
-    pub(crate) fn length(&self) -> usize { 
+    pub(crate) fn length(&self) -> usize {
         self.length
     }
-    pub(crate) fn peers(&self) -> &HashSet<usize> { 
-        &self.peers
-    }
 
-    pub(crate) fn index(&self ) -> usize { 
+    pub(crate) fn index(&self ) -> usize {
         self.piece_i
     }
-    pub(crate) fn hash(&self) -> [u8;20] { 
+    pub(crate) fn hash(&self) -> [u8;20] {
         self.hash
     }
-}
\ No newline at end of file
+}
+
+/// How many of the connected peers have each piece, kept up to date as peers come and go and
+/// announce new pieces.
+#[derive(Debug, Clone)]
+pub struct Availability {
+    counts: Vec<u32>,
+}
+
+impl Availability {
+    pub fn new(npieces: usize) -> Self {
+        Self { counts: vec![0; npieces] }
+    }
+
+    pub fn get(&self, piece_i: usize) -> u32 {
+        self.counts.get(piece_i).copied().unwrap_or(0)
+    }
+
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for piece_i in bitfield.pieces() {
+            self.have(piece_i);
+        }
+    }
+
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for piece_i in bitfield.pieces() {
+            if let Some(count) = self.counts.get_mut(piece_i) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn have(&mut self, piece_i: usize) {
+        if let Some(count) = self.counts.get_mut(piece_i) {
+            *count += 1;
+        }
+    }
+}
+
+/// How urgently a piece (or the file it belongs to) is wanted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Decides which piece a peer should start on next.
+///
+/// `candidates` are the pieces that are still needed, not yet started and that the peer has; it
+/// is never empty.
+pub trait PiecePicker: Send {
+    fn pick(&mut self, candidates: &[usize], availability: &Availability) -> Option<usize>;
+
+    /// Called once every block of a piece has arrived.
+    fn completed(&mut self, _piece_i: usize) {}
+}
+
+/// Picks uniformly among the candidates that minimize `key`.
+fn pick_min_by_key<K: Ord>(rng: &mut Rng, candidates: &[usize], key: impl Fn(usize) -> K) -> Option<usize> {
+    let mut best: Option<(K, usize)> = None;
+    let mut ties = 0;
+    for &piece_i in candidates {
+        let k = key(piece_i);
+        match &best {
+            Some((best_k, _)) if k > *best_k => continue,
+            Some((best_k, _)) if k == *best_k => {
+                // reservoir sampling over the tied pieces
+                ties += 1;
+                if rng.below(ties) == 0 {
+                    best = Some((k, piece_i));
+                }
+            }
+            _ => {
+                ties = 1;
+                best = Some((k, piece_i));
+            }
+        }
+    }
+    best.map(|(_, piece_i)| piece_i)
+}
+
+/// The piece the fewest peers have, so that rare pieces spread before their owners leave.
+#[derive(Debug, Default)]
+pub struct RarestFirst {
+    rng: Rng,
+}
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, candidates: &[usize], availability: &Availability) -> Option<usize> {
+        pick_min_by_key(&mut self.rng, candidates, |piece_i| availability.get(piece_i))
+    }
+}
+
+/// The lowest-numbered piece, for reading the data in order while it downloads.
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, candidates: &[usize], _availability: &Availability) -> Option<usize> {
+        candidates.iter().min().copied()
+    }
+}
+
+/// Random pieces until the first `threshold` pieces are complete, rarest-first after that.
+///
+/// A rare piece takes longest to get, so starting with random ones gets us something to share
+/// sooner.
+#[derive(Debug)]
+pub struct RandomFirst {
+    threshold: usize,
+    completed: usize,
+    rarest: RarestFirst,
+}
+
+impl RandomFirst {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold, completed: 0, rarest: RarestFirst::default() }
+    }
+}
+
+impl PiecePicker for RandomFirst {
+    fn pick(&mut self, candidates: &[usize], availability: &Availability) -> Option<usize> {
+        if self.completed < self.threshold {
+            let rng = &mut self.rarest.rng;
+            return candidates.get(rng.below(candidates.len())).copied();
+        }
+        self.rarest.pick(candidates, availability)
+    }
+
+    fn completed(&mut self, _piece_i: usize) {
+        self.completed += 1;
+    }
+}
+
+/// Restricts another picker to the most important pieces on offer, and never picks pieces that
+/// are [`Priority::Skip`].
+pub struct Prioritized {
+    priorities: Vec<Priority>,
+    inner: Box<dyn PiecePicker>,
+}
+
+impl Prioritized {
+    pub fn new(priorities: Vec<Priority>, inner: Box<dyn PiecePicker>) -> Self {
+        Self { priorities, inner }
+    }
+
+    fn priority(&self, piece_i: usize) -> Priority {
+        self.priorities.get(piece_i).copied().unwrap_or_default()
+    }
+}
+
+impl PiecePicker for Prioritized {
+    fn pick(&mut self, candidates: &[usize], availability: &Availability) -> Option<usize> {
+        let top = candidates.iter().map(|&piece_i| self.priority(piece_i)).max()?;
+        if top == Priority::Skip {
+            return None;
+        }
+        let candidates: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|&piece_i| self.priority(piece_i) == top)
+            .collect();
+        self.inner.pick(&candidates, availability)
+    }
+
+    fn completed(&mut self, piece_i: usize) {
+        self.inner.completed(piece_i);
+    }
+}
+
+/// The piece picking strategies that can be chosen at runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PickerKind {
+    #[default]
+    RarestFirst,
+    Sequential,
+    RandomFirst,
+}
+
+impl PickerKind {
+    /// Builds the picker; `priorities` (one per piece) restrict it when given.
+    pub fn build(self, priorities: Option<Vec<Priority>>) -> Box<dyn PiecePicker> {
+        let picker: Box<dyn PiecePicker> = match self {
+            PickerKind::RarestFirst => Box::new(RarestFirst::default()),
+            PickerKind::Sequential => Box::new(Sequential),
+            PickerKind::RandomFirst => Box::new(RandomFirst::new(4)),
+        };
+        match priorities {
+            Some(priorities) => Box::new(Prioritized::new(priorities, picker)),
+            None => picker,
+        }
+    }
+}
+
+impl FromStr for PickerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rarest-first" | "rarest" => Ok(PickerKind::RarestFirst),
+            "sequential" => Ok(PickerKind::Sequential),
+            "random-first" | "random" => Ok(PickerKind::RandomFirst),
+            _ => anyhow::bail!("unknown piece picker {s:?} (expected rarest-first, sequential or random-first)"),
+        }
+    }
+}
+
+impl fmt::Display for PickerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PickerKind::RarestFirst => "rarest-first",
+            PickerKind::Sequential => "sequential",
+            PickerKind::RandomFirst => "random-first",
+        })
+    }
+}
+
+#[test]
+fn test_rarest_first_and_priorities() {
+    let mut availability = Availability::new(4);
+    availability.add_peer(&Bitfield::new(vec![0b1111_0000]));
+    availability.add_peer(&Bitfield::new(vec![0b1101_0000]));
+    availability.add_peer(&Bitfield::new(vec![0b1000_0000]));
+    // piece 2 is the only one with a single owner
+    let mut rarest = RarestFirst::default();
+    assert_eq!(rarest.pick(&[0, 1, 2, 3], &availability), Some(2));
+    // whatever else is on offer, as long as it is there
+    let picks: std::collections::HashSet<_> =
+        (0..64).filter_map(|_| rarest.pick(&[2, 3], &availability)).collect();
+    assert_eq!(picks.len(), 1);
+    // pieces 1 and 3 both have two owners: the tie is broken both ways eventually
+    let picks: std::collections::HashSet<_> =
+        (0..64).filter_map(|_| rarest.pick(&[1, 3], &availability)).collect();
+    assert_eq!(picks.len(), 2);
+
+    let priorities = vec![Priority::High, Priority::Normal, Priority::Skip, Priority::Normal];
+    let mut picker = PickerKind::Sequential.build(Some(priorities));
+    assert_eq!(picker.pick(&[1, 0, 3], &availability), Some(0));
+    assert_eq!(picker.pick(&[3, 2, 1], &availability), Some(1));
+    assert_eq!(picker.pick(&[2], &availability), None);
+}
@@ -0,0 +1,120 @@
+//! Putting the [`Stats`] of a download into words: a status line for people, and JSON for
+//! scripts.
+
+use std::time::Duration;
+
+use crate::events::Stats;
+
+impl Stats {
+    /// How much of the wanted data is verified, from 0 to 100.
+    pub fn percent(&self) -> f64 {
+        if self.wanted == 0 {
+            return 100.0;
+        }
+        self.verified as f64 * 100.0 / self.wanted as f64
+    }
+
+    /// How long the rest takes at the current download rate, if it is moving at all.
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.wanted.saturating_sub(self.verified);
+        if left == 0 {
+            return Some(Duration::ZERO);
+        }
+        (self.download_rate > 0).then(|| Duration::from_secs(left as u64 / self.download_rate))
+    }
+
+    /// One line to show while downloading, e.g.
+    /// `42.0% 84/200 pieces, 1.2 MiB/s down, 12.0 KiB/s up, 5 peers, ETA 1m03s`.
+    pub fn line(&self) -> String {
+        let eta = match self.eta() {
+            Some(eta) => duration(eta),
+            None => "-".to_string(),
+        };
+        format!(
+            "{:.1}% {}/{} pieces, {}/s down, {}/s up, {} peers, ETA {eta}",
+            self.percent(),
+            self.pieces,
+            self.wanted_pieces,
+            bytes(self.download_rate),
+            bytes(self.upload_rate),
+            self.peers
+        )
+    }
+
+    /// The same as a JSON object, for a `progress` event of a JSON-lines log.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event": "progress",
+            "percent": (self.percent() * 10.0).round() / 10.0,
+            "pieces": self.pieces,
+            "total_pieces": self.wanted_pieces,
+            "verified_bytes": self.verified,
+            "total_bytes": self.wanted,
+            "downloaded_bytes": self.downloaded,
+            "uploaded_bytes": self.uploaded,
+            "download_rate": self.download_rate,
+            "upload_rate": self.upload_rate,
+            "peers": self.peers,
+            "eta_secs": self.eta().map(|eta| eta.as_secs()),
+        })
+    }
+}
+
+/// Bytes in binary units, to one decimal once past a KiB.
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{n} B");
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// `1h02m`, `3m07s` or `42s`.
+fn duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, _) => format!("{h}h{m:02}m"),
+    }
+}
+
+#[test]
+fn test_progress_line() {
+    let stats = Stats {
+        pieces: 84,
+        wanted_pieces: 200,
+        verified: 42 << 20,
+        wanted: 100 << 20,
+        downloaded: 43 << 20,
+        uploaded: 1 << 20,
+        download_rate: 1 << 20,
+        upload_rate: 12 << 10,
+        peers: 5,
+    };
+    assert_eq!(stats.eta(), Some(Duration::from_secs(58)));
+    assert_eq!(stats.line(), "42.0% 84/200 pieces, 1.0 MiB/s down, 12.0 KiB/s up, 5 peers, ETA 58s");
+    let json = stats.json();
+    assert_eq!(json["event"], "progress");
+    assert_eq!(json["percent"], 42.0);
+    assert_eq!(json["eta_secs"], 58);
+
+    // stalled, and done
+    let stalled = Stats { download_rate: 0, ..stats };
+    assert_eq!(stalled.eta(), None);
+    assert!(stalled.line().ends_with("ETA -"));
+    assert!(stalled.json()["eta_secs"].is_null());
+    let done = Stats { verified: stats.wanted, ..stalled };
+    assert_eq!((done.percent(), done.eta()), (100.0, Some(Duration::ZERO)));
+
+    assert_eq!(bytes(1000), "1000 B");
+    assert_eq!(bytes(3 << 30), "3.0 GiB");
+    assert_eq!(duration(Duration::from_secs(3723)), "1h02m");
+    assert_eq!(duration(Duration::from_secs(187)), "3m07s");
+}
@@ -0,0 +1,63 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+
+/// A small, fast, non-cryptographic random number generator (splitmix64).
+///
+/// Good enough for tie-breaking and shuffling; it is seeded from the per-process random keys that
+/// the standard library already pulls from the operating system. Its output gives its state away,
+/// so anything that has to stay secret comes from [`secure_fill`] instead.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        Self::with_seed(hasher.finish())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`. `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fills `buf` from the operating system's cryptographically secure generator, for keys and
+/// secrets.
+pub fn secure_fill(buf: &mut [u8]) -> io::Result<()> {
+    secure_source()?.read_exact(buf)
+}
+
+/// The operating system's secure generator, for whoever draws from it again and again: reading
+/// from a handle kept open cannot fail for want of file descriptors, as opening one can.
+pub fn secure_source() -> io::Result<std::fs::File> {
+    std::fs::File::open("/dev/urandom")
+}
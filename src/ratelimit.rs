@@ -0,0 +1,211 @@
+//! Bandwidth limits: token buckets that peer connections draw from as data goes in and out.
+//!
+//! Buckets may go into debt. A connection only waits before it reads or writes while a bucket it
+//! draws from is in the red, and pays for what it transferred afterwards, so a limit holds on
+//! average without every read having to be sized to the tokens at hand.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Connections held back by a limit look at it again at least this often, so that a change of
+/// rate takes effect promptly.
+const RECHECK: Duration = Duration::from_millis(250);
+
+/// A throughput limit in bytes per second, which can be changed while downloads use it.
+pub struct RateLimit {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // bytes per second, if limited at all
+    rate: Option<u64>,
+    // may be negative while transfers that already happened are paid off
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            // at most a second's worth saved up, so an idle connection cannot burst forever
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.refilled = now;
+    }
+}
+
+impl RateLimit {
+    /// A limit of `rate` bytes per second, or none at all.
+    pub fn new(rate: Option<u64>) -> Self {
+        let tokens = rate.unwrap_or(0) as f64;
+        Self { bucket: Mutex::new(Bucket { rate, tokens, refilled: Instant::now() }) }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().expect("bucket lock is never poisoned").rate
+    }
+
+    /// Changes the limit; connections that are waiting on it pick up the new rate within
+    /// [`RECHECK`].
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().expect("bucket lock is never poisoned");
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        if let Some(rate) = rate {
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        } else {
+            bucket.tokens = 0.0;
+        }
+    }
+
+    /// Pays for `bytes` that went through.
+    pub(crate) fn consume(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().expect("bucket lock is never poisoned");
+        if bucket.rate.is_some() {
+            bucket.refill(Instant::now());
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    /// When to look at the bucket again, or `None` if it is out of debt now.
+    pub(crate) fn ready_at(&self) -> Option<Instant> {
+        let mut bucket = self.bucket.lock().expect("bucket lock is never poisoned");
+        let now = Instant::now();
+        bucket.refill(now);
+        match bucket.rate {
+            // a rate of zero stops transfers altogether, until it is raised again
+            Some(0) => Some(now + RECHECK),
+            Some(rate) if bucket.tokens < 0.0 => {
+                let wait = Duration::from_secs_f64(-bucket.tokens / rate as f64);
+                Some(now + wait.clamp(Duration::from_millis(1), RECHECK))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rate() {
+            Some(rate) => write!(f, "RateLimit({rate} B/s)"),
+            None => write!(f, "RateLimit(unlimited)"),
+        }
+    }
+}
+
+/// A pair of download and upload limits. Clones share the limits, so one kept aside can adjust
+/// the rates of the downloads it was handed to.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    pub download: Arc<RateLimit>,
+    pub upload: Arc<RateLimit>,
+}
+
+impl Bandwidth {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self { download: Arc::new(RateLimit::new(download)), upload: Arc::new(RateLimit::new(upload)) }
+    }
+}
+
+/// The bandwidth a download may use.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimits {
+    /// Just for this torrent.
+    pub per_torrent: Bandwidth,
+    /// Shared between downloads, to cap all of them together.
+    pub global: Option<Bandwidth>,
+    /// Count handshakes, message headers and the like, not just the pieces' data.
+    pub include_overhead: bool,
+}
+
+impl BandwidthLimits {
+    /// What the connections of the download draw from.
+    pub(crate) fn throttle(&self) -> Throttle {
+        let mut throttle = Throttle { download: vec![self.per_torrent.download.clone()], upload: vec![self.per_torrent.upload.clone()], include_overhead: self.include_overhead };
+        if let Some(global) = &self.global {
+            throttle.download.push(global.download.clone());
+            throttle.upload.push(global.upload.clone());
+        }
+        throttle
+    }
+}
+
+/// The limits one connection draws from.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Throttle {
+    download: Vec<Arc<RateLimit>>,
+    upload: Vec<Arc<RateLimit>>,
+    include_overhead: bool,
+}
+
+impl Throttle {
+    /// When the connection may read again, or `None` if it may now.
+    pub(crate) fn read_at(&self) -> Option<Instant> {
+        self.download.iter().filter_map(|limit| limit.ready_at()).max()
+    }
+
+    pub(crate) fn write_at(&self) -> Option<Instant> {
+        self.upload.iter().filter_map(|limit| limit.ready_at()).max()
+    }
+
+    /// Raw bytes read from the connection.
+    pub(crate) fn received(&self, bytes: usize) {
+        if self.include_overhead {
+            self.download.iter().for_each(|limit| limit.consume(bytes));
+        }
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        if self.include_overhead {
+            self.upload.iter().for_each(|limit| limit.consume(bytes));
+        }
+    }
+
+    /// Bytes of piece data received, which count whether or not the overhead does.
+    pub(crate) fn received_payload(&self, bytes: usize) {
+        if !self.include_overhead {
+            self.download.iter().for_each(|limit| limit.consume(bytes));
+        }
+    }
+
+    pub(crate) fn sent_payload(&self, bytes: usize) {
+        if !self.include_overhead {
+            self.upload.iter().for_each(|limit| limit.consume(bytes));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    let limit = RateLimit::new(Some(100_000));
+    assert_eq!(limit.ready_at(), None);
+    // spending a second and a half's worth up front leaves half a second to wait out, which is
+    // checked on every so often
+    limit.consume(150_000);
+    let wait = limit.ready_at().unwrap() - Instant::now();
+    assert!(wait > RECHECK - Duration::from_millis(50) && wait <= RECHECK, "{wait:?}");
+    // a higher rate pays the debt off sooner
+    limit.set_rate(Some(1_000_000));
+    let wait = limit.ready_at().unwrap() - Instant::now();
+    assert!(wait <= Duration::from_millis(50), "{wait:?}");
+    limit.set_rate(Some(0));
+    assert!(limit.ready_at().is_some());
+    limit.set_rate(None);
+    assert_eq!(limit.ready_at(), None);
+    limit.consume(usize::MAX);
+    assert_eq!(limit.ready_at(), None);
+}
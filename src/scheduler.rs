@@ -0,0 +1,314 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use tokio::sync::{broadcast, Notify};
+
+use crate::{peers::{Bitfield, Request}, piece::{Availability, PieceInfo, PiecePicker}, BLOCK_MAX};
+
+/// A single block request: `length` bytes at block `index` of `piece`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Block {
+    pub(crate) piece: usize,
+    pub(crate) index: usize,
+    pub(crate) length: usize,
+}
+
+impl Block {
+    pub(crate) fn begin(&self) -> usize {
+        self.index * BLOCK_MAX
+    }
+
+    pub(crate) fn request(&self) -> Request {
+        Request::new(self.piece as u32, self.begin() as u32, self.length as u32)
+    }
+}
+
+/// Hands out blocks to the peers of a download so that they can all work at the same time.
+///
+/// Peers first help out with pieces that are already under way, and only start a new piece
+/// (one they have, chosen by the [`PiecePicker`]) while fewer than `max_partial` pieces are
+/// partially downloaded. That keeps every peer busy on a piece of its own without an unbounded
+/// number of half-finished pieces piling up in memory.
+///
+/// Once every block of the download has been requested, the blocks that are still outstanding
+/// are handed out again to the other peers that have them (end-game mode), so that the last few
+/// blocks are not held hostage by the slowest peer. The first copy that arrives wins, and every
+/// other peer that asked for the same block is told to send a `Cancel` through
+/// [`Scheduler::subscribe`].
+pub(crate) struct Scheduler {
+    state: Mutex<State>,
+    notify: Notify,
+    cancel: broadcast::Sender<Block>,
+}
+
+struct State {
+    pieces: Vec<PieceInfo>,
+    // pieces that have not been started yet
+    need: BTreeSet<usize>,
+    partial: HashMap<usize, Partial>,
+    max_partial: usize,
+    availability: Availability,
+    picker: Box<dyn PiecePicker>,
+}
+
+struct Partial {
+    length: usize,
+    nblocks: usize,
+    pending: VecDeque<usize>,
+    // block -> number of peers that currently have a request out for it
+    outstanding: HashMap<usize, usize>,
+    done: HashSet<usize>,
+}
+
+impl Partial {
+    fn new(length: usize) -> Self {
+        let nblocks = length.div_ceil(BLOCK_MAX);
+        Self {
+            length,
+            nblocks,
+            pending: (0..nblocks).collect(),
+            outstanding: HashMap::new(),
+            done: HashSet::new(),
+        }
+    }
+
+    fn block(&self, piece: usize, index: usize) -> Block {
+        let length = if index == self.nblocks - 1 {
+            let md = self.length % BLOCK_MAX;
+            if md == 0 {
+                BLOCK_MAX
+            } else {
+                md
+            }
+        } else {
+            BLOCK_MAX
+        };
+        Block { piece, index, length }
+    }
+}
+
+impl State {
+    fn take(&mut self, piece: usize, index: usize) -> Block {
+        let partial = self.partial.get_mut(&piece).expect("only partial pieces hand out blocks");
+        *partial.outstanding.entry(index).or_default() += 1;
+        partial.block(piece, index)
+    }
+
+    fn all_requested(&self) -> bool {
+        self.need.is_empty() && self.partial.values().all(|partial| partial.pending.is_empty())
+    }
+}
+
+impl Scheduler {
+    /// Schedules the `need` pieces out of `pieces`, the torrent's pieces in order.
+    pub(crate) fn new(
+        pieces: Vec<PieceInfo>,
+        need: impl IntoIterator<Item = usize>,
+        picker: Box<dyn PiecePicker>,
+        max_partial: usize,
+    ) -> Self {
+        let (cancel, _) = broadcast::channel(256);
+        Self {
+            state: Mutex::new(State {
+                availability: Availability::new(pieces.len()),
+                pieces,
+                need: need.into_iter().collect(),
+                partial: HashMap::new(),
+                max_partial: max_partial.max(1),
+                picker,
+            }),
+            notify: Notify::new(),
+            cancel,
+        }
+    }
+
+    /// Counts the pieces of a newly connected peer towards their availability.
+    pub(crate) fn add_peer(&self, bitfield: &Bitfield) {
+        let mut state = self.state.lock().expect("scheduler lock is never poisoned");
+        state.availability.add_peer(bitfield);
+    }
+
+    pub(crate) fn remove_peer(&self, bitfield: &Bitfield) {
+        let mut state = self.state.lock().expect("scheduler lock is never poisoned");
+        state.availability.remove_peer(bitfield);
+    }
+
+    /// A peer announced that it now has `piece_i`.
+    pub(crate) fn have(&self, piece_i: usize) {
+        let mut state = self.state.lock().expect("scheduler lock is never poisoned");
+        state.availability.have(piece_i);
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// Receives every block as soon as its first copy has arrived.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Block> {
+        self.cancel.subscribe()
+    }
+
+    /// Waits for the next block a peer with `bitfield` should request.
+    ///
+    /// `requested` are the blocks the peer has already asked for, so that it is never handed a
+    /// duplicate of its own request. Returns `None` once nothing the peer has is still missing.
+    pub(crate) async fn next(&self, bitfield: &Bitfield, requested: &HashSet<Block>) -> Option<Block> {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("scheduler lock is never poisoned");
+                let useful = state.need.iter().any(|&piece| bitfield.has_piece(piece))
+                    || state.partial.keys().any(|&piece| bitfield.has_piece(piece));
+                if !useful {
+                    return None;
+                }
+
+                // help out with the pieces that are already under way
+                let started = state
+                    .partial
+                    .iter_mut()
+                    .filter(|(&piece, _)| bitfield.has_piece(piece))
+                    .find_map(|(&piece, partial)| Some((piece, partial.pending.pop_front()?)));
+                if let Some((piece, index)) = started {
+                    return Some(state.take(piece, index));
+                }
+
+                // or start on a piece of our own
+                if state.partial.len() < state.max_partial {
+                    let candidates: Vec<usize> = state
+                        .need
+                        .iter()
+                        .copied()
+                        .filter(|&piece| bitfield.has_piece(piece))
+                        .collect();
+                    let state = &mut *state;
+                    let best = if candidates.is_empty() {
+                        None
+                    } else {
+                        state.picker.pick(&candidates, &state.availability)
+                    };
+                    if let Some(piece) = best {
+                        state.need.remove(&piece);
+                        let mut partial = Partial::new(state.pieces[piece].length());
+                        let index = partial.pending.pop_front().expect("pieces have at least one block");
+                        state.partial.insert(piece, partial);
+                        return Some(state.take(piece, index));
+                    }
+                }
+
+                if state.all_requested() {
+                    // end-game: duplicate the outstanding block with the fewest requests out for it
+                    let duplicate = state
+                        .partial
+                        .iter()
+                        .filter(|(&piece, _)| bitfield.has_piece(piece))
+                        .flat_map(|(&piece, partial)| {
+                            partial.outstanding.iter().map(move |(&index, &count)| (count, piece, index))
+                        })
+                        .filter(|&(_, piece, index)| {
+                            !requested.iter().any(|block| block.piece == piece && block.index == index)
+                        })
+                        .min();
+                    if let Some((_, piece, index)) = duplicate {
+                        return Some(state.take(piece, index));
+                    }
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Gives a block back because the request for it will not be answered (choke, error).
+    pub(crate) fn requeue(&self, block: Block) {
+        let mut state = self.state.lock().expect("scheduler lock is never poisoned");
+        if let Some(partial) = state.partial.get_mut(&block.piece) {
+            if let Some(count) = partial.outstanding.get_mut(&block.index) {
+                *count -= 1;
+                if *count == 0 {
+                    partial.outstanding.remove(&block.index);
+                    partial.pending.push_back(block.index);
+                }
+            }
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// Schedules all of `piece` again, after the copy that arrived failed its hash check. Peers
+    /// that have the piece pick it up like any other; until one turns up, it simply waits.
+    pub(crate) fn retry(&self, piece: usize) {
+        let mut state = self.state.lock().expect("scheduler lock is never poisoned");
+        if !state.partial.contains_key(&piece) {
+            state.need.insert(piece);
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// Marks a block as received. Returns `true` for the first copy of the block, which is the
+    /// only one the caller should keep; any other peer still waiting on it is told to cancel.
+    pub(crate) fn complete(&self, block: Block) -> bool {
+        let mut state = self.state.lock().expect("scheduler lock is never poisoned");
+        let Some(partial) = state.partial.get_mut(&block.piece) else {
+            return false;
+        };
+        if !partial.done.insert(block.index) {
+            return false;
+        }
+        let duplicated = partial.outstanding.remove(&block.index).is_some_and(|count| count > 1);
+        partial.pending.retain(|&index| index != block.index);
+        if partial.done.len() == partial.nblocks {
+            // frees up a slot for another piece to be started
+            state.partial.remove(&block.piece);
+            state.picker.completed(block.piece);
+        }
+        drop(state);
+        if duplicated {
+            // nobody listening just means there is nobody left to cancel
+            let _ = self.cancel.send(block);
+        }
+        self.notify.notify_waiters();
+        true
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_pieces(npieces: usize, plength: usize) -> Vec<PieceInfo> {
+    use crate::{hash::Hashes, torrent::{Info, Keys, Torrent}};
+    let t = Torrent {
+        announce: String::new(),
+        info: Info {
+            name: "test".to_string(),
+            plength,
+            pieces: Hashes(vec![[0; 20]; npieces]),
+            keys: Keys::SingleFile { length: npieces * plength },
+        },
+        nodes: Vec::new(),
+    };
+    (0..npieces).map(|piece_i| PieceInfo::new(piece_i, &t)).collect()
+}
+
+#[tokio::test]
+async fn test_scheduler_spreads_pieces_and_endgame() {
+    let all = Bitfield::new(vec![0xff]);
+    let picker = crate::piece::PickerKind::Sequential.build(None);
+    let scheduler = Scheduler::new(test_pieces(2, BLOCK_MAX), 0..2, picker, 2);
+    let mut cancelled = scheduler.subscribe();
+    let a = scheduler.next(&all, &HashSet::new()).await.unwrap();
+    let b = scheduler.next(&all, &HashSet::new()).await.unwrap();
+    // two peers end up working on two different pieces at once
+    assert_ne!(a.piece, b.piece);
+    // every block is out, so a peer that asked for `a` is handed `b` again
+    let dup = scheduler.next(&all, &HashSet::from([a])).await.unwrap();
+    assert_eq!(dup, b);
+    assert!(scheduler.complete(b));
+    assert!(!scheduler.complete(b));
+    assert_eq!(cancelled.recv().await.unwrap(), b);
+    assert!(scheduler.complete(a));
+    assert_eq!(scheduler.next(&all, &HashSet::new()).await, None);
+    // a piece that turns out corrupt is fetched all over again
+    scheduler.retry(a.piece);
+    let again = scheduler.next(&all, &HashSet::new()).await.unwrap();
+    assert_eq!(again, a);
+    assert!(scheduler.complete(again));
+}
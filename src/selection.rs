@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::{piece::Priority, torrent::FileInfo};
+
+/// Picks out files of a torrent, either by their index or by a glob over their `/`-separated path.
+///
+/// In globs `*` and `?` stay within one path component, `**` crosses them.
+#[derive(Debug, Clone)]
+pub enum FileMatcher {
+    Index(usize),
+    Glob(Regex),
+}
+
+impl FileMatcher {
+    pub fn matches(&self, index: usize, file: &FileInfo) -> bool {
+        match self {
+            FileMatcher::Index(i) => *i == index,
+            FileMatcher::Glob(glob) => glob.is_match(&file.path.join("/")),
+        }
+    }
+}
+
+impl FromStr for FileMatcher {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(index) = s.parse() {
+            return Ok(FileMatcher::Index(index));
+        }
+        let mut pattern = String::from("^");
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    pattern.push_str(".*");
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        Ok(FileMatcher::Glob(Regex::new(&pattern)?))
+    }
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => anyhow::bail!("unknown priority {s:?} (expected skip, low, normal or high)"),
+        }
+    }
+}
+
+/// A `<files>=<priority>` rule, e.g. `*.mkv=high` or `3=skip`.
+#[derive(Debug, Clone)]
+pub struct FilePriority {
+    pub files: FileMatcher,
+    pub priority: Priority,
+}
+
+impl FromStr for FilePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((files, priority)) = s.rsplit_once('=') else {
+            anyhow::bail!("expected <files>=<priority>, got {s:?}");
+        };
+        Ok(FilePriority { files: files.parse()?, priority: priority.parse()? })
+    }
+}
+
+/// Which files of a torrent to download, and how urgently.
+///
+/// By default every file is downloaded at [`Priority::Normal`]. Once [`FileSelection::only`] has
+/// been used, files that no `only` matcher picks out are skipped. Priority rules apply on top of
+/// that in order, so later rules win.
+#[derive(Debug, Clone, Default)]
+pub struct FileSelection {
+    only: Vec<FileMatcher>,
+    rules: Vec<FilePriority>,
+}
+
+impl FileSelection {
+    pub fn only(mut self, files: FileMatcher) -> Self {
+        self.only.push(files);
+        self
+    }
+
+    pub fn priority(mut self, rule: FilePriority) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Whether every file is wanted at the default priority.
+    pub fn is_everything(&self) -> bool {
+        self.only.is_empty() && self.rules.is_empty()
+    }
+
+    pub fn file_priorities(&self, files: &[FileInfo]) -> Vec<Priority> {
+        files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let wanted = self.only.is_empty() || self.only.iter().any(|m| m.matches(index, file));
+                let priority = if wanted { Priority::Normal } else { Priority::Skip };
+                self.rules
+                    .iter()
+                    .rev()
+                    .find(|rule| rule.files.matches(index, file))
+                    .map_or(priority, |rule| rule.priority)
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_file_selection() {
+    let file = |path: &str| FileInfo { length: 1, path: path.split('/').map(String::from).collect() };
+    let files = [file("a/x.log"), file("a/b/y.log"), file("z.mkv"), file("readme")];
+    let selection = FileSelection::default()
+        .only("a/*.log".parse().unwrap())
+        .only("2".parse().unwrap())
+        .priority("**.log=low".parse().unwrap())
+        .priority("*.mkv=high".parse().unwrap());
+    assert_eq!(
+        selection.file_priorities(&files),
+        vec![Priority::Low, Priority::Low, Priority::High, Priority::Skip]
+    );
+}
@@ -0,0 +1,784 @@
+//! Running many torrents at once. A session owns what they share: the port peers connect to, the
+//! DHT and local discovery, and the limits on connections and bandwidth across all of them. Each
+//! torrent goes through its own states, from checking what is on disk to seeding, and can be
+//! paused, resumed or removed while the others carry on.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::Stream;
+use sha1::{Digest, Sha1};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connections::GlobalLimit,
+    dht::{Dht, DhtConfig},
+    download::{self, DownloadOptions},
+    events::Event,
+    lsd::{Lsd, LsdConfig},
+    magnet::Magnet,
+    metadata,
+    mse,
+    peers::{Bitfield, Handshake, Peer},
+    piece::{PieceInfo, Priority},
+    ratelimit::Bandwidth,
+    storage::{self, FileStorage, Layout, Storage},
+    torrent::Torrent,
+    tracker::TrackerResponse,
+};
+
+/// How long a peer that connected to us gets to say which torrent it wants, and send its bitfield.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Peers that connected to us and wait for their torrent to take them on.
+const INBOUND_QUEUE: usize = 8;
+/// Pause after the listener fails to accept, which happens when we run out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What a [`Session`] shares between its torrents.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Torrents are downloaded below this directory, each under its name.
+    pub download_dir: PathBuf,
+    /// The TCP port peers connect to, for every torrent; 0 picks a free one.
+    pub listen_port: u16,
+    /// Join the DHT with this configuration, and look for the peers of every torrent in it.
+    pub dht: Option<DhtConfig>,
+    /// Announce every torrent on the local network, and use the peers found there.
+    pub lsd: bool,
+    /// Connections open at once, across all torrents.
+    pub max_connections: Option<usize>,
+    /// Download and upload rates, across all torrents.
+    pub bandwidth: Option<Bandwidth>,
+    /// What each torrent is downloaded with. The session fills in the parts it shares.
+    pub download: DownloadOptions,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            download_dir: PathBuf::from("."),
+            listen_port: 6881,
+            dht: None,
+            lsd: false,
+            max_connections: None,
+            bandwidth: None,
+            download: DownloadOptions::default(),
+        }
+    }
+}
+
+/// Where a torrent is at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Hashing what is on disk already, to find the pieces that are still missing.
+    Checking,
+    /// Fetching the metadata of a magnet link, or the missing pieces.
+    Downloading,
+    /// Every wanted piece is on disk, and served to the peers that connect for it.
+    Seeding,
+    /// Stopped until it is resumed.
+    Paused,
+    /// Stopped by the error, until it is resumed.
+    Error(String),
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentState::Checking => write!(f, "checking"),
+            TorrentState::Downloading => write!(f, "downloading"),
+            TorrentState::Seeding => write!(f, "seeding"),
+            TorrentState::Paused => write!(f, "paused"),
+            TorrentState::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+/// A snapshot of one torrent of a [`Session`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub state: TorrentState,
+    /// Bytes of the wanted pieces that are on disk and checked.
+    pub verified: usize,
+    /// Bytes of the wanted pieces; 0 while the metadata of a magnet link is on its way.
+    pub wanted: usize,
+}
+
+/// Many torrents sharing a listen port, the DHT, local discovery and connection and bandwidth
+/// limits. Torrents run in the background until they are removed or the session is dropped.
+pub struct Session {
+    inner: Arc<Inner>,
+    listen_port: u16,
+    listener: JoinHandle<()>,
+}
+
+struct Inner {
+    download_dir: PathBuf,
+    // with the shared parts filled in
+    options: DownloadOptions,
+    torrents: Mutex<HashMap<[u8; 20], Entry>>,
+    // whenever any torrent changes state
+    changed: Notify,
+}
+
+struct Entry {
+    magnet: Option<Magnet>,
+    // once the metadata is known
+    torrent: Option<Torrent>,
+    state: TorrentState,
+    verified: usize,
+    wanted: usize,
+    // while downloading, where the peers that connected to us for it go
+    inbound: Option<mpsc::Sender<Peer>>,
+    // while seeding, what the peers that connect to us for it are served from
+    seeding: Option<Seeding>,
+    runner: Option<JoinHandle<()>>,
+    // counts the runners started, so that one that was stopped cannot touch the entry anymore
+    run: u64,
+}
+
+impl Entry {
+    fn new(torrent: Option<Torrent>, magnet: Option<Magnet>) -> Self {
+        Self { magnet, torrent, state: TorrentState::Checking, verified: 0, wanted: 0, inbound: None, seeding: None, runner: None, run: 0 }
+    }
+
+    fn status(&self, info_hash: [u8; 20]) -> TorrentStatus {
+        let name = match (&self.torrent, &self.magnet) {
+            (Some(torrent), _) => torrent.info.name.clone(),
+            (None, Some(Magnet { name: Some(name), .. })) => name.clone(),
+            (None, _) => hex::encode(info_hash),
+        };
+        TorrentStatus { info_hash, name, state: self.state.clone(), verified: self.verified, wanted: self.wanted }
+    }
+
+    fn stop(&mut self) {
+        if let Some(runner) = self.runner.take() {
+            runner.abort();
+        }
+        self.inbound = None;
+        if let Some(seeding) = self.seeding.take() {
+            seeding.stop.cancel();
+        }
+        self.run += 1;
+    }
+}
+
+/// What a seeding torrent uploads from.
+#[derive(Clone)]
+struct Seeding {
+    storage: Arc<FileStorage>,
+    // the wanted pieces, all of them on disk
+    have: Bitfield,
+    plength: usize,
+    length: usize,
+    // the connections it may still take on
+    slots: Arc<Semaphore>,
+    // cancelled when the torrent stops, which ends its uploads
+    stop: CancellationToken,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("listen_port", &self.listen_port)
+            .field("torrents", &self.inner.torrents.lock().expect("torrents lock is never poisoned").len())
+            .finish()
+    }
+}
+
+impl Session {
+    /// Starts listening for peers, and joins the DHT and local discovery if `config` asks for
+    /// them.
+    pub async fn new(config: SessionConfig) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            config.download.proxy.is_none() || (config.dht.is_none() && !config.lsd),
+            "the DHT and local peer discovery cannot go through a proxy, so they cannot be used with one"
+        );
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], config.listen_port)))
+            .await
+            .with_context(|| format!("listen on port {}", config.listen_port))?;
+        let listen_port = listener.local_addr().context("listener address")?.port();
+        let mut options = config.download;
+        options.listen_port = Some(listen_port);
+        if let Some(dht) = config.dht {
+            let dht = Dht::bind(DhtConfig { ip_filter: options.ip_filter.clone(), events: options.events.clone(), ..dht }).await?;
+            if let Err(e) = dht.bootstrap().await {
+                let message = format!("failed to join the dht, carrying on without it for now: {e:#}");
+                options.events.send(Event::Log { info_hash: None, message });
+            }
+            options.dht = Some(Arc::new(dht));
+        }
+        if config.lsd {
+            options.lsd = Some(Arc::new(Lsd::bind(LsdConfig { port: listen_port, events: options.events.clone(), ..Default::default() }).await?));
+        }
+        if let Some(connections) = config.max_connections {
+            options.limits.global = Some(GlobalLimit::new(connections));
+        }
+        if let Some(bandwidth) = config.bandwidth {
+            options.bandwidth.global = Some(bandwidth);
+        }
+        let inner = Arc::new(Inner { download_dir: config.download_dir, options, torrents: Mutex::new(HashMap::new()), changed: Notify::new() });
+        let listener = tokio::spawn(inner.clone().listen(listener));
+        Ok(Self { inner, listen_port, listener })
+    }
+
+    /// What the torrents of the session report, from now on.
+    pub fn events(&self) -> impl Stream<Item = Event> + Send + 'static {
+        self.inner.options.events.subscribe()
+    }
+
+    /// The port peers connect to.
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    /// The DHT node the torrents share, if the session joined the DHT.
+    pub fn dht(&self) -> Option<&Arc<Dht>> {
+        self.inner.options.dht.as_ref()
+    }
+
+    /// Adds a torrent and starts it, by checking what of it is on disk already.
+    pub fn add_torrent(&self, torrent: Torrent) -> anyhow::Result<[u8; 20]> {
+        let info_hash = torrent.clone().info_hash();
+        self.inner.add(info_hash, Entry::new(Some(torrent), None))?;
+        Ok(info_hash)
+    }
+
+    pub async fn add_torrent_file(&self, path: impl AsRef<Path>) -> anyhow::Result<[u8; 20]> {
+        self.add_torrent(Torrent::read(path).await?)
+    }
+
+    /// Adds the torrent of a magnet link, whose metadata is fetched from its peers first.
+    pub fn add_magnet(&self, link: &str) -> anyhow::Result<[u8; 20]> {
+        let magnet: Magnet = link.parse()?;
+        let info_hash = magnet.info_hash;
+        self.inner.add(info_hash, Entry::new(None, Some(magnet)))?;
+        Ok(info_hash)
+    }
+
+    /// Stops a torrent, and deletes what it downloaded if `delete_files` says so.
+    pub fn remove(&self, info_hash: &[u8; 20], delete_files: bool) -> anyhow::Result<()> {
+        let mut entry = {
+            let mut torrents = self.inner.torrents.lock().expect("torrents lock is never poisoned");
+            torrents.remove(info_hash).context("no such torrent")?
+        };
+        entry.stop();
+        self.inner.changed.notify_waiters();
+        let Some(torrent) = entry.torrent.filter(|_| delete_files) else {
+            return Ok(());
+        };
+        // only the files of the torrent go, never whatever else shares their directories
+        let download_dir = &self.inner.download_dir;
+        let paths = Layout::new(&torrent).paths(&self.inner.root(&torrent)?)?;
+        let mut dirs = HashSet::new();
+        for path in &paths {
+            anyhow::ensure!(path.starts_with(download_dir), "{} is not in the download directory", path.display());
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e).with_context(|| format!("delete {}", path.display())),
+                _ => {}
+            }
+            dirs.extend(path.ancestors().skip(1).take_while(|dir| *dir != download_dir));
+        }
+        // the directories they were in, deepest first, unless something else is in them still
+        let mut dirs: Vec<&Path> = dirs.into_iter().collect();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in dirs {
+            let _ = std::fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+
+    /// Stops a torrent, keeping what it downloaded, until it is resumed.
+    pub fn pause(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let mut torrents = self.inner.torrents.lock().expect("torrents lock is never poisoned");
+        let entry = torrents.get_mut(info_hash).context("no such torrent")?;
+        if entry.state != TorrentState::Paused {
+            entry.stop();
+            self.inner.set_state(*info_hash, entry, TorrentState::Paused);
+        }
+        Ok(())
+    }
+
+    /// Starts a paused torrent, or one that stopped with an error, over from checking.
+    pub fn resume(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let mut torrents = self.inner.torrents.lock().expect("torrents lock is never poisoned");
+        let entry = torrents.get_mut(info_hash).context("no such torrent")?;
+        if matches!(entry.state, TorrentState::Paused | TorrentState::Error(_)) {
+            self.inner.start(*info_hash, entry);
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, info_hash: &[u8; 20]) -> Option<TorrentStatus> {
+        let torrents = self.inner.torrents.lock().expect("torrents lock is never poisoned");
+        torrents.get(info_hash).map(|entry| entry.status(*info_hash))
+    }
+
+    /// Every torrent of the session, in no particular order.
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        let torrents = self.inner.torrents.lock().expect("torrents lock is never poisoned");
+        torrents.iter().map(|(info_hash, entry)| entry.status(*info_hash)).collect()
+    }
+
+    /// Waits until a torrent has every wanted piece, and fails if it stops with an error or is
+    /// removed first.
+    pub async fn finished(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        loop {
+            let changed = self.inner.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            match self.status(info_hash).map(|status| status.state) {
+                None => anyhow::bail!("torrent was removed"),
+                Some(TorrentState::Seeding) => return Ok(()),
+                Some(TorrentState::Error(e)) => anyhow::bail!(e),
+                Some(_) => {}
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.listener.abort();
+        let mut torrents = self.inner.torrents.lock().expect("torrents lock is never poisoned");
+        for entry in torrents.values_mut() {
+            entry.stop();
+        }
+    }
+}
+
+impl Inner {
+    fn add(self: &Arc<Self>, info_hash: [u8; 20], mut entry: Entry) -> anyhow::Result<()> {
+        let mut torrents = self.torrents.lock().expect("torrents lock is never poisoned");
+        anyhow::ensure!(!torrents.contains_key(&info_hash), "torrent {} is in the session already", hex::encode(info_hash));
+        self.start(info_hash, &mut entry);
+        torrents.insert(info_hash, entry);
+        Ok(())
+    }
+
+    fn start(self: &Arc<Self>, info_hash: [u8; 20], entry: &mut Entry) {
+        entry.stop();
+        entry.runner = Some(tokio::spawn(self.clone().run(info_hash, entry.run)));
+        let state = if entry.torrent.is_some() { TorrentState::Checking } else { TorrentState::Downloading };
+        // told even when it is the state the entry was made with, since it is where every start begins
+        entry.state = state.clone();
+        self.options.events.send(Event::StateChanged { info_hash, state });
+        self.changed.notify_waiters();
+    }
+
+    /// Moves a torrent on to `state`, and tells everyone who waits for that.
+    fn set_state(&self, info_hash: [u8; 20], entry: &mut Entry, state: TorrentState) {
+        if entry.state != state {
+            entry.state = state.clone();
+            self.options.events.send(Event::StateChanged { info_hash, state });
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Changes the entry of a torrent, unless it was removed or runner `run` of it was stopped.
+    fn update(&self, info_hash: [u8; 20], run: u64, change: impl FnOnce(&mut Entry)) {
+        let mut torrents = self.torrents.lock().expect("torrents lock is never poisoned");
+        if let Some(entry) = torrents.get_mut(&info_hash).filter(|entry| entry.run == run) {
+            let state = entry.state.clone();
+            change(entry);
+            let changed = std::mem::replace(&mut entry.state, state);
+            self.set_state(info_hash, entry, changed);
+        }
+    }
+
+    async fn run(self: Arc<Self>, info_hash: [u8; 20], run: u64) {
+        let (state, seeding) = match self.drive(info_hash, run).await {
+            Ok(seeding) => (TorrentState::Seeding, Some(seeding)),
+            Err(e) => (TorrentState::Error(format!("{e:#}")), None),
+        };
+        self.update(info_hash, run, |entry| {
+            entry.state = state;
+            entry.inbound = None;
+            entry.seeding = seeding;
+        });
+    }
+
+    /// Takes a torrent through its states up to seeding, and hands back what it seeds from.
+    async fn drive(self: &Arc<Self>, info_hash: [u8; 20], run: u64) -> anyhow::Result<Seeding> {
+        let (torrent, magnet) = {
+            let torrents = self.torrents.lock().expect("torrents lock is never poisoned");
+            let entry = torrents.get(&info_hash).context("torrent was removed")?;
+            (entry.torrent.clone(), entry.magnet.clone())
+        };
+        let mut options = self.options.clone();
+        if let Some(magnet) = &magnet {
+            options.peers.extend(&magnet.peers);
+        }
+        let torrent = match torrent {
+            Some(torrent) => torrent,
+            None => {
+                let magnet = magnet.expect("torrents without metadata come from magnet links");
+                let torrent = self.fetch_metadata(&magnet, &options).await?;
+                self.update(info_hash, run, |entry| {
+                    entry.torrent = Some(torrent.clone());
+                    entry.state = TorrentState::Checking;
+                });
+                torrent
+            }
+        };
+
+        let layout = Layout::new(&torrent);
+        let file_priorities = options.file_priorities(&layout);
+        let priorities = layout.piece_priorities(&file_priorities);
+        let path = self.root(&torrent)?;
+        let storage = FileStorage::new(&path, layout, &file_priorities).with_context(|| format!("create files in {}", path.display()))?;
+        let storage = Arc::new(storage);
+        let have = tokio::task::spawn_blocking({
+            let (storage, torrent, priorities) = (storage.clone(), torrent.clone(), priorities.clone());
+            move || check(&*storage, &torrent, &priorities)
+        })
+        .await
+        .context("check the pieces on disk")?;
+        let length = |pieces: &mut dyn Iterator<Item = usize>| pieces.map(|piece_i| PieceInfo::new(piece_i, &torrent).length()).sum::<usize>();
+        let wanted = length(&mut (0..priorities.len()).filter(|&piece_i| priorities[piece_i] != Priority::Skip));
+        let verified = length(&mut have.iter().copied());
+        let (inbound, mut peers) = mpsc::channel(INBOUND_QUEUE);
+        self.update(info_hash, run, |entry| {
+            entry.wanted = wanted;
+            entry.verified = verified;
+            if verified < wanted {
+                entry.state = TorrentState::Downloading;
+                entry.inbound = Some(inbound);
+            }
+        });
+        if verified < wanted {
+            let storage = Counted { storage: storage.clone(), session: self.clone(), info_hash, run };
+            download::run(&torrent, info_hash, &options, &storage, &have, Some(&mut peers)).await?;
+        }
+        let mut have = Bitfield::new(vec![0; priorities.len().div_ceil(u8::BITS as usize)]);
+        for piece_i in (0..priorities.len()).filter(|&piece_i| priorities[piece_i] != Priority::Skip) {
+            have.set_piece(piece_i);
+        }
+        Ok(Seeding {
+            storage,
+            have,
+            plength: torrent.info.plength,
+            length: torrent.length(),
+            slots: Arc::new(Semaphore::new(options.limits.per_torrent)),
+            stop: CancellationToken::new(),
+        })
+    }
+
+    /// Where the files of `torrent` go: its name in the download directory. The name may come
+    /// from the metadata peers sent for a magnet link, so one that would lead anywhere else is
+    /// refused.
+    fn root(&self, torrent: &Torrent) -> anyhow::Result<PathBuf> {
+        storage::check_file_name(&torrent.info.name).context("torrent has an unusable name")?;
+        Ok(self.download_dir.join(&torrent.info.name))
+    }
+
+    /// Gets the info dictionary of a magnet link from the peers it names, and those its trackers
+    /// and the DHT know of.
+    async fn fetch_metadata(&self, magnet: &Magnet, options: &DownloadOptions) -> anyhow::Result<Torrent> {
+        let info_hash = magnet.info_hash;
+        let mut peers = magnet.peers.clone();
+        for tracker in &magnet.trackers {
+            // the length is not known yet; anything but 0 keeps the tracker from taking us for a seed
+            match TrackerResponse::announce(tracker, info_hash, 1, options.listen_port.unwrap_or(6881), options.proxy.as_ref()).await {
+                Ok(response) => {
+                    options.events.send(Event::TrackerReply { info_hash, tracker: tracker.clone(), peers: response.peers.0.len() });
+                    peers.extend(response.peers.0);
+                }
+                Err(e) => options.events.send(Event::TrackerError { info_hash, tracker: tracker.clone(), error: format!("{e:#}") }),
+            }
+        }
+        if let Some(dht) = &options.dht {
+            peers.extend(dht.get_peers(info_hash).await);
+        }
+        if let Some(filter) = &options.ip_filter {
+            peers.retain(|peer| !filter.blocks(peer));
+        }
+        let mut seen = HashSet::new();
+        peers.retain(|peer| seen.insert(*peer));
+        anyhow::ensure!(!peers.is_empty(), "found no peers to get the metadata from");
+        let connector = download::connector(options).await?;
+        let (info, _) = metadata::fetch(info_hash, &peers, &*connector, &options.events).await?;
+        Ok(Torrent { announce: magnet.trackers.first().cloned().unwrap_or_default(), info, nodes: Vec::new() })
+    }
+
+    /// Accepts peers for as long as the session lives.
+    async fn listen(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    self.options.events.send(Event::Log { info_hash: None, message: format!("failed to accept a peer: {e}") });
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let SocketAddr::V4(addr) = addr else {
+                // the wire protocol layer only knows IPv4 peers
+                continue;
+            };
+            if self.options.ip_filter.as_ref().is_some_and(|filter| filter.blocks(&addr)) {
+                continue;
+            }
+            let inner = self.clone();
+            tokio::spawn(async move {
+                let welcomed = tokio::time::timeout(HANDSHAKE_TIMEOUT, inner.welcome(stream, addr)).await;
+                if let Ok(Err((info_hash, e))) = welcomed {
+                    inner.options.events.send(Event::Log { info_hash: Some(info_hash), message: format!("turned away peer {addr}: {e:#}") });
+                }
+            });
+        }
+    }
+
+    /// Reads the handshake of a peer that connected to us, encrypted or not as our policy
+    /// allows, and hands the peer over to the torrent it asks for, or uploads to it if that
+    /// torrent is seeding. Peers that do not get as far as naming a torrent of ours are dropped
+    /// without a word; the others fail with the info hash they asked for.
+    async fn welcome(&self, stream: TcpStream, addr: SocketAddrV4) -> Result<(), ([u8; 20], anyhow::Error)> {
+        let info_hashes: Vec<[u8; 20]> = self.torrents.lock().expect("torrents lock is never poisoned").keys().copied().collect();
+        let Ok(mut stream) = mse::accept(stream, &info_hashes, self.options.encryption).await else {
+            return Ok(());
+        };
+        let mut handshake = [0u8; Handshake::LEN];
+        if stream.read_exact(&mut handshake).await.is_err() {
+            return Ok(());
+        }
+        let Ok(theirs) = Handshake::decode(&handshake) else {
+            return Ok(());
+        };
+        let info_hash = theirs.info_hash;
+        let (npieces, inbound, seeding) = {
+            let torrents = self.torrents.lock().expect("torrents lock is never poisoned");
+            let Some(entry) = torrents.get(&info_hash) else {
+                return Ok(());
+            };
+            if entry.inbound.is_none() && entry.seeding.is_none() {
+                return Err((info_hash, anyhow::anyhow!("torrent is neither downloading nor seeding")));
+            }
+            let npieces = entry.torrent.as_ref().expect("running torrents have metadata").info.pieces.0.len();
+            (npieces, entry.inbound.clone(), entry.seeding.clone())
+        };
+        if let Some(inbound) = inbound {
+            let peer = Peer::accept(addr, Box::new(stream), theirs, npieces, self.options.dht.clone()).await.map_err(|e| (info_hash, e))?;
+            return inbound.send(peer).await.ok().context("torrent stopped downloading").map_err(|e| (info_hash, e));
+        }
+        let seeding = seeding.expect("torrents that are not downloading are seeding");
+        let permit = self.options.limits.global.as_ref().map(GlobalLimit::try_acquire);
+        let Some(slot) = seeding.slots.clone().try_acquire_owned().ok().filter(|_| !matches!(permit, Some(None))) else {
+            return Err((info_hash, anyhow::anyhow!("torrent has no room for another peer")));
+        };
+        let mut peer = Peer::accept_to_seed(addr, Box::new(stream), theirs, &seeding.have, npieces, self.options.dht.clone())
+            .await
+            .map_err(|e| (info_hash, e))?;
+        peer.throttle(self.options.bandwidth.throttle());
+        let events = self.options.events.clone();
+        tokio::spawn(async move {
+            let _held = (permit, slot);
+            let Seeding { storage, have, plength, length, stop, .. } = seeding;
+            if let Err(e) = peer.upload(&have, &*storage, plength, length, &stop).await {
+                events.send(Event::Log { info_hash: Some(info_hash), message: format!("stopped uploading to peer {addr}: {e:#}") });
+            }
+        });
+        Ok(())
+    }
+}
+
+/// The pieces of `t` whose data in `storage` checks out, of those `priorities` does not skip.
+fn check(storage: &dyn Storage, t: &Torrent, priorities: &[Priority]) -> HashSet<usize> {
+    (0..priorities.len())
+        .filter(|&piece_i| priorities[piece_i] != Priority::Skip)
+        .filter(|&piece_i| {
+            let piece = PieceInfo::new(piece_i, t);
+            let mut data = vec![0u8; piece.length()];
+            storage.read(piece_i * t.info.plength, &mut data).is_ok() && <[u8; 20]>::from(Sha1::digest(&data)) == piece.hash()
+        })
+        .collect()
+}
+
+/// Storage that counts the verified bytes written to it towards the status of its torrent.
+struct Counted {
+    storage: Arc<FileStorage>,
+    session: Arc<Inner>,
+    info_hash: [u8; 20],
+    run: u64,
+}
+
+impl Storage for Counted {
+    fn write(&self, offset: usize, data: &[u8]) -> std::io::Result<()> {
+        self.storage.write(offset, data)?;
+        self.session.update(self.info_hash, self.run, |entry| entry.verified += data.len());
+        Ok(())
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.storage.read(offset, buf)
+    }
+}
+
+#[tokio::test]
+async fn test_session() {
+    use crate::{download::{scripted_torrent, Script}, torrent::Info, BLOCK_MAX};
+    use std::net::Ipv4Addr;
+    let npieces = 4;
+    let seeder = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1);
+    let refuses = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1);
+    let (t, mut peers) = scripted_torrent(npieces, BLOCK_MAX, [(seeder, Script { peer_id: [1; 20], ..Default::default() })]).await;
+    let info = Info { name: "one".to_string(), ..t.info };
+    let t = Torrent { info: info.clone(), ..t };
+    let magnet_info = Info { name: "two".to_string(), ..info.clone() };
+    peers.metadata = Some(Arc::new(serde_bencode::to_bytes(&magnet_info).unwrap()));
+    let data = peers.data.to_vec();
+    let peers = Arc::new(peers);
+    let dir = tempfile::tempdir().unwrap();
+    let config = SessionConfig {
+        download_dir: dir.path().to_path_buf(),
+        listen_port: 0,
+        max_connections: Some(4),
+        download: DownloadOptions { connector: Some(peers.clone()), ..Default::default() },
+        ..Default::default()
+    };
+    let session = Session::new(config).await.unwrap();
+    let events = session.events();
+    tokio::pin!(events);
+
+    let one = session.add_torrent(t.clone()).unwrap();
+    assert!(session.add_torrent(t.clone()).is_err());
+    session.finished(&one).await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join("one")).unwrap(), data);
+    let status = session.status(&one).unwrap();
+    assert_eq!((status.state, status.verified, status.wanted), (TorrentState::Seeding, data.len(), data.len()));
+
+    // resuming checks the files again, and finds nothing left to download
+    session.pause(&one).unwrap();
+    assert_eq!(session.status(&one).unwrap().state, TorrentState::Paused);
+    let served = peers.served.lock().unwrap()[&seeder];
+    session.resume(&one).unwrap();
+    session.finished(&one).await.unwrap();
+    assert_eq!(peers.served.lock().unwrap()[&seeder], served);
+    let mut states = Vec::new();
+    while let Some(Some(event)) = futures_util::FutureExt::now_or_never(futures_util::StreamExt::next(&mut events)) {
+        if let crate::events::Event::StateChanged { info_hash, state } = event {
+            assert_eq!(info_hash, one);
+            states.push(state);
+        }
+    }
+    use TorrentState::*;
+    assert_eq!(states, [Checking, Downloading, Seeding, Paused, Checking, Seeding]);
+
+    // a magnet link gets its metadata from the peer it names
+    let magnet_hash: [u8; 20] = Sha1::digest(serde_bencode::to_bytes(&magnet_info).unwrap()).into();
+    let two = session.add_magnet(&format!("magnet:?xt=urn:btih:{}&x.pe={seeder}", hex::encode(magnet_hash))).unwrap();
+    assert_eq!(two, magnet_hash);
+    session.finished(&two).await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join("two")).unwrap(), data);
+    assert_eq!(session.torrents().len(), 2);
+
+    // with no peer of its own to download from, a torrent takes on one that connects to it
+    let tracker = crate::tracker::udp_tracker_stand_in(vec![refuses]).await;
+    let three = Torrent { announce: format!("udp://{tracker}/announce"), info: Info { name: "three".to_string(), ..info }, nodes: Vec::new() };
+    let three_hash = session.add_torrent(three).unwrap();
+    while session.status(&three_hash).unwrap().state != TorrentState::Downloading {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut conn = TcpStream::connect((Ipv4Addr::LOCALHOST, session.listen_port())).await.unwrap();
+    let theirs = Handshake::new(&three_hash, &[3; 20]).exchange(&mut conn).await.unwrap();
+    assert_eq!(theirs.info_hash, three_hash);
+    let incoming = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3);
+    tokio::spawn(download::seed(conn, incoming, Script::default(), Arc::new(data.clone()), BLOCK_MAX, peers.served.clone()));
+    session.finished(&three_hash).await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join("three")).unwrap(), data);
+    assert_eq!(peers.served.lock().unwrap()[&incoming], npieces);
+
+    // a seeding torrent serves the peers that connect to it
+    {
+        use crate::peers::{Bitfield, Message, MessageFramer, PieceBlock, Request};
+        use futures_util::{SinkExt, StreamExt};
+        let mut conn = TcpStream::connect((Ipv4Addr::LOCALHOST, session.listen_port())).await.unwrap();
+        Handshake::new(&one, &[5; 20]).exchange(&mut conn).await.unwrap();
+        let mut conn = tokio_util::codec::Framed::new(conn, MessageFramer::for_pieces(npieces));
+        assert_eq!(conn.next().await.unwrap().unwrap(), Message::Bitfield(Bitfield::full(npieces)));
+        conn.send(Message::Interested).await.unwrap();
+        assert_eq!(conn.next().await.unwrap().unwrap(), Message::Unchoke);
+        conn.send(Message::Request(Request::new(2, 0, BLOCK_MAX as u32))).await.unwrap();
+        let block = bytes::Bytes::copy_from_slice(&data[2 * BLOCK_MAX..3 * BLOCK_MAX]);
+        assert_eq!(conn.next().await.unwrap().unwrap(), Message::Piece(PieceBlock { index: 2, begin: 0, block }));
+        // and stops when the torrent does
+        session.pause(&one).unwrap();
+        assert!(conn.next().await.is_none());
+    }
+
+    session.remove(&one, true).unwrap();
+    assert!(!dir.path().join("one").exists() && dir.path().join("two").exists());
+    assert!(session.finished(&one).await.is_err());
+}
+
+#[tokio::test]
+async fn test_inbound_encryption() {
+    use crate::{download::{scripted_torrent, Script}, mse::Encryption, BLOCK_MAX};
+    use std::net::Ipv4Addr;
+    let refuses = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1);
+    let (t, peers) = scripted_torrent(2, BLOCK_MAX, [(refuses, Script { refuses: true, ..Default::default() })]).await;
+    let (info_hash, peers) = (t.clone().info_hash(), Arc::new(peers));
+    let dir = tempfile::tempdir().unwrap();
+    let config = SessionConfig {
+        download_dir: dir.path().to_path_buf(),
+        listen_port: 0,
+        download: DownloadOptions { connector: Some(peers.clone()), encryption: Encryption::Require, ..Default::default() },
+        ..Default::default()
+    };
+    let session = Session::new(config).await.unwrap();
+    session.add_torrent(t).unwrap();
+    while session.status(&info_hash).unwrap().state != TorrentState::Downloading {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let listening = (Ipv4Addr::LOCALHOST, session.listen_port());
+
+    // encryption is required, so a plaintext peer is turned away
+    let mut conn = TcpStream::connect(listening).await.unwrap();
+    assert!(Handshake::new(&info_hash, &[3; 20]).exchange(&mut conn).await.is_err());
+
+    // and one that comes encrypted is taken on
+    let conn = TcpStream::connect(listening).await.unwrap();
+    let mut conn = mse::initiate(conn, &info_hash, Encryption::Require).await.unwrap();
+    Handshake::new(&info_hash, &[4; 20]).exchange(&mut conn).await.unwrap();
+    let incoming = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4);
+    tokio::spawn(download::seed(conn, incoming, Script::default(), peers.data.clone(), BLOCK_MAX, peers.served.clone()));
+    session.finished(&info_hash).await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join("test")).unwrap(), *peers.data);
+}
+
+#[tokio::test]
+async fn test_unsafe_torrent_name() {
+    use crate::{download::{scripted_torrent, Script}, torrent::Info, BLOCK_MAX};
+    use std::net::Ipv4Addr;
+    let seeder = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1);
+    let (t, peers) = scripted_torrent(2, BLOCK_MAX, [(seeder, Script::default())]).await;
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("victim"), b"precious").unwrap();
+    let config = SessionConfig {
+        download_dir: dir.path().join("downloads"),
+        listen_port: 0,
+        download: DownloadOptions { connector: Some(Arc::new(peers)), ..Default::default() },
+        ..Default::default()
+    };
+    let session = Session::new(config).await.unwrap();
+
+    // a name leading out of the download directory stops the torrent before anything is written
+    let t = Torrent { info: Info { name: "../victim".to_string(), ..t.info }, ..t };
+    let info_hash = session.add_torrent(t).unwrap();
+    assert!(session.finished(&info_hash).await.is_err());
+    assert!(matches!(session.status(&info_hash).unwrap().state, TorrentState::Error(_)));
+    assert!(session.remove(&info_hash, true).is_err());
+    assert_eq!(std::fs::read(dir.path().join("victim")).unwrap(), b"precious");
+}
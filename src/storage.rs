@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{piece::Priority, torrent::{FileInfo, Keys, Torrent}};
+
+/// A contiguous part of a single file that some range of the torrent maps onto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file: usize,
+    /// Offset within the file.
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Where the files of a torrent sit in the single byte stream that the pieces are cut from.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    files: Vec<FileInfo>,
+    // offset of every file in the torrent, plus the total length at the end
+    offsets: Vec<usize>,
+    plength: usize,
+    single: bool,
+}
+
+impl Layout {
+    pub fn new(t: &Torrent) -> Self {
+        let single = matches!(t.info.keys, Keys::SingleFile { .. });
+        let files = match &t.info.keys {
+            Keys::SingleFile { length } => vec![FileInfo {
+                length: *length,
+                path: vec![t.info.name.clone()],
+            }],
+            Keys::MultiFile { files } => files.clone(),
+        };
+        let mut offsets = Vec::with_capacity(files.len() + 1);
+        let mut offset = 0;
+        for file in &files {
+            offsets.push(offset);
+            offset += file.length;
+        }
+        offsets.push(offset);
+        Self { files, offsets, plength: t.info.plength, single }
+    }
+
+    pub fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    /// Where every file goes: `root` itself for a single file, below it otherwise. Fails if a
+    /// file's path would lead anywhere else, as one with `..` or an absolute part would.
+    pub fn paths(&self, root: &Path) -> io::Result<Vec<PathBuf>> {
+        if self.single {
+            return Ok(vec![root.to_path_buf()]);
+        }
+        self.files
+            .iter()
+            .map(|file| {
+                if file.path.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "file without a path"));
+                }
+                file.path.iter().try_fold(root.to_path_buf(), |path, part| {
+                    check_file_name(part)?;
+                    Ok(path.join(part))
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_single_file(&self) -> bool {
+        self.single
+    }
+
+    pub fn length(&self) -> usize {
+        *self.offsets.last().expect("always holds the total length")
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.plength
+    }
+
+    pub fn npieces(&self) -> usize {
+        self.length().div_ceil(self.plength)
+    }
+
+    /// The byte range of the torrent that `file` occupies.
+    pub fn file_range(&self, file: usize) -> std::ops::Range<usize> {
+        self.offsets[file]..self.offsets[file + 1]
+    }
+
+    /// The byte range of the torrent that piece `piece_i` covers.
+    pub fn piece_range(&self, piece_i: usize) -> std::ops::Range<usize> {
+        let start = piece_i * self.plength;
+        start..(start + self.plength).min(self.length())
+    }
+
+    /// The file spans covering `length` bytes of the torrent starting at `offset`.
+    pub fn spans(&self, offset: usize, length: usize) -> impl Iterator<Item = FileSpan> + '_ {
+        let end = offset + length;
+        // the last file that starts at or before offset
+        let first = self.offsets[1..].partition_point(|&file_end| file_end <= offset);
+        (first..self.files.len())
+            .take_while(move |&file| self.offsets[file] < end)
+            .filter_map(move |file| {
+                let range = self.file_range(file);
+                let start = range.start.max(offset);
+                let stop = range.end.min(end);
+                (start < stop).then_some(FileSpan { file, offset: start - range.start, length: stop - start })
+            })
+    }
+
+    /// The files that piece `piece_i` has data for.
+    pub fn piece_files(&self, piece_i: usize) -> impl Iterator<Item = usize> + '_ {
+        let range = self.piece_range(piece_i);
+        self.spans(range.start, range.len()).map(|span| span.file)
+    }
+
+    /// The priority of every piece given the priority of every file: a piece is as important as
+    /// the most important file it overlaps, so pieces on the boundary of a skipped file are still
+    /// downloaded in full.
+    pub fn piece_priorities(&self, file_priorities: &[Priority]) -> Vec<Priority> {
+        (0..self.npieces())
+            .map(|piece_i| {
+                self.piece_files(piece_i)
+                    .map(|file| file_priorities.get(file).copied().unwrap_or_default())
+                    .max()
+                    .unwrap_or(Priority::Skip)
+            })
+            .collect()
+    }
+}
+
+/// Checks that `name`, a file or directory name out of a torrent, names something right inside
+/// the directory it is joined onto: it must not be empty, `.` or `..`, absolute, or have a
+/// separator or drive prefix in it. Torrents come from strangers.
+pub fn check_file_name(name: &str) -> io::Result<()> {
+    let mut components = Path::new(name).components();
+    let plain = matches!((components.next(), components.next()), (Some(Component::Normal(only)), None) if only == name);
+    let drive = matches!(name.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic());
+    if !plain || drive || name.contains(['/', '\\']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{name:?} is not a plain file name")));
+    }
+    Ok(())
+}
+
+/// Where verified data ends up. Offsets are into the torrent as a whole.
+pub trait Storage: Send + Sync {
+    fn write(&self, offset: usize, data: &[u8]) -> io::Result<()>;
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// Keeps the whole torrent in memory.
+pub struct MemoryStorage {
+    bytes: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(length: usize) -> Self {
+        Self { bytes: Mutex::new(vec![0; length]) }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes.into_inner().expect("storage lock is never poisoned")
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write(&self, offset: usize, data: &[u8]) -> io::Result<()> {
+        let mut bytes = self.bytes.lock().expect("storage lock is never poisoned");
+        let Some(dst) = bytes.get_mut(offset..offset + data.len()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "write past the end of the torrent"));
+        };
+        dst.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        let bytes = self.bytes.lock().expect("storage lock is never poisoned");
+        let Some(src) = bytes.get(offset..offset + buf.len()) else {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past the end of the torrent"));
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+/// Writes the torrent's files to disk.
+///
+/// A single-file torrent is written to `root` itself; the files of a multi-file torrent go below
+/// `root`. Files that are not skipped are created up front at their full length. Skipped files are
+/// only created if a piece that straddles them and a wanted file gets written. A torrent whose
+/// file paths would lead out of `root` is refused.
+pub struct FileStorage {
+    layout: Layout,
+    paths: Vec<PathBuf>,
+    handles: Mutex<HashMap<usize, File>>,
+}
+
+impl FileStorage {
+    pub fn new(root: impl AsRef<Path>, layout: Layout, file_priorities: &[Priority]) -> io::Result<Self> {
+        let paths = layout.paths(root.as_ref())?;
+        let storage = Self { layout, paths, handles: Mutex::new(HashMap::new()) };
+        let mut handles = storage.handles.lock().expect("storage lock is never poisoned");
+        for file in 0..storage.paths.len() {
+            if file_priorities.get(file).copied().unwrap_or_default() != Priority::Skip {
+                let handle = storage.open(file)?;
+                handle.set_len(storage.layout.files()[file].length as u64)?;
+                handles.insert(file, handle);
+            }
+        }
+        drop(handles);
+        Ok(storage)
+    }
+
+    pub fn path(&self, file: usize) -> &Path {
+        &self.paths[file]
+    }
+
+    fn handle<'a>(&self, handles: &'a mut HashMap<usize, File>, file: usize) -> io::Result<&'a mut File> {
+        Ok(match handles.entry(file) {
+            std::collections::hash_map::Entry::Occupied(handle) => handle.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => entry.insert(self.open(file)?),
+        })
+    }
+
+    fn open(&self, file: usize) -> io::Result<File> {
+        let path = &self.paths[file];
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+    }
+}
+
+impl Storage for FileStorage {
+    fn write(&self, offset: usize, data: &[u8]) -> io::Result<()> {
+        let mut handles = self.handles.lock().expect("storage lock is never poisoned");
+        let mut written = 0;
+        for span in self.layout.spans(offset, data.len()) {
+            let handle = self.handle(&mut handles, span.file)?;
+            handle.seek(SeekFrom::Start(span.offset as u64))?;
+            handle.write_all(&data[written..written + span.length])?;
+            written += span.length;
+        }
+        Ok(())
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        let mut handles = self.handles.lock().expect("storage lock is never poisoned");
+        let mut read = 0;
+        for span in self.layout.spans(offset, buf.len()) {
+            let handle = self.handle(&mut handles, span.file)?;
+            handle.seek(SeekFrom::Start(span.offset as u64))?;
+            handle.read_exact(&mut buf[read..read + span.length])?;
+            read += span.length;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_layout_spans_and_priorities() {
+    use crate::{hash::Hashes, torrent::Info};
+    let file = |length, name: &str| FileInfo { length, path: vec![name.to_string()] };
+    let t = Torrent {
+        announce: String::new(),
+        info: Info {
+            name: "test".to_string(),
+            plength: 4,
+            pieces: Hashes(vec![[0; 20]; 3]),
+            keys: Keys::MultiFile { files: vec![file(3, "a"), file(0, "empty"), file(6, "b"), file(1, "c")] },
+        },
+        nodes: Vec::new(),
+    };
+    let layout = Layout::new(&t);
+    let spans: Vec<_> = layout.spans(2, 6).collect();
+    assert_eq!(
+        spans,
+        vec![FileSpan { file: 0, offset: 2, length: 1 }, FileSpan { file: 2, offset: 0, length: 5 }]
+    );
+    let priorities = layout.piece_priorities(&[Priority::Skip, Priority::Skip, Priority::High, Priority::Skip]);
+    // piece 0 straddles the skipped `a` and the wanted `b`, piece 2 only holds `b`'s last byte and `c`
+    assert_eq!(priorities, vec![Priority::High, Priority::High, Priority::High]);
+    let priorities = layout.piece_priorities(&[Priority::Low, Priority::Skip, Priority::Skip, Priority::Skip]);
+    assert_eq!(priorities, vec![Priority::Low, Priority::Skip, Priority::Skip]);
+}
+
+#[test]
+fn test_file_storage_stays_below_root() {
+    use crate::{hash::Hashes, torrent::Info};
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("torrent");
+    let torrent = |path: &[&str]| Torrent {
+        announce: String::new(),
+        info: Info {
+            name: "torrent".to_string(),
+            plength: 4,
+            pieces: Hashes(vec![[0; 20]]),
+            keys: Keys::MultiFile { files: vec![FileInfo { length: 1, path: path.iter().map(|part| part.to_string()).collect() }] },
+        },
+        nodes: Vec::new(),
+    };
+    for path in [&["..", "..", ".bashrc"][..], &["/etc", "passwd"], &["a/../../b"], &[""], &["."], &["C:", "x"], &["a\\b"], &[]] {
+        assert!(FileStorage::new(&root, Layout::new(&torrent(path)), &[]).is_err(), "{path:?}");
+    }
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    let storage = FileStorage::new(&root, Layout::new(&torrent(&["sub", "..data"])), &[]).unwrap();
+    assert_eq!(storage.path(0), root.join("sub").join("..data"));
+}
+
+#[test]
+fn test_file_storage_cuts_output_to_length() {
+    use crate::{hash::Hashes, torrent::Info};
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    std::fs::write(&path, b"0123456789").unwrap();
+    let t = Torrent {
+        announce: String::new(),
+        info: Info {
+            name: "out".to_string(),
+            plength: 4,
+            pieces: Hashes(vec![[0; 20]; 2]),
+            keys: Keys::SingleFile { length: 6 },
+        },
+        nodes: Vec::new(),
+    };
+    // what was downloaded already stays, whatever an earlier, longer file left behind goes
+    let storage = FileStorage::new(&path, Layout::new(&t), &[]).unwrap();
+    drop(storage);
+    assert_eq!(std::fs::read(&path).unwrap(), b"012345");
+}
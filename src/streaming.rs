@@ -0,0 +1,325 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::{events::{Event, Events}, piece::{Availability, PiecePicker}, storage::{Layout, Storage}};
+
+/// Where a reader of a streaming download currently is, and how far ahead of it to fetch first.
+#[derive(Debug, Clone)]
+pub struct Playhead {
+    piece: Arc<AtomicUsize>,
+    window: usize,
+}
+
+impl Playhead {
+    /// `window` is the number of pieces from the playhead on that are fetched before anything else.
+    pub fn new(window: usize) -> Self {
+        Self { piece: Arc::new(AtomicUsize::new(0)), window: window.max(1) }
+    }
+
+    pub fn seek(&self, piece_i: usize) {
+        self.piece.store(piece_i, Ordering::Relaxed);
+    }
+
+    pub fn piece(&self) -> usize {
+        self.piece.load(Ordering::Relaxed)
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+}
+
+/// Fetches the pieces right after the playhead first, lowest first, and leaves the rest to
+/// another picker; pieces behind the playhead come last.
+pub struct Streaming {
+    playhead: Playhead,
+    inner: Box<dyn PiecePicker>,
+}
+
+impl Streaming {
+    pub fn new(playhead: Playhead, inner: Box<dyn PiecePicker>) -> Self {
+        Self { playhead, inner }
+    }
+}
+
+impl PiecePicker for Streaming {
+    fn pick(&mut self, candidates: &[usize], availability: &Availability) -> Option<usize> {
+        let start = self.playhead.piece();
+        let window = start..start + self.playhead.window();
+        if let Some(piece_i) = candidates.iter().copied().filter(|piece_i| window.contains(piece_i)).min() {
+            return Some(piece_i);
+        }
+        let ahead: Vec<usize> = candidates.iter().copied().filter(|&piece_i| piece_i >= start).collect();
+        if ahead.is_empty() {
+            self.inner.pick(candidates, availability)
+        } else {
+            self.inner.pick(&ahead, availability)
+        }
+    }
+
+    fn completed(&mut self, piece_i: usize) {
+        self.inner.completed(piece_i);
+    }
+}
+
+/// Wraps the storage of a download and lets readers wait for data that has not been verified yet.
+///
+/// The download only ever writes whole pieces once their hash checks out, so every piece a write
+/// covers is marked as readable.
+pub struct StreamStorage {
+    inner: Box<dyn Storage>,
+    layout: Layout,
+    verified: Mutex<Vec<bool>>,
+    notify: Notify,
+}
+
+impl StreamStorage {
+    pub fn new(inner: Box<dyn Storage>, layout: Layout) -> Self {
+        let verified = Mutex::new(vec![false; layout.npieces()]);
+        Self { inner, layout, verified, notify: Notify::new() }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn covered(&self, offset: usize, length: usize) -> bool {
+        if length == 0 {
+            return true;
+        }
+        let plength = self.layout.piece_length();
+        let verified = self.verified.lock().expect("verified lock is never poisoned");
+        (offset / plength..=(offset + length - 1) / plength).all(|piece_i| verified[piece_i])
+    }
+
+    /// Fills `buf` from `offset` on, waiting for as long as the pieces it needs are missing.
+    pub async fn read_verified(&self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.covered(offset, buf.len()) {
+                return self.inner.read(offset, buf);
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Storage for StreamStorage {
+    fn write(&self, offset: usize, data: &[u8]) -> io::Result<()> {
+        self.inner.write(offset, data)?;
+        let end = offset + data.len();
+        let mut verified = self.verified.lock().expect("verified lock is never poisoned");
+        for (piece_i, verified) in verified.iter_mut().enumerate() {
+            let range = self.layout.piece_range(piece_i);
+            if offset <= range.start && range.end <= end {
+                *verified = true;
+            }
+        }
+        drop(verified);
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read(offset, buf)
+    }
+}
+
+const CHUNK: usize = 1 << 16;
+
+/// Serves the files of a streaming download over HTTP, with support for `Range` requests.
+///
+/// A single-file torrent is served at `/`; the files of a multi-file torrent at `/<index>`, with a
+/// listing at `/`. Reads move the playhead, so the download follows whatever is being read.
+/// Requests that fail are reported to `events`.
+pub async fn serve(listener: TcpListener, storage: Arc<StreamStorage>, playhead: Playhead, events: Events) -> anyhow::Result<()> {
+    loop {
+        let (conn, _) = listener.accept().await.context("accept http connection")?;
+        let storage = storage.clone();
+        let playhead = playhead.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(conn, &storage, &playhead).await {
+                events.send(Event::Log { info_hash: None, message: format!("http: {e:#}") });
+            }
+        });
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+async fn read_request(conn: &mut TcpStream) -> anyhow::Result<HttpRequest> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        anyhow::ensure!(head.len() < 8192, "request head too large");
+        let n = conn.read(&mut buf).await.context("read request")?;
+        anyhow::ensure!(n > 0, "connection closed before the request was complete");
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, value)| value.trim().to_string());
+    Ok(HttpRequest { method, path, range })
+}
+
+/// Parses a single `bytes=` range against a resource of `length` bytes into an inclusive range.
+/// `Err` means the range cannot be satisfied.
+fn parse_range(range: &str, length: usize) -> Result<(usize, usize), ()> {
+    let spec = range.strip_prefix("bytes=").ok_or(())?;
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().map_err(|_| ())?;
+            (length.saturating_sub(suffix), length.checked_sub(1).ok_or(())?)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, length.checked_sub(1).ok_or(())?),
+        (start, end) => {
+            let end: usize = end.parse().map_err(|_| ())?;
+            (start.parse().map_err(|_| ())?, end.min(length.saturating_sub(1)))
+        }
+    };
+    if start > end || start >= length {
+        return Err(());
+    }
+    Ok((start, end))
+}
+
+async fn respond(mut conn: TcpStream, storage: &StreamStorage, playhead: &Playhead) -> anyhow::Result<()> {
+    let request = read_request(&mut conn).await?;
+    let layout = storage.layout();
+    if request.method != "GET" && request.method != "HEAD" {
+        conn.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+        return Ok(());
+    }
+
+    let file = match request.path.trim_start_matches('/') {
+        "" if layout.is_single_file() => Some(0),
+        "" => {
+            let mut listing = String::new();
+            for (i, file) in layout.files().iter().enumerate() {
+                listing.push_str(&format!("/{i}\t{}\t{}\n", file.length, file.path.join("/")));
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                listing.len()
+            );
+            conn.write_all(head.as_bytes()).await?;
+            if request.method == "GET" {
+                conn.write_all(listing.as_bytes()).await?;
+            }
+            return Ok(());
+        }
+        index => index.parse().ok().filter(|&i| i < layout.files().len()),
+    };
+    let Some(file) = file else {
+        conn.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+        return Ok(());
+    };
+
+    let file_range = layout.file_range(file);
+    let length = file_range.len();
+    let (status, start, end) = match request.range.as_deref().map(|range| parse_range(range, length)) {
+        None => ("200 OK", 0, length),
+        Some(Ok((start, end))) => ("206 Partial Content", start, end + 1),
+        Some(Err(())) => {
+            let head = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{length}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            conn.write_all(head.as_bytes()).await?;
+            return Ok(());
+        }
+    };
+    let mut head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n",
+        end - start
+    );
+    if request.range.is_some() {
+        head.push_str(&format!("Content-Range: bytes {start}-{}/{length}\r\n", end - 1));
+    }
+    head.push_str("\r\n");
+    conn.write_all(head.as_bytes()).await?;
+    if request.method == "HEAD" {
+        return Ok(());
+    }
+
+    let mut buf = vec![0u8; CHUNK];
+    let mut pos = file_range.start + start;
+    let stop = file_range.start + end;
+    while pos < stop {
+        let piece_end = layout.piece_range(pos / layout.piece_length()).end;
+        let n = CHUNK.min(stop - pos).min(piece_end - pos);
+        playhead.seek(pos / layout.piece_length());
+        storage.read_verified(pos, &mut buf[..n]).await.context("read from storage")?;
+        conn.write_all(&buf[..n]).await?;
+        pos += n;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Ok((0, 99)));
+    assert_eq!(parse_range("bytes=900-", 1000), Ok((900, 999)));
+    assert_eq!(parse_range("bytes=-100", 1000), Ok((900, 999)));
+    assert_eq!(parse_range("bytes=990-2000", 1000), Ok((990, 999)));
+    assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+    assert_eq!(parse_range("items=0-1", 1000), Err(()));
+}
+
+#[tokio::test]
+async fn test_range_request_waits_for_verified_piece() {
+    use crate::{hash::Hashes, storage::MemoryStorage, torrent::{Info, Keys, Torrent}};
+    let t = Torrent {
+        announce: String::new(),
+        info: Info {
+            name: "test".to_string(),
+            plength: 4,
+            pieces: Hashes(vec![[0; 20]; 3]),
+            keys: Keys::SingleFile { length: 10 },
+        },
+        nodes: Vec::new(),
+    };
+    let layout = Layout::new(&t);
+    let storage = Arc::new(StreamStorage::new(Box::new(MemoryStorage::new(10)), layout));
+    let playhead = Playhead::new(2);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, storage.clone(), playhead.clone(), Events::new()));
+
+    let client = tokio::spawn(async move {
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"GET / HTTP/1.1\r\nRange: bytes=5-8\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    });
+    // the reader is stuck on piece 1 until it has been verified
+    while playhead.piece() != 1 {
+        tokio::task::yield_now().await;
+    }
+    storage.write(4, b"4567").unwrap();
+    storage.write(8, b"89").unwrap();
+    let response = client.await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(response.contains("Content-Range: bytes 5-8/10\r\n"));
+    assert!(response.ends_with("\r\n\r\n5678"));
+}
@@ -2,15 +2,48 @@ use std::path::Path;
 
 use anyhow::{Context, Ok};
 use serde::*;
+use serde_bencode::value::Value;
 use super::hash::Hashes;
 use sha1::{Sha1, Digest};
 use super::download;
+use super::storage::{FileStorage, Layout, MemoryStorage, Storage};
 #[derive( Clone, Serialize, Deserialize, Debug)]
 pub struct Torrent { 
     // URL to a "tracker", which is a central server that keeps track of peers participating in the sharing of a torrent 
+    // Trackerless torrents leave it out and list DHT nodes instead.
+    #[serde(default)]
     pub announce : String, 
     // A dictionary with keys
-    pub info : Info
+    pub info : Info,
+    // DHT nodes to join through, as `[host, port]` pairs (BEP 5)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes : Vec<DhtNode>
+}
+
+/// A `[host, port]` entry of the metainfo `nodes` list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhtNode {
+    pub host : String,
+    pub port : u16
+}
+
+impl Serialize for DhtNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (&self.host, self.port).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DhtNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // serde_bencode loses its place after a tuple inside a list, so go through plain values
+        match Vec::<Value>::deserialize(deserializer)?.as_slice() {
+            [Value::Bytes(host), Value::Int(port)] => Result::Ok(DhtNode {
+                host: String::from_utf8(host.clone()).map_err(de::Error::custom)?,
+                port: u16::try_from(*port).map_err(de::Error::custom)?,
+            }),
+            _ => Err(de::Error::custom("expected a [host, port] list")),
+        }
+    }
 }
 
 impl Torrent { 
@@ -19,14 +52,21 @@ impl Torrent {
         let mut hasher = Sha1::new();
         hasher.update(info_encoded);
         let info_hash = hasher.finalize();
-        info_hash.try_into().expect("GenericArray<u8> to [u8;20]")
+        info_hash.into()
     }
 
     pub async fn read(file : impl AsRef<Path>) -> anyhow::Result<Self>  {
-        let mut f = tokio::fs::read(file).await.context("read torren file bytes")?;
-        let tf_info: Torrent = serde_bencode::from_bytes(&mut f).context("parse the file")?;
+        let f = tokio::fs::read(file).await.context("read torren file bytes")?;
+        let tf_info: Torrent = serde_bencode::from_bytes(&f).context("parse the file")?;
         Ok(tf_info)
     }
+    /// The `nodes` of the metainfo as `host:port` strings, ready to bootstrap the DHT with.
+    pub fn dht_nodes(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .map(|DhtNode { host, port }| if host.contains(':') { format!("[{host}]:{port}") } else { format!("{host}:{port}") })
+            .collect()
+    }
     pub fn length(&self) -> usize { 
         match &self.info.keys {
             Keys::SingleFile { length } => *length,
@@ -37,14 +77,28 @@ impl Torrent {
         match &self.info.keys {
             Keys::SingleFile { .. } => eprintln!("{}", self.info.name),
             Keys::MultiFile { files } => { 
-                for file in files { 
-                    eprintln!("{:?}", file.path.join(std::path::MAIN_SEPARATOR_STR));
+                for (i, file) in files.iter().enumerate() { 
+                    eprintln!("{i}: {:?}", file.path.join(std::path::MAIN_SEPARATOR_STR));
                 }
             },
         }
     }
     pub async fn download_all(&self) -> anyhow::Result<download::Downloaded> { 
-        download::all(&self).await
+        let storage = MemoryStorage::new(self.length());
+        download::all(self, &download::DownloadOptions::default(), &storage).await?;
+        Ok(download::Downloaded { bytes: storage.into_bytes(), files: Layout::new(self).files().to_vec() })
+    }
+    /// Downloads the selected files to disk: a single-file torrent to `output` itself, the files
+    /// of a multi-file torrent below the `output` directory.
+    pub async fn download_to(&self, output: impl AsRef<Path>, options: &download::DownloadOptions) -> anyhow::Result<()> {
+        let layout = Layout::new(self);
+        let priorities = options.file_priorities(&layout);
+        let storage = FileStorage::new(output, layout, &priorities).context("create output files")?;
+        download::all(self, options, &storage).await
+    }
+    /// Downloads the selected pieces into any [`Storage`].
+    pub async fn download_into(&self, storage: &dyn Storage, options: &download::DownloadOptions) -> anyhow::Result<()> {
+        download::all(self, options, storage).await
     }
 }
 
@@ -89,3 +143,13 @@ pub struct FileInfo {
     pub path : Vec<String> 
 
 }
+
+#[test]
+fn test_trackerless_torrent_nodes() {
+    let t: Torrent = serde_bencode::from_bytes(
+        b"d4:infod6:lengthi3e4:name1:a12:piece lengthi3e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel7:2001:dbi1eeee",
+    )
+    .unwrap();
+    assert!(t.announce.is_empty());
+    assert_eq!(t.dht_nodes(), vec!["127.0.0.1:6881".to_string(), "[2001:db]:1".to_string()]);
+}
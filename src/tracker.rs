@@ -1,7 +1,12 @@
-use anyhow::Ok;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::{Context, Ok};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
 
-use crate::{peers::Peers, torrent::Torrent};
+use crate::{peers::Peers, proxy::{Proxy, Socks5Udp}, random::Rng, torrent::Torrent};
 
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -39,32 +44,218 @@ pub struct TrackerResponse {
 
 
 impl TrackerResponse { 
-    pub(crate) async fn query_tracker_info(t : &Torrent, info_hash : [u8; 20])  -> anyhow::Result<Self> {
-         
+    pub(crate) async fn query_tracker_info(t : &Torrent, info_hash : [u8; 20], port: u16, proxy: Option<&Proxy>)  -> anyhow::Result<Self> {
+        Self::announce(&t.announce, info_hash, t.clone().length(), port, proxy).await
+    }
+
+    /// Asks the tracker at `announce` for peers, over UDP for `udp://` trackers and HTTP otherwise,
+    /// telling it that we accept peers on `port`.
+    pub(crate) async fn announce(announce: &str, info_hash: [u8; 20], left: usize, port: u16, proxy: Option<&Proxy>) -> anyhow::Result<Self> {
+        if announce.starts_with("udp://") {
+            return udp_announce(announce, info_hash, left, port, proxy).await;
+        }
         let request = TrackerRequest {  
             peer_id : "00112233445566778899".to_string(),
-            port : 6881,
+            port,
             uploaded: 0, 
             downloaded : 0,
-            left : t.clone().length(), 
+            left, 
             compact : 1
         };
         
         let query_params = serde_urlencoded::to_string(&request).expect("encode into url params");
-        let tracker_url = format!("{}?{}&info_hash={}", t.announce, query_params, &urlencode(&info_hash));
-        let res = reqwest::get(tracker_url).await?;
-        let res_bytes = res.bytes().await.expect("expected response bytes");
-        let tracker_response : TrackerResponse = serde_bencode::from_bytes(&res_bytes).expect("Tracker Response");
+        let tracker_url = format!("{}?{}&info_hash={}", announce, query_params, &urlencode(&info_hash));
+        let res_bytes = match proxy {
+            Some(proxy) => {
+                let url = Url::parse(&tracker_url).context("parse tracker url")?;
+                proxy.get(&url).await.context("query tracker through proxy")?
+            }
+            None => {
+                let res = reqwest::get(tracker_url).await?;
+                res.bytes().await.context("read tracker response")?.to_vec()
+            }
+        };
+        let tracker_response : TrackerResponse = serde_bencode::from_bytes(&res_bytes).context("decode tracker response")?;
         Ok(tracker_response)
     }
 }
 
+/// The magic number that opens every UDP tracker conversation (BEP 15).
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+/// BEP 15 waits 15 * 2^n seconds for the n-th retry; we give up sooner than it allows.
+const UDP_TIMEOUT: Duration = Duration::from_secs(15);
+const UDP_ATTEMPTS: u32 = 3;
+
+/// Where UDP tracker datagrams go: straight to the tracker, or through a SOCKS5 relay.
+enum UdpRoute {
+    Direct(UdpSocket, SocketAddr),
+    Proxied(Socks5Udp, String, u16),
+}
+
+impl UdpRoute {
+    async fn send(&self, packet: &[u8]) -> anyhow::Result<()> {
+        match self {
+            UdpRoute::Direct(socket, tracker) => {
+                socket.send_to(packet, tracker).await.context("send to udp tracker")?;
+                Ok(())
+            }
+            UdpRoute::Proxied(relay, host, port) => relay.send_to(packet, host, *port).await,
+        }
+    }
+
+    async fn recv(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            UdpRoute::Direct(socket, tracker) => {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let (n, from) = socket.recv_from(&mut buf).await.context("receive from udp tracker")?;
+                    if from == *tracker {
+                        return Ok(buf[..n].to_vec());
+                    }
+                }
+            }
+            UdpRoute::Proxied(relay, _, _) => relay.recv().await,
+        }
+    }
+
+    /// Sends `request` until a response to `transaction_id` comes back, and returns what follows
+    /// the action and transaction id.
+    async fn transact(&self, request: &[u8], action: u32, transaction_id: u32) -> anyhow::Result<Vec<u8>> {
+        for attempt in 0..UDP_ATTEMPTS {
+            self.send(request).await?;
+            let response = async {
+                loop {
+                    let response = self.recv().await?;
+                    if response.len() < 8 || response[4..8] != transaction_id.to_be_bytes() {
+                        continue;
+                    }
+                    let got = u32::from_be_bytes(response[..4].try_into().expect("4 bytes"));
+                    if got == ACTION_ERROR {
+                        anyhow::bail!("udp tracker error: {}", String::from_utf8_lossy(&response[8..]));
+                    }
+                    anyhow::ensure!(got == action, "udp tracker answered with action {got}, not {action}");
+                    return Ok(response[8..].to_vec());
+                }
+            };
+            if let Result::Ok(response) = tokio::time::timeout(UDP_TIMEOUT * 2u32.pow(attempt), response).await {
+                return response;
+            }
+        }
+        anyhow::bail!("udp tracker did not answer")
+    }
+}
+
+async fn udp_announce(announce: &str, info_hash: [u8; 20], left: usize, listen_port: u16, proxy: Option<&Proxy>) -> anyhow::Result<TrackerResponse> {
+    let url = Url::parse(announce).with_context(|| format!("bad tracker url {announce:?}"))?;
+    let host = url.host_str().context("tracker url without a host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port().context("udp tracker url without a port")?;
+    let route = match proxy {
+        Some(proxy) => UdpRoute::Proxied(proxy.udp_associate().await?, host.to_string(), port),
+        None => {
+            let tracker = tokio::net::lookup_host((host, port))
+                .await
+                .with_context(|| format!("resolve tracker {host}"))?
+                .find(SocketAddr::is_ipv4)
+                .with_context(|| format!("tracker {host} has no IPv4 address"))?;
+            let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await.context("bind udp socket")?;
+            UdpRoute::Direct(socket, tracker)
+        }
+    };
+    let mut rng = Rng::new();
+
+    let transaction_id = rng.next_u64() as u32;
+    let mut connect = Vec::with_capacity(16);
+    connect.extend_from_slice(&UDP_PROTOCOL_ID.to_be_bytes());
+    connect.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    connect.extend_from_slice(&transaction_id.to_be_bytes());
+    let response = route.transact(&connect, ACTION_CONNECT, transaction_id).await?;
+    let connection_id = response.get(..8).context("short udp connect response")?;
+
+    let transaction_id = rng.next_u64() as u32;
+    let mut request = Vec::with_capacity(98);
+    request.extend_from_slice(connection_id);
+    request.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    request.extend_from_slice(&transaction_id.to_be_bytes());
+    request.extend_from_slice(&info_hash);
+    request.extend_from_slice(b"00112233445566778899");
+    request.extend_from_slice(&0u64.to_be_bytes()); // downloaded
+    request.extend_from_slice(&(left as u64).to_be_bytes());
+    request.extend_from_slice(&0u64.to_be_bytes()); // uploaded
+    request.extend_from_slice(&0u32.to_be_bytes()); // event: none
+    request.extend_from_slice(&0u32.to_be_bytes()); // ip: the one we send from
+    request.extend_from_slice(&(rng.next_u64() as u32).to_be_bytes()); // key
+    request.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: the tracker's default
+    request.extend_from_slice(&listen_port.to_be_bytes());
+    let response = route.transact(&request, ACTION_ANNOUNCE, transaction_id).await?;
+    anyhow::ensure!(response.len() >= 12, "short udp announce response");
+    let interval = u32::from_be_bytes(response[..4].try_into().expect("4 bytes")) as usize;
+    // then leechers and seeders, which we have no use for
+    let peers = response[12..]
+        .chunks_exact(6)
+        .map(|peer| SocketAddrV4::new(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]), u16::from_be_bytes([peer[4], peer[5]])))
+        .collect();
+    Ok(TrackerResponse { interval, peers: Peers(peers) })
+}
+
+/// A UDP tracker that hands out `peers` to anyone announcing.
+#[cfg(test)]
+pub(crate) async fn udp_tracker_stand_in(peers: Vec<SocketAddrV4>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            let mut response = buf[8..16].to_vec();
+            match action {
+                ACTION_CONNECT if n == 16 && buf[..8] == UDP_PROTOCOL_ID.to_be_bytes() => {
+                    response.extend_from_slice(&0xc0ffee_u64.to_be_bytes());
+                }
+                ACTION_ANNOUNCE if n == 98 && buf[..8] == 0xc0ffee_u64.to_be_bytes() => {
+                    response.extend_from_slice(&1800u32.to_be_bytes());
+                    response.extend_from_slice(&[0; 8]);
+                    for peer in &peers {
+                        response.extend_from_slice(&peer.ip().octets());
+                        response.extend_from_slice(&peer.port().to_be_bytes());
+                    }
+                }
+                _ => {
+                    response[..4].copy_from_slice(&ACTION_ERROR.to_be_bytes());
+                    response.extend_from_slice(b"bad request");
+                }
+            }
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_udp_tracker() {
+    let peers = vec![SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881), SocketAddrV4::new(Ipv4Addr::LOCALHOST, 51413)];
+    let tracker = udp_tracker_stand_in(peers.clone()).await;
+    let announce = format!("udp://{tracker}/announce");
+    let response = TrackerResponse::announce(&announce, [7; 20], 1000, 6881, None).await.unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.peers.0, peers);
+
+    // and the same through a socks5 proxy
+    let proxy: Proxy = format!("socks5://user:secret@{}", crate::proxy::socks5_stand_in().await).parse().unwrap();
+    let response = TrackerResponse::announce(&announce, [7; 20], 1000, 6881, Some(&proxy)).await.unwrap();
+    assert_eq!(response.peers.0, peers);
+}
+
  
 pub fn urlencode(t : &[u8;20]) -> String { 
     let mut encoded = String::with_capacity(3 * t.len());
     for byte in t { 
         encoded.push('%');
-        encoded.push_str(&hex::encode(&[*byte]));
+        encoded.push_str(&hex::encode([*byte]));
     }
     encoded
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+//! How connections to peers are carried: plain TCP, or uTP over UDP. The peer layer only sees
+//! a [`Connector`] handing out byte streams, so anything that can carry the wire protocol plugs in.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::events::Events;
+use crate::utp::{UtpSocket, UtpStream};
+
+/// A byte stream to a peer that the wire protocol can run over.
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for S {}
+
+/// Opens streams to peers.
+pub trait Connector: fmt::Debug + Send + Sync {
+    fn connect(&self, addr: SocketAddrV4) -> BoxFuture<'_, anyhow::Result<Box<dyn PeerStream>>>;
+}
+
+/// Which transport to reach each peer over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportPolicy {
+    #[default]
+    Tcp,
+    Utp,
+    /// uTP for peers that answer it, TCP for the rest.
+    PreferUtp,
+}
+
+impl FromStr for TransportPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(TransportPolicy::Tcp),
+            "utp" => Ok(TransportPolicy::Utp),
+            "prefer-utp" => Ok(TransportPolicy::PreferUtp),
+            _ => anyhow::bail!("unknown transport {s:?} (expected tcp, utp or prefer-utp)"),
+        }
+    }
+}
+
+impl fmt::Display for TransportPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TransportPolicy::Tcp => "tcp",
+            TransportPolicy::Utp => "utp",
+            TransportPolicy::PreferUtp => "prefer-utp",
+        })
+    }
+}
+
+/// How long a peer gets to answer over uTP before we try TCP instead.
+const UTP_FALLBACK_AFTER: Duration = Duration::from_secs(3);
+
+/// Opens connections to peers as a [`TransportPolicy`] says, sharing one uTP socket between them.
+#[derive(Debug, Clone, Default)]
+pub struct Transport {
+    policy: TransportPolicy,
+    utp: Option<Arc<UtpSocket>>,
+}
+
+impl Transport {
+    /// Sets up the transports `policy` needs; the uTP socket reports to `events`.
+    pub async fn new(policy: TransportPolicy, events: Events) -> anyhow::Result<Self> {
+        let utp = match policy {
+            TransportPolicy::Tcp => None,
+            _ => Some(Arc::new(UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)), events).await?)),
+        };
+        Ok(Self { policy, utp })
+    }
+
+    pub fn policy(&self) -> TransportPolicy {
+        self.policy
+    }
+
+    pub async fn connect(&self, addr: SocketAddrV4) -> anyhow::Result<Connection> {
+        let tcp = || async {
+            let tcp = TcpStream::connect(addr).await.context("connect to peer")?;
+            Ok(Connection::Tcp(tcp))
+        };
+        let Some(utp) = &self.utp else {
+            return tcp().await;
+        };
+        match self.policy {
+            TransportPolicy::Tcp => tcp().await,
+            TransportPolicy::Utp => {
+                let utp = utp.connect(addr.into()).await.context("connect to peer over utp")?;
+                Ok(Connection::Utp(utp))
+            }
+            TransportPolicy::PreferUtp => {
+                match tokio::time::timeout(UTP_FALLBACK_AFTER, utp.connect(addr.into())).await {
+                    Ok(Ok(utp)) => Ok(Connection::Utp(utp)),
+                    _ => tcp().await,
+                }
+            }
+        }
+    }
+}
+
+impl Connector for Transport {
+    fn connect(&self, addr: SocketAddrV4) -> BoxFuture<'_, anyhow::Result<Box<dyn PeerStream>>> {
+        Box::pin(async move {
+            let conn: Box<dyn PeerStream> = Box::new(Transport::connect(self, addr).await?);
+            Ok(conn)
+        })
+    }
+}
+
+/// A connection to a peer over whichever transport reached it.
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            Connection::Utp(utp) => Pin::new(utp).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            Connection::Utp(utp) => Pin::new(utp).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(tcp) => Pin::new(tcp).poll_flush(cx),
+            Connection::Utp(utp) => Pin::new(utp).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            Connection::Utp(utp) => Pin::new(utp).poll_shutdown(cx),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_prefer_utp_falls_back_to_tcp() {
+    use std::net::Ipv4Addr;
+    // a peer that only listens on TCP never answers over uTP
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+    let transport = Transport::new(TransportPolicy::PreferUtp, Events::new()).await.unwrap();
+    let (conn, accepted) = tokio::join!(transport.connect(addr), listener.accept());
+    assert!(matches!(conn.unwrap(), Connection::Tcp(_)));
+    accepted.unwrap();
+}
@@ -0,0 +1,139 @@
+//! LEDBAT congestion control: grow the send window while the one-way delay stays under a target,
+//! and shrink it as soon as queues start building up, so that uTP yields to other traffic.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The queueing delay we are willing to add to the path.
+const TARGET: Duration = Duration::from_millis(100);
+/// How far the window may grow per round trip when there is no queueing delay at all.
+const MAX_INCREASE_PER_RTT: f64 = 3000.0;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+/// The base delay is the lowest delay seen in this many minutes, so that it follows route
+/// changes and clock drift.
+const BASE_DELAY_MINUTES: usize = 2;
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    mss: usize,
+    window: f64,
+    // the lowest delay sample of each of the last few minutes
+    base_delays: VecDeque<(Instant, u32)>,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    last_decrease: Option<Instant>,
+}
+
+impl Ledbat {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            window: (2 * mss) as f64,
+            base_delays: VecDeque::new(),
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: Duration::from_secs(1),
+            last_decrease: None,
+        }
+    }
+
+    /// How many bytes may be in flight.
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// How long to wait for an ack before sending a packet again.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Takes in an ack for `bytes` bytes. `delay` is the one-way delay the other side measured
+    /// for our packets, in microseconds, and `rtt` the round trip of a packet sent only once.
+    pub fn on_ack(&mut self, bytes: usize, delay: u32, rtt: Option<Duration>) {
+        if let Some(sample) = rtt {
+            self.on_rtt(sample);
+        }
+        if delay == 0 || bytes == 0 {
+            // the other side has not measured anything yet
+            return;
+        }
+        let now = Instant::now();
+        match self.base_delays.back_mut() {
+            Some((minute, lowest)) if now.duration_since(*minute) < Duration::from_secs(60) => {
+                *lowest = (*lowest).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_MINUTES {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        let base_delay = self.base_delays.iter().map(|&(_, lowest)| lowest).min().expect("just pushed");
+        // what we add on top of the emptiest the path has been: our share of the queues
+        let queueing = delay.wrapping_sub(base_delay) as f64;
+        let target = TARGET.as_micros() as f64;
+        let off_target = (target - queueing) / target;
+        let window_factor = (bytes as f64).min(self.window) / (bytes as f64).max(self.window);
+        self.window = (self.window + MAX_INCREASE_PER_RTT * window_factor * off_target)
+            .clamp(self.mss as f64, MAX_WINDOW);
+    }
+
+    fn on_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        let rtt = self.rtt.expect("just set");
+        self.rto = (rtt + 4 * self.rtt_var).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// A packet went missing while later ones made it: halve the window, at most once per
+    /// round trip.
+    pub fn on_loss(&mut self) {
+        let rtt = self.rtt.unwrap_or(self.rto);
+        if self.last_decrease.is_some_and(|last| last.elapsed() < rtt) {
+            return;
+        }
+        self.last_decrease = Some(Instant::now());
+        self.window = (self.window / 2.0).max(self.mss as f64);
+    }
+
+    /// Nothing got through for a whole timeout: start over from a single packet and back off.
+    pub fn on_timeout(&mut self) {
+        self.window = self.mss as f64;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+#[test]
+fn test_ledbat_window() {
+    let mut ledbat = Ledbat::new(1000);
+    assert_eq!(ledbat.window(), 2000);
+    // no queueing on top of the base delay: the window opens up
+    for _ in 0..10 {
+        ledbat.on_ack(1000, 20_000, Some(Duration::from_millis(40)));
+    }
+    let grown = ledbat.window();
+    assert!(grown > 2000);
+    // a queue twice the target builds up: the window closes again
+    for _ in 0..10 {
+        ledbat.on_ack(1000, 220_000, None);
+    }
+    assert!(ledbat.window() < grown);
+    ledbat.on_loss();
+    assert!(ledbat.window() >= 1000);
+    ledbat.on_timeout();
+    assert_eq!(ledbat.window(), 1000);
+    assert_eq!(ledbat.rto(), 2 * MIN_RTO);
+}
@@ -0,0 +1,717 @@
+//! uTP (BEP 29): reliable, ordered streams over UDP whose LEDBAT congestion control backs off as
+//! soon as it notices queues building up, so that bulk transfers leave room for everyone else.
+//!
+//! One [`UtpSocket`] carries any number of connections over a single UDP port, and each
+//! [`UtpStream`] is an `AsyncRead + AsyncWrite` byte stream just like a `TcpStream`.
+
+pub mod ledbat;
+pub mod packet;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+use crate::events::{Event, Events};
+use crate::random::Rng;
+use ledbat::Ledbat;
+use packet::{decode_sack, encode_sack, seq_less, Packet, Type};
+
+/// The most payload we put in one datagram, which keeps packets clear of the usual 1500-byte MTU.
+const MSS: usize = 1400;
+/// How much received data we buffer before the reader gets to it.
+const RECV_WINDOW: usize = 1 << 20;
+/// How much written data we buffer before it is packetized.
+const SEND_BUFFER: usize = 1 << 18;
+/// How far past a gap we keep packets that arrived out of order.
+const MAX_REORDER: u16 = 1024;
+/// Times a packet is sent again before the connection is given up.
+const MAX_RETRANSMISSIONS: u32 = 6;
+const MAX_SYN_RETRANSMISSIONS: u32 = 2;
+/// Connections accepted but not yet picked up by [`UtpSocket::accept`].
+const BACKLOG: usize = 64;
+/// Duplicate acks after which the packet they point at counts as lost.
+const DUPLICATE_ACKS: u32 = 3;
+
+/// A UDP socket speaking uTP. Connections keep working only while it is alive.
+pub struct UtpSocket {
+    inner: Arc<SocketInner>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    receiver: JoinHandle<()>,
+}
+
+struct SocketInner {
+    udp: UdpSocket,
+    // (remote, connection id they send to us) -> connection
+    connections: Mutex<HashMap<(SocketAddr, u16), Arc<Connection>>>,
+    incoming: mpsc::Sender<UtpStream>,
+    epoch: Instant,
+    rng: Mutex<Rng>,
+    // where packets that fail to go out are reported
+    events: Events,
+}
+
+/// A uTP connection. Dropping it closes the connection once everything written has been acked.
+pub struct UtpStream {
+    connection: Arc<Connection>,
+}
+
+struct Connection {
+    socket: Arc<SocketInner>,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: Mutex<State>,
+    // tells the driver there is something to send
+    wake_driver: Notify,
+    // tells `connect` the handshake went one way or the other
+    established: Notify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    SynSent,
+    Connected,
+}
+
+struct Sent {
+    ty: Type,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    resend: bool,
+}
+
+struct State {
+    phase: Phase,
+    // the next sequence number to send, and the last one received in order
+    seq_nr: u16,
+    ack_nr: u16,
+    send_buf: VecDeque<u8>,
+    unacked: VecDeque<Sent>,
+    in_flight: usize,
+    ledbat: Ledbat,
+    peer_window: usize,
+    last_ack: u16,
+    duplicate_acks: u32,
+    recv_buf: VecDeque<u8>,
+    // packets that arrived past a gap
+    reorder: HashMap<u16, (Type, Vec<u8>)>,
+    eof: bool,
+    // the delay we measured for their last packet, handed back to them in ours
+    reply_micro: u32,
+    ack_due: bool,
+    // we want to send a FIN once the send buffer has drained, and whether we did
+    closing: bool,
+    fin_sent: bool,
+    dropped: bool,
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpSocket").field("addr", &self.inner.udp.local_addr().ok()).finish()
+    }
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream")
+            .field("remote", &self.connection.remote)
+            .field("recv_id", &self.connection.recv_id)
+            .finish()
+    }
+}
+
+impl UtpSocket {
+    /// Listens on `addr`, reporting to `events` what goes wrong in the background.
+    pub async fn bind(addr: SocketAddr, events: Events) -> anyhow::Result<Self> {
+        let udp = UdpSocket::bind(addr).await.context("bind utp socket")?;
+        let (incoming, accepted) = mpsc::channel(BACKLOG);
+        let inner = Arc::new(SocketInner {
+            udp,
+            connections: Mutex::new(HashMap::new()),
+            incoming,
+            epoch: Instant::now(),
+            rng: Mutex::new(Rng::new()),
+            events,
+        });
+        let receiver = tokio::spawn(inner.clone().receive());
+        Ok(Self { inner, incoming: tokio::sync::Mutex::new(accepted), receiver })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.udp.local_addr()
+    }
+
+    /// Opens a connection to `remote`.
+    pub async fn connect(&self, remote: SocketAddr) -> io::Result<UtpStream> {
+        let connection = {
+            let mut connections = self.inner.connections.lock().expect("connections lock is never poisoned");
+            let mut rng = self.inner.rng.lock().expect("rng lock is never poisoned");
+            let recv_id = loop {
+                let id = rng.next_u64() as u16;
+                if !connections.contains_key(&(remote, id)) {
+                    break id;
+                }
+            };
+            let mut state = State::new(1, 0);
+            state.phase = Phase::SynSent;
+            state.queue(Type::Syn, Vec::new());
+            let connection = Arc::new(Connection::new(self.inner.clone(), remote, recv_id, recv_id.wrapping_add(1), state));
+            connections.insert((remote, recv_id), connection.clone());
+            connection
+        };
+        tokio::spawn(connection.clone().drive());
+        loop {
+            let established = connection.established.notified();
+            tokio::pin!(established);
+            established.as_mut().enable();
+            {
+                let state = connection.state();
+                if let Some(error) = state.error {
+                    return Err(io::Error::new(error, format!("utp connect to {remote}")));
+                }
+                if state.phase == Phase::Connected {
+                    break;
+                }
+            }
+            established.await;
+        }
+        Ok(UtpStream { connection })
+    }
+
+    /// Waits for someone to connect to us.
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "utp socket is gone"))
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+        let connections: Vec<_> = self
+            .inner
+            .connections
+            .lock()
+            .expect("connections lock is never poisoned")
+            .drain()
+            .map(|(_, connection)| connection)
+            .collect();
+        for connection in connections {
+            connection.state().error.get_or_insert(io::ErrorKind::ConnectionAborted);
+            connection.wake_all();
+        }
+    }
+}
+
+impl SocketInner {
+    fn now_micros(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, from) = match self.udp.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let Ok(packet) = Packet::decode(&buf[..n]) else {
+                continue;
+            };
+            let existing = {
+                let connections = self.connections.lock().expect("connections lock is never poisoned");
+                match packet.ty {
+                    // a SYN carries the id the other side receives on; we will receive on the next one
+                    Type::Syn => connections.get(&(from, packet.connection_id.wrapping_add(1))).cloned(),
+                    _ => connections.get(&(from, packet.connection_id)).cloned(),
+                }
+            };
+            match existing {
+                Some(connection) => connection.on_packet(packet),
+                None if packet.ty == Type::Syn => self.clone().incoming(from, packet),
+                // most likely for a connection we already closed
+                None => {}
+            }
+        }
+    }
+
+    fn incoming(self: Arc<Self>, from: SocketAddr, syn: Packet) {
+        let seq_nr = self.rng.lock().expect("rng lock is never poisoned").next_u64() as u16;
+        let mut state = State::new(seq_nr, syn.seq_nr);
+        state.ack_due = true;
+        state.reply_micro = self.now_micros().wrapping_sub(syn.timestamp);
+        state.peer_window = syn.wnd_size as usize;
+        let recv_id = syn.connection_id.wrapping_add(1);
+        let connection = Arc::new(Connection::new(self.clone(), from, recv_id, syn.connection_id, state));
+        let stream = UtpStream { connection: connection.clone() };
+        // if nobody is accepting, the other side will time out
+        if self.incoming.try_send(stream).is_ok() {
+            self.connections
+                .lock()
+                .expect("connections lock is never poisoned")
+                .insert((from, recv_id), connection.clone());
+            tokio::spawn(connection.drive());
+        }
+    }
+}
+
+impl State {
+    fn new(seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            phase: Phase::Connected,
+            seq_nr,
+            ack_nr,
+            send_buf: VecDeque::new(),
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            ledbat: Ledbat::new(MSS),
+            peer_window: MSS,
+            last_ack: 0,
+            duplicate_acks: 0,
+            recv_buf: VecDeque::new(),
+            reorder: HashMap::new(),
+            eof: false,
+            reply_micro: 0,
+            ack_due: false,
+            closing: false,
+            fin_sent: false,
+            dropped: false,
+            error: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Puts a packet that takes up a sequence number into the queue of packets awaiting an ack.
+    fn queue(&mut self, ty: Type, payload: Vec<u8>) {
+        self.in_flight += payload.len();
+        self.unacked.push_back(Sent {
+            ty,
+            seq_nr: self.seq_nr,
+            payload,
+            sent_at: Instant::now(),
+            transmissions: 0,
+            resend: true,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+    }
+
+    fn lost(&mut self) {
+        if let Some(first) = self.unacked.front_mut() {
+            first.resend = true;
+            self.ledbat.on_loss();
+        }
+    }
+
+    /// Acks everything up to and including `ack_nr`, plus what `sack` lists. Returns the bytes
+    /// acked and a round trip sample from a packet that was only sent once. An ack for a packet
+    /// we have not sent yet is forged or stale, and acks nothing.
+    fn acked(&mut self, ack_nr: u16, sack: Option<&[u8]>) -> (usize, Option<Duration>) {
+        let mut bytes = 0;
+        let mut rtt = None;
+        if seq_less(self.seq_nr.wrapping_sub(1), ack_nr) {
+            return (bytes, rtt);
+        }
+        let mut take = |sent: Sent, in_flight: &mut usize| {
+            *in_flight -= sent.payload.len();
+            bytes += sent.payload.len();
+            if sent.transmissions == 1 {
+                rtt = Some(sent.sent_at.elapsed());
+            }
+        };
+        while self.unacked.front().is_some_and(|sent| !seq_less(ack_nr, sent.seq_nr)) {
+            let sent = self.unacked.pop_front().expect("just checked");
+            take(sent, &mut self.in_flight);
+        }
+        if let Some(sack) = sack {
+            let mut past_gap = 0;
+            for seq_nr in decode_sack(ack_nr, sack) {
+                if let Some(i) = self.unacked.iter().position(|sent| sent.seq_nr == seq_nr) {
+                    let sent = self.unacked.remove(i).expect("just found it");
+                    take(sent, &mut self.in_flight);
+                }
+                past_gap += 1;
+            }
+            // enough packets made it past the first one we are missing that it must be lost
+            if past_gap >= DUPLICATE_ACKS && self.unacked.front().is_some_and(|sent| !sent.resend) {
+                self.lost();
+            }
+        }
+        (bytes, rtt)
+    }
+
+    /// Puts a packet that arrived in order into the receive buffer.
+    fn deliver(&mut self, ty: Type, payload: Vec<u8>) {
+        match ty {
+            Type::Fin => self.eof = true,
+            _ => self.recv_buf.extend(payload),
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Connection {
+    fn new(socket: Arc<SocketInner>, remote: SocketAddr, recv_id: u16, send_id: u16, state: State) -> Self {
+        Self {
+            socket,
+            remote,
+            recv_id,
+            send_id,
+            state: Mutex::new(state),
+            wake_driver: Notify::new(),
+            established: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("connection lock is never poisoned")
+    }
+
+    fn wake_all(&self) {
+        let mut state = self.state();
+        state.wake_reader();
+        state.wake_writer();
+        drop(state);
+        self.established.notify_waiters();
+        self.wake_driver.notify_one();
+    }
+
+    fn on_packet(&self, packet: Packet) {
+        let mut state = self.state();
+        state.reply_micro = self.socket.now_micros().wrapping_sub(packet.timestamp);
+        state.peer_window = packet.wnd_size as usize;
+        if packet.ty == Type::Reset {
+            state.error.get_or_insert(io::ErrorKind::ConnectionReset);
+            drop(state);
+            self.wake_all();
+            return;
+        }
+        if state.phase == Phase::SynSent {
+            if packet.ty != Type::State {
+                return;
+            }
+            // their first data packet will carry the sequence number of this ack
+            state.ack_nr = packet.seq_nr.wrapping_sub(1);
+            state.phase = Phase::Connected;
+            self.established.notify_waiters();
+        }
+
+        let (bytes, rtt) = state.acked(packet.ack_nr, packet.sack.as_deref());
+        if bytes == 0 && packet.ty == Type::State && !state.unacked.is_empty() && packet.ack_nr == state.last_ack {
+            state.duplicate_acks += 1;
+            if state.duplicate_acks == DUPLICATE_ACKS {
+                state.lost();
+            }
+        } else {
+            state.duplicate_acks = 0;
+        }
+        state.last_ack = packet.ack_nr;
+        state.ledbat.on_ack(bytes, packet.timestamp_diff, rtt);
+
+        match packet.ty {
+            Type::Data | Type::Fin => {
+                state.ack_due = true;
+                let expected = state.ack_nr.wrapping_add(1);
+                if packet.seq_nr == expected {
+                    state.ack_nr = expected;
+                    state.deliver(packet.ty, packet.payload);
+                    loop {
+                        let next = state.ack_nr.wrapping_add(1);
+                        let Some((ty, payload)) = state.reorder.remove(&next) else {
+                            break;
+                        };
+                        state.ack_nr = next;
+                        state.deliver(ty, payload);
+                    }
+                    state.wake_reader();
+                } else if seq_less(expected, packet.seq_nr) && packet.seq_nr.wrapping_sub(expected) < MAX_REORDER {
+                    state.reorder.insert(packet.seq_nr, (packet.ty, packet.payload));
+                }
+                // anything else is a duplicate, which still deserves an ack
+            }
+            // the other side did not hear our ack for its SYN
+            Type::Syn => state.ack_due = true,
+            Type::State | Type::Reset => {}
+        }
+        drop(state);
+        self.wake_driver.notify_one();
+    }
+
+    fn packet(&self, state: &State, ty: Type, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            ty,
+            // a SYN names the id we want to receive on; everything else the one they receive on
+            connection_id: if ty == Type::Syn { self.recv_id } else { self.send_id },
+            timestamp: self.socket.now_micros(),
+            timestamp_diff: state.reply_micro,
+            wnd_size: RECV_WINDOW.saturating_sub(state.recv_buf.len()) as u32,
+            seq_nr,
+            ack_nr: state.ack_nr,
+            sack: encode_sack(state.ack_nr, state.reorder.keys().copied()),
+            payload,
+        }
+    }
+
+    /// Works out what to send now, and when to look again. Returns `None` once the connection
+    /// is over.
+    fn transmit(&self, state: &mut State) -> Option<(Vec<Vec<u8>>, Option<Instant>)> {
+        let now = Instant::now();
+        if state.error.is_some() || (state.dropped && (state.phase == Phase::SynSent || state.fin_sent) && state.unacked.is_empty()) {
+            return None;
+        }
+        let rto = state.ledbat.rto();
+        if let Some(first) = state.unacked.front_mut() {
+            if first.transmissions > 0 && !first.resend && first.sent_at + rto <= now {
+                let limit = if first.ty == Type::Syn { MAX_SYN_RETRANSMISSIONS } else { MAX_RETRANSMISSIONS };
+                if first.transmissions > limit {
+                    state.error = Some(io::ErrorKind::TimedOut);
+                    return None;
+                }
+                first.resend = true;
+                state.ledbat.on_timeout();
+            }
+        }
+
+        // packetize what has been written, as far as both windows allow
+        if state.phase == Phase::Connected {
+            let window = state.ledbat.window().min(state.peer_window);
+            while !state.send_buf.is_empty() {
+                let len = state.send_buf.len().min(MSS);
+                // with nothing in flight a packet goes out regardless, to probe a closed window
+                if state.in_flight > 0 && state.in_flight + len > window {
+                    break;
+                }
+                let payload: Vec<u8> = state.send_buf.drain(..len).collect();
+                state.queue(Type::Data, payload);
+                state.wake_writer();
+            }
+            if state.closing && !state.fin_sent && state.send_buf.is_empty() {
+                state.fin_sent = true;
+                state.queue(Type::Fin, Vec::new());
+            }
+        }
+
+        let mut packets = Vec::new();
+        for i in 0..state.unacked.len() {
+            if !state.unacked[i].resend {
+                continue;
+            }
+            let sent = &state.unacked[i];
+            let packet = self.packet(state, sent.ty, sent.seq_nr, sent.payload.clone());
+            packets.push(packet.encode());
+            let sent = &mut state.unacked[i];
+            sent.resend = false;
+            sent.sent_at = now;
+            sent.transmissions += 1;
+        }
+        if state.ack_due && packets.is_empty() && state.phase == Phase::Connected {
+            packets.push(self.packet(state, Type::State, state.seq_nr, Vec::new()).encode());
+        }
+        state.ack_due = false;
+        let deadline = state.unacked.front().map(|first| first.sent_at + state.ledbat.rto());
+        Some((packets, deadline))
+    }
+
+    /// Sends what there is to send for as long as the connection lasts.
+    async fn drive(self: Arc<Self>) {
+        loop {
+            let notified = self.wake_driver.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let transmit = self.transmit(&mut self.state());
+            let Some((packets, deadline)) = transmit else {
+                break;
+            };
+            for packet in packets {
+                if let Err(e) = self.socket.udp.send_to(&packet, self.remote).await {
+                    // e.g. the network went away; the retransmission timer has another go
+                    let message = format!("utp: send to {}: {e}", self.remote);
+                    self.socket.events.send(Event::Log { info_hash: None, message });
+                }
+            }
+            match deadline {
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = notified => {}
+                        _ = tokio::time::sleep_until(deadline.into()) => {}
+                    }
+                }
+                None => notified.await,
+            }
+        }
+        self.socket
+            .connections
+            .lock()
+            .expect("connections lock is never poisoned")
+            .remove(&(self.remote, self.recv_id));
+        self.wake_all();
+    }
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.remote
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut state = self.connection.state();
+        state.closing = true;
+        state.dropped = true;
+        drop(state);
+        self.connection.wake_driver.notify_one();
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.connection.state();
+        if !state.recv_buf.is_empty() {
+            let n = state.recv_buf.len().min(buf.remaining());
+            let (front, back) = state.recv_buf.as_slices();
+            let from_front = n.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            state.recv_buf.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        if state.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(error) = state.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.connection.state();
+        if let Some(error) = state.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if state.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let room = SEND_BUFFER.saturating_sub(state.send_buf.len());
+        if room == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(buf.len());
+        state.send_buf.extend(&buf[..n]);
+        drop(state);
+        self.connection.wake_driver.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.connection.state();
+        if let Some(error) = state.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        // flushed as far as we can tell, once it is all on its way
+        if state.send_buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        state.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.connection.state().closing = true;
+        self.connection.wake_driver.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_utp_transfer() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap(), Events::new()).await.unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap(), Events::new()).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    // enough data for the window to have to open up, and for packets to queue behind it
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let echo = tokio::spawn(async move {
+        let mut stream = server.accept().await.unwrap();
+        let mut received = vec![0u8; expected.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert!(received == expected);
+        stream.write_all(b"thanks").await.unwrap();
+        stream.shutdown().await.unwrap();
+        // reading past their FIN gives EOF
+        assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0);
+        server
+    });
+    let mut stream = client.connect(addr).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    stream.flush().await.unwrap();
+    let mut thanks = Vec::new();
+    stream.read_to_end(&mut thanks).await.unwrap();
+    assert_eq!(thanks, b"thanks");
+    stream.shutdown().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), echo).await.unwrap().unwrap();
+
+    // nobody listening: the connection attempt gives up
+    let gone = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    assert!(tokio::time::timeout(Duration::from_secs(30), client.connect(gone)).await.unwrap().is_err());
+}
+
+#[test]
+fn test_selective_ack_detects_loss() {
+    let mut state = State::new(10, 0);
+    for _ in 0..5 {
+        state.queue(Type::Data, vec![0; MSS]);
+    }
+    for sent in &mut state.unacked {
+        sent.resend = false;
+        sent.transmissions = 1;
+    }
+    // 10 arrived, 11 did not, 12 to 14 did
+    let (bytes, _) = state.acked(10, encode_sack(10, [12, 13, 14]).as_deref());
+    assert_eq!(bytes, 4 * MSS);
+    assert_eq!(state.in_flight, MSS);
+    assert_eq!(state.unacked.len(), 1);
+    assert!(state.unacked[0].seq_nr == 11 && state.unacked[0].resend);
+
+    // nothing past 14 was sent, so an ack for it is not believed
+    assert_eq!(state.acked(15, None).0, 0);
+    assert_eq!(state.unacked.len(), 1);
+    assert_eq!(state.acked(11, None).0, MSS);
+}
@@ -0,0 +1,146 @@
+//! The uTP packet format (BEP 29): a 20-byte header, optional extensions, then the payload.
+
+use anyhow::Context;
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub ty: Type,
+    pub connection_id: u16,
+    /// When the packet was sent, in microseconds on the sender's clock.
+    pub timestamp: u32,
+    /// The delay the sender last measured for packets coming the other way.
+    pub timestamp_diff: u32,
+    /// How many more bytes the sender is willing to receive.
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Bit `i` acknowledges `ack_nr + 2 + i`, for packets that arrived past a gap.
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + self.payload.len() + 8);
+        packet.push((self.ty as u8) << 4 | VERSION);
+        packet.push(if self.sack.is_some() { EXTENSION_SACK } else { EXTENSION_NONE });
+        packet.extend_from_slice(&self.connection_id.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        packet.extend_from_slice(&self.wnd_size.to_be_bytes());
+        packet.extend_from_slice(&self.seq_nr.to_be_bytes());
+        packet.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            packet.push(EXTENSION_NONE);
+            packet.push(sack.len() as u8);
+            packet.extend_from_slice(sack);
+        }
+        packet.extend_from_slice(&self.payload);
+        packet
+    }
+
+    pub fn decode(packet: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(packet.len() >= HEADER_LEN, "packet of {} bytes is too short", packet.len());
+        anyhow::ensure!(packet[0] & 0x0f == VERSION, "unknown version {}", packet[0] & 0x0f);
+        let ty = match packet[0] >> 4 {
+            0 => Type::Data,
+            1 => Type::Fin,
+            2 => Type::State,
+            3 => Type::Reset,
+            4 => Type::Syn,
+            ty => anyhow::bail!("unknown packet type {ty}"),
+        };
+        let u16_at = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().expect("4 bytes"));
+        let mut sack = None;
+        let mut extension = packet[1];
+        let mut rest = &packet[HEADER_LEN..];
+        while extension != EXTENSION_NONE {
+            let (&next, rest_) = rest.split_first().context("truncated extension")?;
+            let (&len, rest_) = rest_.split_first().context("truncated extension")?;
+            let data = rest_.get(..len as usize).context("truncated extension")?;
+            if extension == EXTENSION_SACK {
+                sack = Some(data.to_vec());
+            }
+            extension = next;
+            rest = &rest_[len as usize..];
+        }
+        Ok(Packet {
+            ty,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: rest.to_vec(),
+        })
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap-around.
+pub fn seq_less(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+/// The selective ack for the packets we hold past `ack_nr`, or `None` if there are none.
+pub fn encode_sack(ack_nr: u16, received: impl IntoIterator<Item = u16>) -> Option<Vec<u8>> {
+    let mut sack = Vec::new();
+    for seq_nr in received {
+        let bit = seq_nr.wrapping_sub(ack_nr).wrapping_sub(2) as usize;
+        // a bitmask of more than a few hundred packets is not worth sending
+        if bit >= 8 * 64 {
+            continue;
+        }
+        if sack.len() <= bit / 8 {
+            // the mask goes in multiples of four bytes
+            sack.resize((bit / 32 + 1) * 4, 0);
+        }
+        sack[bit / 8] |= 1 << (bit % 8);
+    }
+    (!sack.is_empty()).then_some(sack)
+}
+
+/// The sequence numbers a selective ack covers.
+pub fn decode_sack(ack_nr: u16, sack: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    (0..sack.len() * 8)
+        .filter(|&bit| sack[bit / 8] & (1 << (bit % 8)) != 0)
+        .map(move |bit| ack_nr.wrapping_add(2).wrapping_add(bit as u16))
+}
+
+#[test]
+fn test_packet_roundtrip() {
+    let packet = Packet {
+        ty: Type::State,
+        connection_id: 0xbeef,
+        timestamp: 123_456,
+        timestamp_diff: 789,
+        wnd_size: 1 << 20,
+        seq_nr: 0xfffe,
+        ack_nr: 3,
+        sack: encode_sack(3, [5, 7, 40]),
+        payload: Vec::new(),
+    };
+    let encoded = packet.encode();
+    assert_eq!(encoded.len(), HEADER_LEN + 2 + 8);
+    let decoded = Packet::decode(&encoded).unwrap();
+    assert_eq!(decoded, packet);
+    assert_eq!(decode_sack(3, decoded.sack.as_ref().unwrap()).collect::<Vec<_>>(), vec![5, 7, 40]);
+    assert!(seq_less(0xfffe, 1));
+    assert!(!seq_less(1, 0xfffe));
+}